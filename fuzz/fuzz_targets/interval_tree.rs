@@ -0,0 +1,127 @@
+//! Random operations on `IntervalTree`, checked against a list of entries.
+
+#![no_main]
+
+use std::ops::Range;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use looplift::fuzzing::{IntervalTree, IntervalTreeEntry};
+
+/// Length of the tree's span, small so that entries collide often.
+const SPAN: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    interval: Range<u64>,
+    id: u8,
+}
+
+impl IntervalTreeEntry for Entry {
+    fn interval(&self) -> Range<u64> {
+        self.interval.clone()
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+enum Op {
+    Insert {
+        start: u16,
+        length: u16,
+        id: u8,
+    },
+    /// Removes the `index`th entry of the model, or a missing one.
+    Remove {
+        index: u8,
+        start: u16,
+        length: u16,
+    },
+    Find {
+        start: u16,
+        length: u16,
+    },
+    First,
+    FirstAtOrAfter {
+        position: u16,
+    },
+}
+
+/// A non-empty interval within the span.
+fn interval(start: u16, length: u16) -> Range<u64> {
+    let start = u64::from(start) % SPAN;
+    let length = 1 + u64::from(length) % (SPAN - start);
+    start..(start + length)
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut tree = IntervalTree::new(0..SPAN);
+    let mut model: Vec<Entry> = Vec::new();
+
+    for op in ops {
+        match op {
+            Op::Insert { start, length, id } => {
+                let entry = Entry {
+                    interval: interval(start, length),
+                    id,
+                };
+                let expected = !model.contains(&entry);
+                if expected {
+                    model.push(entry.clone());
+                }
+                assert_eq!(tree.insert(entry), expected);
+            }
+            Op::Remove {
+                index,
+                start,
+                length,
+            } => {
+                let entry = match model.get(usize::from(index)) {
+                    Some(entry) => entry.clone(),
+                    None => Entry {
+                        interval: interval(start, length),
+                        id: index,
+                    },
+                };
+                let position = model.iter().position(|e| *e == entry);
+                if let Some(position) = position {
+                    model.swap_remove(position);
+                }
+                assert_eq!(tree.remove(&entry), position.is_some());
+            }
+            Op::Find { start, length } => {
+                let query = interval(start, length);
+                let mut found: Vec<&Entry> = tree.find(&query);
+                let mut expected: Vec<&Entry> = model
+                    .iter()
+                    .filter(|e| overlaps(&e.interval, &query))
+                    .collect();
+                let key = |e: &&Entry| (e.interval.start, e.interval.end, e.id);
+                found.sort_by_key(key);
+                expected.sort_by_key(key);
+                assert_eq!(found, expected);
+            }
+            Op::First => match tree.first() {
+                Some(entry) => assert!(model.contains(entry)),
+                None => assert!(model.is_empty()),
+            },
+            Op::FirstAtOrAfter { position } => {
+                let position = u64::from(position) % (SPAN + 1);
+                let expected = model
+                    .iter()
+                    .map(|e| e.interval.start)
+                    .filter(|&start| start >= position)
+                    .min();
+                let found = tree.first_at_or_after(position);
+                assert_eq!(found.map(|e| e.interval.start), expected);
+                if let Some(entry) = found {
+                    assert!(model.contains(entry));
+                }
+            }
+        }
+        assert_eq!(tree.is_empty(), model.is_empty());
+    }
+});
@@ -0,0 +1,98 @@
+//! Random valid reports, lifted onto a device in memory, which must end up
+//! holding exactly the data the report describes.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use looplift::{
+    device::MemDevice, fuzzing::checksum, lift, ExtentSource, IoSize, LiftOptions, OrderPolicy,
+    ReportExtent, ReportSummary, ReportWriter,
+};
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    /// Length of each extent, in order of destination, and whether it has
+    /// data rather than being a zero extent.
+    extents: Vec<(u8, bool)>,
+    /// Sort keys for placing the extents on the device, the space of zero
+    /// extents being left free.
+    placement: Vec<u16>,
+    jobs: u8,
+    elevator: bool,
+    check_zeros: bool,
+}
+
+fuzz_target!(|input: Input| {
+    let extents: Vec<(u64, bool)> = input
+        .extents
+        .iter()
+        .take(64)
+        .map(|&(length, data)| (1 + u64::from(length) % 32, data))
+        .collect();
+    let device_length: u64 = extents.iter().map(|(length, _)| length).sum();
+    if device_length == 0 {
+        return;
+    }
+
+    let mut order: Vec<usize> = (0..extents.len()).collect();
+    order.sort_by_key(|&i| (input.placement.get(i).copied().unwrap_or(0), i));
+    let mut sources = vec![0u64; extents.len()];
+    let mut offset = 0;
+    for &i in &order {
+        sources[i] = offset;
+        offset += extents[i].0;
+    }
+
+    let original: Vec<u8> = (0..device_length)
+        .map(|i| (i.wrapping_mul(2654435761) >> 16) as u8)
+        .collect();
+    let mut expected = Vec::new();
+    let mut report = Vec::new();
+    let mut writer = ReportWriter::new(&mut report);
+    writer
+        .write_summary(&ReportSummary { device_length })
+        .unwrap();
+    let mut destination_offset = 0;
+    for (&(length, data), &offset) in extents.iter().zip(&sources) {
+        let source = match data {
+            true => {
+                let range = (offset as usize)..((offset + length) as usize);
+                expected.extend_from_slice(&original[range.clone()]);
+                ExtentSource::Offset {
+                    offset,
+                    checksum: checksum(&original[range]),
+                }
+            }
+            false => {
+                expected.resize(expected.len() + length as usize, 0);
+                ExtentSource::Zeros
+            }
+        };
+        writer
+            .write(&ReportExtent {
+                destination_offset,
+                length,
+                source,
+            })
+            .unwrap();
+        destination_offset += length;
+    }
+
+    lift::do_plan(&mut report.as_slice(), &mut std::io::sink()).unwrap();
+
+    let options = LiftOptions {
+        dry_run: false,
+        check_zeros: input.check_zeros,
+        jobs: 1 + usize::from(input.jobs % 4),
+        io_size: IoSize::Bytes(4096),
+        order: match input.elevator {
+            true => OrderPolicy::Elevator,
+            false => OrderPolicy::Source,
+        },
+        ..Default::default()
+    };
+    let device = MemDevice::from(original);
+    lift::do_lift(&device, &mut report.as_slice(), &options).unwrap();
+    assert!(device.into_inner() == expected);
+});
@@ -0,0 +1,33 @@
+//! Arbitrary bytes as a report: reading, planning and a dry-run lift must
+//! reject anything invalid with an error, never a panic.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use looplift::{device::MemDevice, lift, IoSize, LiftOptions, ReportReader, ResultType};
+
+/// Largest device to lift onto in memory.
+const MAX_DEVICE_LENGTH: u64 = 1 << 20;
+
+fuzz_target!(|data: &[u8]| {
+    let device_length = match ReportReader::new(data) {
+        Ok(reader) => {
+            let device_length = reader.summary().device_length;
+            let _ = reader.collect::<ResultType<Vec<_>>>();
+            device_length
+        }
+        Err(_) => return,
+    };
+
+    let _ = lift::do_plan(&mut &data[..], &mut std::io::sink());
+
+    if device_length <= MAX_DEVICE_LENGTH {
+        let device = MemDevice::new(device_length as usize);
+        let options = LiftOptions {
+            dry_run: true,
+            io_size: IoSize::Bytes(4096),
+            ..Default::default()
+        };
+        let _ = lift::do_lift(&device, &mut &data[..], &options);
+    }
+});
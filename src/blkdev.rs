@@ -0,0 +1,246 @@
+use std::{
+    ffi::c_int,
+    fs::{self, File},
+    io,
+    ops::Range,
+    os::{
+        fd::AsRawFd,
+        raw::c_ulong,
+        unix::fs::{FileTypeExt, MetadataExt},
+    },
+};
+
+use crate::fiemap::ioctl;
+
+extern "C" {
+    fn fallocate(fd: c_int, mode: c_int, offset: i64, len: i64) -> c_int;
+    fn lseek(fd: c_int, offset: i64, whence: c_int) -> i64;
+}
+
+const FALLOC_FL_KEEP_SIZE: c_int = 0x01;
+const FALLOC_FL_PUNCH_HOLE: c_int = 0x02;
+
+const SEEK_DATA: c_int = 3;
+const SEEK_HOLE: c_int = 4;
+
+/// Block device ioctls all have the type `0x12`, and no size.
+fn blk_io(nr: u64) -> c_ulong {
+    (0x12 << 8) | nr
+}
+
+/// The value of BLKDISCARD.
+fn blkdiscard() -> c_ulong {
+    blk_io(119)
+}
+
+/// The value of BLKZEROOUT.
+fn blkzeroout() -> c_ulong {
+    blk_io(127)
+}
+
+/// The value of BLKSSZGET.
+fn blksszget() -> c_ulong {
+    blk_io(104)
+}
+
+/// The value of BLKPBSZGET.
+fn blkpbszget() -> c_ulong {
+    blk_io(123)
+}
+
+/// The `O_DIRECT` open flag, which varies by architecture.
+#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+pub(crate) const O_DIRECT: c_int = 0o200000;
+#[cfg(any(target_arch = "powerpc", target_arch = "powerpc64"))]
+pub(crate) const O_DIRECT: c_int = 0o400000;
+#[cfg(any(target_arch = "mips", target_arch = "mips64"))]
+pub(crate) const O_DIRECT: c_int = 0o100000;
+#[cfg(not(any(
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64"
+)))]
+pub(crate) const O_DIRECT: c_int = 0o40000;
+
+/// Logical and physical block sizes of a block device.
+///
+/// For anything else the preferred IO size reported by `stat` is used for both.
+pub(crate) fn block_sizes(f: &File) -> io::Result<(u64, u64)> {
+    let metadata = f.metadata()?;
+    if !metadata.file_type().is_block_device() {
+        return Ok((metadata.blksize(), metadata.blksize()));
+    }
+    let mut logical: c_int = 0;
+    let mut physical: u32 = 0;
+    check(unsafe { ioctl(f.as_raw_fd(), blksszget(), &mut logical as *mut c_int) })?;
+    check(unsafe { ioctl(f.as_raw_fd(), blkpbszget(), &mut physical as *mut u32) })?;
+    Ok((logical.try_into().unwrap(), physical.into()))
+}
+
+/// A device number, as `(major, minor)`.
+pub(crate) type DevNum = (u64, u64);
+
+/// Splits a Linux `dev_t` into major and minor numbers.
+pub(crate) fn dev_num(rdev: u64) -> DevNum {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & 0xfffff000);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & 0xffffff00);
+    (major, minor)
+}
+
+/// Reads an attribute from the sysfs `queue` directory of a block device.
+///
+/// Partitions do not have their own queue, so the parent device is tried too.
+pub(crate) fn queue_attribute(device: &File, name: &str) -> Option<String> {
+    let (major, minor) = dev_num(device.metadata().ok()?.rdev());
+    [
+        format!("/sys/dev/block/{}:{}/queue/{}", major, minor, name),
+        format!("/sys/dev/block/{}:{}/../queue/{}", major, minor, name),
+    ]
+    .iter()
+    .find_map(|p| fs::read_to_string(p).ok())
+    .map(|s| s.trim().to_string())
+}
+
+/// The queue limits which guide the choice of IO size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueLimits {
+    /// In bytes, zero if the device has no preference.
+    pub optimal_io_size: u64,
+    /// Largest request the kernel will issue, zero if unknown.
+    pub max_sectors_kb: u64,
+    /// True for spinning disks.
+    pub rotational: bool,
+}
+
+/// Reads the queue limits of a block device, `None` for anything else.
+pub(crate) fn queue_limits(device: &File) -> Option<QueueLimits> {
+    if !device.metadata().ok()?.file_type().is_block_device() {
+        return None;
+    }
+    let number = |name| {
+        queue_attribute(device, name)
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    Some(QueueLimits {
+        optimal_io_size: number("optimal_io_size"),
+        max_sectors_kb: number("max_sectors_kb"),
+        rotational: queue_attribute(device, "rotational").as_deref() == Some("1"),
+    })
+}
+
+/// True if the device guarantees that BLKDISCARD leaves ranges reading as zeros.
+///
+/// Only kernels before 4.12 report this, it has been always 0 since.
+pub(crate) fn discard_zeroes_data(device: &File) -> bool {
+    queue_attribute(device, "discard_zeroes_data").as_deref() == Some("1")
+}
+
+/// True if the device accepts write-zeroes requests, which `punch_hole`
+/// issues with unmapping permitted.
+pub(crate) fn write_zeroes_supported(device: &File) -> bool {
+    queue_attribute(device, "write_zeroes_max_bytes")
+        .and_then(|v| v.parse::<u64>().ok())
+        .is_some_and(|v| v > 0)
+}
+
+fn check(result: c_int) -> io::Result<()> {
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn range_arg(range: &Range<u64>) -> [u64; 2] {
+    [range.start, range.end - range.start]
+}
+
+/// Discards a range of a block device.
+pub(crate) fn discard(device: &File, range: &Range<u64>) -> io::Result<()> {
+    let mut arg = range_arg(range);
+    check(unsafe { ioctl(device.as_raw_fd(), blkdiscard(), &mut arg as *mut [u64; 2]) })
+}
+
+/// Zeroes a range of a block device, allowing the device to unmap it.
+///
+/// Uses `fallocate(PUNCH_HOLE)` on the block device, which the kernel
+/// issues as a write-zeroes request with unmapping permitted.  Falls back to
+/// the `BLKZEROOUT` ioctl when that is unsupported, which may have the
+/// kernel write zeros itself.
+pub(crate) fn zeroout(device: &File, range: &Range<u64>) -> io::Result<()> {
+    match punch_hole(device, range) {
+        Err(e) if e.raw_os_error() == Some(EOPNOTSUPP) => {
+            let mut arg = range_arg(range);
+            check(unsafe { ioctl(device.as_raw_fd(), blkzeroout(), &mut arg as *mut [u64; 2]) })
+        }
+        result => result,
+    }
+}
+
+/// Deallocates a range of a regular file (or block device), leaving it reading as zeros.
+pub(crate) fn punch_hole(f: &File, range: &Range<u64>) -> io::Result<()> {
+    check(unsafe {
+        fallocate(
+            f.as_raw_fd(),
+            FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+            range.start.try_into().unwrap(),
+            (range.end - range.start).try_into().unwrap(),
+        )
+    })
+}
+
+/// The next range of data in a regular file at or after `offset`, from
+/// `SEEK_DATA` and `SEEK_HOLE`, or `None` if only a hole follows.
+pub(crate) fn next_data(f: &File, offset: u64) -> io::Result<Option<Range<u64>>> {
+    let seek = |offset: u64, whence| match unsafe {
+        lseek(f.as_raw_fd(), offset.try_into().unwrap(), whence)
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        result => Ok(u64::try_from(result).unwrap()),
+    };
+    let start = match seek(offset, SEEK_DATA) {
+        Err(e) if e.raw_os_error() == Some(ENXIO) => return Ok(None),
+        result => result?,
+    };
+    Ok(Some(start..seek(start, SEEK_HOLE)?))
+}
+
+const ENXIO: i32 = 6;
+const EOPNOTSUPP: i32 = 95;
+const ENOTTY: i32 = 25;
+const EINVAL: i32 = 22;
+
+/// True for errors meaning "this operation isn't supported here", rather than an IO failure.
+pub(crate) fn is_unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported
+        || matches!(e.raw_os_error(), Some(EOPNOTSUPP | ENOTTY | EINVAL))
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_hex::assert_eq_hex;
+
+    use crate::tests::init_logger;
+
+    use super::{blkdiscard, blkpbszget, blksszget, blkzeroout, dev_num};
+
+    #[test]
+    fn ioctl_values() {
+        init_logger();
+        assert_eq_hex!(0x1277, blkdiscard());
+        assert_eq_hex!(0x127f, blkzeroout());
+        assert_eq_hex!(0x1268, blksszget());
+        assert_eq_hex!(0x127b, blkpbszget());
+    }
+
+    #[test]
+    fn dev_numbers() {
+        init_logger();
+        assert_eq!(dev_num(0x0803), (8, 3));
+        assert_eq!(dev_num(0xfd00), (253, 0));
+        assert_eq!(dev_num(0x1000_0010_0345), (0x1003, 0x145));
+    }
+}
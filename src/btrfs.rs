@@ -0,0 +1,328 @@
+use std::{
+    ffi::{c_int, c_long},
+    fs::File,
+    io,
+    ops::Range,
+    os::{fd::AsRawFd, raw::c_ulong},
+};
+
+use log::{debug, info};
+
+use crate::{fiemap::ioctl, ResultType};
+
+extern "C" {
+    fn fstatfs(fd: c_int, buf: *mut Statfs) -> c_int;
+}
+
+const BTRFS_SUPER_MAGIC: c_long = 0x9123683E;
+const BTRFS_IOCTL_MAGIC: u64 = 0x94;
+const BTRFS_CHUNK_TREE_OBJECTID: u64 = 3;
+const BTRFS_FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+const BTRFS_CHUNK_ITEM_KEY: u32 = 228;
+
+/// Block group profile bits, anything other than "single".
+const BTRFS_BLOCK_GROUP_PROFILE_MASK: u64 = 0x8 // RAID0
+    | 0x10 // RAID1
+    | 0x20 // DUP
+    | 0x40 // RAID10
+    | 0x80 // RAID5
+    | 0x100 // RAID6
+    | 0x200 // RAID1C3
+    | 0x400; // RAID1C4
+
+/// Only the leading `f_type` field is used, the rest is padding
+/// generously sized for every architecture's `struct statfs`.
+#[repr(C)]
+struct Statfs {
+    f_type: c_long,
+    _rest: [u64; 32],
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct BtrfsIoctlSearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    nr_items: u32,
+    unused: u32,
+    unused1: u64,
+    unused2: u64,
+    unused3: u64,
+    unused4: u64,
+}
+
+#[repr(C)]
+struct BtrfsIoctlSearchArgs {
+    key: BtrfsIoctlSearchKey,
+    buf: [u8; 4096 - std::mem::size_of::<BtrfsIoctlSearchKey>()],
+}
+
+#[repr(C)]
+struct BtrfsIoctlFsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    rest: [u8; 1024 - 16],
+}
+
+/// Size of `struct btrfs_ioctl_search_header`.
+const SEARCH_HEADER_LEN: usize = 32;
+/// Size of `struct btrfs_chunk`, excluding the trailing stripes.
+const CHUNK_HEADER_LEN: usize = 48;
+/// Size of `struct btrfs_stripe`.
+const STRIPE_LEN: usize = 32;
+
+fn btrfs_ioc(dir: u64, nr: u64, size: usize) -> c_ulong {
+    (dir << 30) | ((u64::try_from(size).unwrap() & 0x3FFF) << 16) | (BTRFS_IOCTL_MAGIC << 8) | nr
+}
+
+/// The value of BTRFS_IOC_TREE_SEARCH.
+fn btrfs_ioc_tree_search() -> c_ulong {
+    btrfs_ioc(0b11, 17, std::mem::size_of::<BtrfsIoctlSearchArgs>())
+}
+
+/// The value of BTRFS_IOC_FS_INFO.
+fn btrfs_ioc_fs_info() -> c_ulong {
+    btrfs_ioc(0b10, 31, std::mem::size_of::<BtrfsIoctlFsInfoArgs>())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Chunk {
+    logical: Range<u64>,
+    flags: u64,
+    /// Device offsets of each stripe.
+    stripes: Vec<u64>,
+}
+
+/// Translates btrfs logical addresses (as reported by FIEMAP) to offsets on
+/// the single device backing the filesystem.
+pub(crate) struct ChunkMap {
+    chunks: Vec<Chunk>,
+}
+
+impl ChunkMap {
+    /// Builds the chunk map for the filesystem hosting `file`, or returns
+    /// `None` if the file is not on btrfs.
+    ///
+    /// Reading the chunk tree requires `CAP_SYS_ADMIN`.
+    pub fn for_file(file: &File) -> ResultType<Option<Self>> {
+        let mut st = Statfs {
+            f_type: 0,
+            _rest: [0; 32],
+        };
+        if unsafe { fstatfs(file.as_raw_fd(), &mut st) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if st.f_type != BTRFS_SUPER_MAGIC {
+            return Ok(None);
+        }
+        info!("File is on btrfs, translating logical addresses via the chunk tree.");
+
+        let mut fs_info = BtrfsIoctlFsInfoArgs {
+            max_id: 0,
+            num_devices: 0,
+            rest: [0; 1024 - 16],
+        };
+        if unsafe {
+            ioctl(
+                file.as_raw_fd(),
+                btrfs_ioc_fs_info(),
+                &mut fs_info as *mut BtrfsIoctlFsInfoArgs,
+            )
+        } != 0
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+        if fs_info.num_devices != 1 {
+            return Err(format!(
+                "Btrfs filesystem spans {} devices, only single device filesystems are supported.",
+                fs_info.num_devices
+            )
+            .into());
+        }
+
+        let mut chunks = Vec::new();
+        let mut min_offset = 0u64;
+        loop {
+            let mut args = BtrfsIoctlSearchArgs {
+                key: BtrfsIoctlSearchKey {
+                    tree_id: BTRFS_CHUNK_TREE_OBJECTID,
+                    min_objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+                    max_objectid: BTRFS_FIRST_CHUNK_TREE_OBJECTID,
+                    min_offset,
+                    max_offset: u64::MAX,
+                    max_transid: u64::MAX,
+                    min_type: BTRFS_CHUNK_ITEM_KEY,
+                    max_type: BTRFS_CHUNK_ITEM_KEY,
+                    nr_items: u32::MAX,
+                    ..Default::default()
+                },
+                buf: [0; 4096 - std::mem::size_of::<BtrfsIoctlSearchKey>()],
+            };
+            if unsafe {
+                ioctl(
+                    file.as_raw_fd(),
+                    btrfs_ioc_tree_search(),
+                    &mut args as *mut BtrfsIoctlSearchArgs,
+                )
+            } != 0
+            {
+                return Err(format!(
+                    "Failed to read btrfs chunk tree (root required?): {}",
+                    io::Error::last_os_error()
+                )
+                .into());
+            }
+            if args.key.nr_items == 0 {
+                break;
+            }
+
+            let mut pos = 0usize;
+            for _ in 0..args.key.nr_items {
+                let header = &args.buf[pos..(pos + SEARCH_HEADER_LEN)];
+                let offset = le64(header, 16);
+                let item_type = le32(header, 24);
+                let len = usize::try_from(le32(header, 28)).unwrap();
+                let item = &args.buf[(pos + SEARCH_HEADER_LEN)..(pos + SEARCH_HEADER_LEN + len)];
+                if item_type == BTRFS_CHUNK_ITEM_KEY {
+                    chunks.push(parse_chunk(offset, item)?);
+                }
+                pos += SEARCH_HEADER_LEN + len;
+                min_offset = offset.saturating_add(1);
+            }
+            if min_offset == u64::MAX {
+                break;
+            }
+        }
+        debug!("Btrfs chunks: {:?}", chunks);
+
+        Ok(Some(Self { chunks }))
+    }
+
+    /// Translates a logical range to device offsets, as (offset, length)
+    /// for each chunk the range spans.  Data chunks are usually adjacent,
+    /// and FIEMAP merges extents across them.
+    ///
+    /// Every chunk must have the "single" profile.
+    pub fn to_physical(&self, logical: u64, length: u64) -> ResultType<Vec<(u64, u64)>> {
+        let mut pieces = Vec::new();
+        let mut pos = logical;
+        let end = logical + length;
+        while pos < end {
+            let chunk = self
+                .chunks
+                .iter()
+                .find(|c| c.logical.contains(&pos))
+                .ok_or_else(|| format!("No btrfs chunk maps logical address {}.", pos))?;
+            if chunk.flags & BTRFS_BLOCK_GROUP_PROFILE_MASK != 0 || chunk.stripes.len() != 1 {
+                return Err(format!(
+                    "Extent at logical address {} is in a RAID/DUP block group (flags 0x{:x}), only the single profile is supported.",
+                    pos, chunk.flags
+                )
+                .into());
+            }
+            let piece_end = u64::min(end, chunk.logical.end);
+            pieces.push((
+                chunk.stripes[0] + (pos - chunk.logical.start),
+                piece_end - pos,
+            ));
+            pos = piece_end;
+        }
+        Ok(pieces)
+    }
+}
+
+fn parse_chunk(logical: u64, item: &[u8]) -> ResultType<Chunk> {
+    if item.len() < CHUNK_HEADER_LEN {
+        return Err("Truncated btrfs chunk item.".into());
+    }
+    let num_stripes = usize::from(u16::from_le_bytes(item[44..46].try_into().unwrap()));
+    if item.len() < CHUNK_HEADER_LEN + num_stripes * STRIPE_LEN {
+        return Err("Truncated btrfs chunk item.".into());
+    }
+    Ok(Chunk {
+        logical: logical..(logical + le64(item, 0)),
+        flags: le64(item, 24),
+        stripes: (0..num_stripes)
+            .map(|i| le64(item, CHUNK_HEADER_LEN + i * STRIPE_LEN + 8))
+            .collect(),
+    })
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..(offset + 4)].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..(offset + 8)].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_hex::assert_eq_hex;
+
+    use crate::tests::init_logger;
+
+    use super::{btrfs_ioc_fs_info, btrfs_ioc_tree_search, parse_chunk, Chunk, ChunkMap};
+
+    #[test]
+    fn ioctl_values() {
+        init_logger();
+        assert_eq_hex!(0xD0009411, btrfs_ioc_tree_search());
+        assert_eq_hex!(0x8400941F, btrfs_ioc_fs_info());
+    }
+
+    fn chunk_item(length: u64, flags: u64, stripes: &[u64]) -> Vec<u8> {
+        let mut item = vec![0u8; 48];
+        item[0..8].copy_from_slice(&length.to_le_bytes());
+        item[24..32].copy_from_slice(&flags.to_le_bytes());
+        item[44..46].copy_from_slice(&u16::try_from(stripes.len()).unwrap().to_le_bytes());
+        for s in stripes {
+            let mut stripe = vec![0u8; 32];
+            stripe[0..8].copy_from_slice(&1u64.to_le_bytes());
+            stripe[8..16].copy_from_slice(&s.to_le_bytes());
+            item.extend(stripe);
+        }
+        item
+    }
+
+    #[test]
+    fn translation() {
+        init_logger();
+        let single = parse_chunk(1 << 30, &chunk_item(1 << 20, 0x1, &[5 << 20])).unwrap();
+        assert_eq!(
+            single,
+            Chunk {
+                logical: (1 << 30)..((1 << 30) + (1 << 20)),
+                flags: 0x1,
+                stripes: vec![5 << 20],
+            }
+        );
+        let raid1 = parse_chunk(2 << 30, &chunk_item(1 << 20, 0x11, &[6 << 20, 7 << 20])).unwrap();
+        // Adjacent to the first chunk, but elsewhere on the device.
+        let adjacent =
+            parse_chunk((1 << 30) + (1 << 20), &chunk_item(1 << 20, 0x1, &[2 << 20])).unwrap();
+        let map = ChunkMap {
+            chunks: vec![single, raid1, adjacent],
+        };
+
+        assert_eq!(
+            map.to_physical((1 << 30) + 4096, 4096).unwrap(),
+            [((5 << 20) + 4096, 4096)]
+        );
+        assert_eq!(
+            map.to_physical((1 << 30) + 4096, 1 << 20).unwrap(),
+            [((5 << 20) + 4096, (1 << 20) - 4096), (2 << 20, 4096)]
+        );
+        assert!(map.to_physical((1 << 30) + 4096, 2 << 20).is_err());
+        assert!(map.to_physical(2 << 30, 4096).is_err());
+        assert!(map.to_physical(0, 4096).is_err());
+        assert!(parse_chunk(0, &chunk_item(1, 1, &[1])[0..60]).is_err());
+    }
+}
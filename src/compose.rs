@@ -0,0 +1,315 @@
+use std::{io, ops::Range};
+
+use log::{debug, info};
+
+use crate::{
+    device::BlockDevice,
+    error::LoopliftError,
+    progress::PhaseProgress,
+    report::{ExtentSource, ReportExtent, ReportReader, ReportSummary, ReportWriter},
+    utils::{validate_device_size, FileOps, Segment},
+    ResultType,
+};
+
+/// Combines two reports for nested images into one.
+///
+/// The inner report maps the innermost file onto the loop file described by
+/// the outer report, which in turn maps that loop file onto `device`.  The
+/// result maps the innermost file directly onto `device`.  Every extent is
+/// verified against `device`.
+pub fn do_compose(
+    device: &impl BlockDevice,
+    inner: &mut impl io::Read,
+    outer: &mut impl io::Read,
+    out: &mut impl io::Write,
+) -> ResultType<()> {
+    let outer_reader = ReportReader::new(outer)?;
+    let outer_length = outer_reader.summary().device_length;
+    let outer_extents = outer_reader.collect::<ResultType<Vec<ReportExtent>>>()?;
+    info!("Loaded {} outer extents.", outer_extents.len());
+    let physical_length = device.length()?;
+    for o in &outer_extents {
+        if let ExtentSource::Offset { offset, .. } = o.source {
+            if offset
+                .checked_add(o.length)
+                .is_none_or(|end| end > physical_length)
+            {
+                return Err(LoopliftError::ReportInconsistent(format!(
+                    "source of outer extent at offset {} lies beyond the end of the device.",
+                    o.destination_offset
+                )));
+            }
+        }
+    }
+
+    let inner_reader = ReportReader::new(inner)?;
+    let device_length = inner_reader.summary().device_length;
+    validate_device_size(device, device_length)?;
+    let mut writer = ReportWriter::new(out);
+    writer.write_summary(&ReportSummary { device_length })?;
+
+    let mut fops = FileOps::new(true);
+    let mut pb = PhaseProgress::new("Composing", device_length, &fops);
+
+    for e in inner_reader {
+        let e = e?;
+        pb.set(e.destination_offset);
+
+        let (offset, checksum) = match e.source {
+            ExtentSource::Zeros => {
+                writer.write(&e)?;
+                continue;
+            }
+            ExtentSource::Offset { offset, checksum } => (offset, checksum),
+        };
+        let source = match offset.checked_add(e.length) {
+            Some(end) if end <= outer_length => offset..end,
+            _ => {
+                return Err(LoopliftError::ReportInconsistent(format!(
+                    "extent at offset {} lies beyond the end of the outer report.",
+                    e.destination_offset
+                )))
+            }
+        };
+
+        let segments = map_through(&outer_extents, &source);
+        debug!("Extent {:?} maps to {:?}", e, segments);
+        if fops.compute_checksum_of_segments(device, &segments)? != checksum {
+            return Err(LoopliftError::ChecksumMismatch {
+                offset,
+                length: e.length,
+            });
+        }
+
+        let mut destination_offset = e.destination_offset;
+        for segment in &segments {
+            let (length, source) = match segment {
+                Segment::Zeros(length) => (*length, ExtentSource::Zeros),
+                Segment::Data(range) => {
+                    let length = range.end - range.start;
+                    let checksum = match segments.len() {
+                        1 => checksum,
+                        _ => fops.compute_checksum(device, range.start, length)?,
+                    };
+                    let source = ExtentSource::Offset {
+                        offset: range.start,
+                        checksum,
+                    };
+                    (length, source)
+                }
+            };
+            writer.write(&ReportExtent {
+                destination_offset,
+                length,
+                source,
+            })?;
+            destination_offset += length;
+        }
+    }
+    pb.set(device_length);
+    pb.finish(&fops);
+
+    fops.log_stats();
+
+    Ok(())
+}
+
+/// Maps a range of the outer report's file through that report, merging
+/// neighbouring pieces where possible.
+fn map_through(outer: &[ReportExtent], range: &Range<u64>) -> Vec<Segment> {
+    let first = outer.partition_point(|o| o.destination_offset + o.length <= range.start);
+
+    let mut result: Vec<Segment> = Vec::new();
+    for o in &outer[first..] {
+        if o.destination_offset >= range.end {
+            break;
+        }
+        let start = u64::max(range.start, o.destination_offset);
+        let end = u64::min(range.end, o.destination_offset + o.length);
+        let segment = match o.source {
+            ExtentSource::Zeros => Segment::Zeros(end - start),
+            ExtentSource::Offset { offset, .. } => Segment::Data(
+                (offset + (start - o.destination_offset))..(offset + (end - o.destination_offset)),
+            ),
+        };
+        match (result.last_mut(), segment) {
+            (Some(Segment::Zeros(last)), Segment::Zeros(length)) => *last += length,
+            (Some(Segment::Data(last)), Segment::Data(r)) if last.end == r.start => {
+                last.end = r.end
+            }
+            (_, segment) => result.push(segment),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        device::{BlockDevice, MemDevice},
+        error::LoopliftError,
+        report::{ExtentSource, ReportExtent, ReportReader, ReportSummary, ReportWriter},
+        tests::init_logger,
+        utils::{FileOps, Segment},
+        ResultType,
+    };
+
+    use super::{do_compose, map_through};
+
+    fn extent(destination_offset: u64, length: u64, offset: Option<u64>) -> ReportExtent {
+        ReportExtent {
+            destination_offset,
+            length,
+            source: match offset {
+                Some(offset) => ExtentSource::Offset {
+                    offset,
+                    checksum: 0,
+                },
+                None => ExtentSource::Zeros,
+            },
+        }
+    }
+
+    #[test]
+    fn mapping() {
+        init_logger();
+        let outer = vec![
+            extent(0, 100, Some(1000)),
+            extent(100, 50, Some(1100)),
+            extent(150, 50, None),
+            extent(200, 50, None),
+            extent(250, 100, Some(5000)),
+        ];
+
+        assert_eq!(
+            map_through(&outer, &(10..20)),
+            vec![Segment::Data(1010..1020)]
+        );
+        assert_eq!(
+            map_through(&outer, &(50..140)),
+            vec![Segment::Data(1050..1140)]
+        );
+        assert_eq!(map_through(&outer, &(160..240)), vec![Segment::Zeros(80)]);
+        assert_eq!(
+            map_through(&outer, &(90..300)),
+            vec![
+                Segment::Data(1090..1150),
+                Segment::Zeros(100),
+                Segment::Data(5000..5050)
+            ]
+        );
+        assert_eq!(
+            map_through(&outer, &(349..350)),
+            vec![Segment::Data(5099..5100)]
+        );
+    }
+    /// Serializes a report, with the checksum of each data extent taken
+    /// from `device`.  Sources beyond its end keep a checksum of zero.
+    fn write_report(
+        device: &impl BlockDevice,
+        device_length: u64,
+        extents: &[ReportExtent],
+    ) -> ResultType<Vec<u8>> {
+        let length = device.length()?;
+        let mut fops = FileOps::new(true);
+        let mut report = Vec::new();
+        let mut writer = ReportWriter::new(&mut report);
+        writer.write_summary(&ReportSummary { device_length })?;
+        for e in extents {
+            let mut e = e.clone();
+            if let ExtentSource::Offset { offset, checksum } = &mut e.source {
+                if offset
+                    .checked_add(e.length)
+                    .is_some_and(|end| end <= length)
+                {
+                    *checksum = fops.compute_checksum(device, *offset, e.length)?;
+                }
+            }
+            writer.write(&e)?;
+        }
+        Ok(report)
+    }
+
+    #[test]
+    fn composing() -> ResultType<()> {
+        init_logger();
+        let device = MemDevice::from((0..16384).map(|i| (i % 251 + 1) as u8).collect::<Vec<u8>>());
+
+        // A loop file of 4 KiB with a hole in the middle.
+        let outer_extents = vec![
+            extent(0, 2048, Some(8192)),
+            extent(2048, 1024, None),
+            extent(3072, 1024, Some(12288)),
+        ];
+        let outer = write_report(&device, 4096, &outer_extents)?;
+        let mut content = vec![0u8; 4096];
+        device.read_at(&mut content[..2048], 8192)?;
+        device.read_at(&mut content[3072..], 12288)?;
+        let loop_file = MemDevice::from(content);
+
+        let compose = |inner: &[ReportExtent], outer: &[u8]| -> ResultType<Vec<ReportExtent>> {
+            let inner = write_report(&loop_file, 4096, inner)?;
+            let mut out = Vec::new();
+            do_compose(&device, &mut inner.as_slice(), &mut &outer[..], &mut out)?;
+            ReportReader::new(out.as_slice())?.collect()
+        };
+
+        // Zeros come from the inner report and from the hole in the outer
+        // one, and the last extent is split where the outer extents end.
+        let composed = compose(
+            &[
+                extent(0, 1024, Some(0)),
+                extent(1024, 512, None),
+                extent(1536, 512, Some(2304)),
+                extent(2048, 2048, Some(1536)),
+            ],
+            &outer,
+        )?;
+        let expected = write_report(
+            &device,
+            4096,
+            &[
+                extent(0, 1024, Some(8192)),
+                extent(1024, 512, None),
+                extent(1536, 512, None),
+                extent(2048, 512, Some(9728)),
+                extent(2560, 1024, None),
+                extent(3584, 512, Some(12288)),
+            ],
+        )?;
+        let expected: Vec<ReportExtent> =
+            ReportReader::new(expected.as_slice())?.collect::<ResultType<_>>()?;
+        assert_eq!(composed, expected);
+
+        // An inner checksum taken from the device instead of the loop file.
+        let inner = write_report(&device, 4096, &[extent(0, 1024, Some(0))])?;
+        let result = do_compose(
+            &device,
+            &mut inner.as_slice(),
+            &mut &outer[..],
+            &mut Vec::new(),
+        );
+        assert!(matches!(
+            result,
+            Err(LoopliftError::ChecksumMismatch { .. })
+        ));
+
+        // Inner sources beyond the loop file, or overflowing.
+        for offset in [3584, u64::MAX - 100] {
+            assert!(matches!(
+                compose(&[extent(0, 1024, Some(offset))], &outer),
+                Err(LoopliftError::ReportInconsistent(_))
+            ));
+        }
+
+        // Outer sources beyond the device, or overflowing.
+        for offset in [16000, u64::MAX - 100] {
+            let outer = write_report(&device, 4096, &[extent(0, 4096, Some(offset))])?;
+            assert!(matches!(
+                compose(&[extent(0, 1024, Some(0))], &outer),
+                Err(LoopliftError::ReportInconsistent(_))
+            ));
+        }
+        Ok(())
+    }
+}
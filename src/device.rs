@@ -0,0 +1,331 @@
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom},
+    ops::Range,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::fs::{FileExt, FileTypeExt},
+    },
+};
+
+pub use crate::blkdev::QueueLimits;
+use crate::{blkdev, utils::ZeroStrategy};
+
+pub use faulty::FaultyDevice;
+pub use mem::MemDevice;
+pub use overlay::OverlayDevice;
+
+mod faulty;
+mod mem;
+mod overlay;
+
+/// Somewhere to lift onto: a block device, a regular file, or a stand-in
+/// for one in tests.
+///
+/// Reads and writes are positional and complete, as with `read_exact_at`
+/// and `write_all_at`.  The optional operations default to failing with
+/// `io::ErrorKind::Unsupported`.
+pub trait BlockDevice: Send + Sync {
+    /// Fills `buf` from `offset`, failing if the device ends first.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Writes all of `buf` at `offset`, failing if the device ends first.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Waits for all writes so far to reach stable storage.
+    fn flush(&self) -> io::Result<()>;
+
+    /// Length in bytes.
+    fn length(&self) -> io::Result<u64>;
+
+    /// Zeroes `range` by means other than writing zeros, `strategy` being
+    /// one of `Zeroout`, `Discard` and `PunchHole`.
+    fn zero(&self, strategy: ZeroStrategy, range: &Range<u64>) -> io::Result<()> {
+        let _ = (strategy, range);
+        Err(unsupported())
+    }
+
+    /// Discards `range`, which reads as zeros afterwards if
+    /// `discard_zeroes_data`.
+    fn discard(&self, range: &Range<u64>) -> io::Result<()> {
+        let _ = range;
+        Err(unsupported())
+    }
+
+    /// True if discarded ranges are guaranteed to read back as zeros.
+    fn discard_zeroes_data(&self) -> bool {
+        false
+    }
+
+    /// The strategy chosen for `ZeroStrategy::Auto`.
+    fn auto_zero_strategy(&self) -> io::Result<ZeroStrategy> {
+        Ok(ZeroStrategy::Write)
+    }
+
+    /// The next range at or after `offset` which may hold data, or `None`
+    /// if only zeros follow.  Only regular files know where their holes are.
+    fn next_data(&self, offset: u64) -> io::Result<Option<Range<u64>>> {
+        Ok(Some(offset..u64::MAX))
+    }
+
+    /// Logical and physical block sizes, needed for direct IO.
+    fn block_sizes(&self) -> io::Result<(u64, u64)> {
+        Err(unsupported())
+    }
+
+    /// Queue limits, for choosing an IO size, if this is a block device.
+    fn queue_limits(&self) -> Option<QueueLimits> {
+        None
+    }
+
+    /// A file descriptor, for queueing IO through io_uring, if there is one.
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// The error for optional operations which a device does not support.
+fn unsupported() -> io::Error {
+    io::ErrorKind::Unsupported.into()
+}
+
+/// Regular files and block devices, which is to say the real thing.
+impl BlockDevice for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write_all_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    /// Found by seeking to the end, which gives the size of both regular
+    /// files and block devices (and unlike reading the last byte, works
+    /// with O_DIRECT).
+    fn length(&self) -> io::Result<u64> {
+        let mut f = self;
+        f.seek(SeekFrom::End(0))
+    }
+
+    fn zero(&self, strategy: ZeroStrategy, range: &Range<u64>) -> io::Result<()> {
+        match strategy {
+            ZeroStrategy::Zeroout => blkdev::zeroout(self, range),
+            ZeroStrategy::Discard => self.discard(range),
+            ZeroStrategy::PunchHole => blkdev::punch_hole(self, range),
+            ZeroStrategy::Auto | ZeroStrategy::Write => Err(unsupported()),
+        }
+    }
+
+    /// Punches a hole in regular files.  Block devices get BLKDISCARD where
+    /// the kernel still reports `discard_zeroes_data` (before Linux 4.12),
+    /// and otherwise a write-zeroes request permitting the device to unmap.
+    fn discard(&self, range: &Range<u64>) -> io::Result<()> {
+        let file_type = self.metadata()?.file_type();
+        if file_type.is_block_device() && blkdev::discard_zeroes_data(self) {
+            blkdev::discard(self, range)
+        } else {
+            blkdev::punch_hole(self, range)
+        }
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        match self.metadata().map(|m| m.file_type()) {
+            Ok(t) if t.is_file() => true,
+            Ok(t) if t.is_block_device() => {
+                blkdev::discard_zeroes_data(self) || blkdev::write_zeroes_supported(self)
+            }
+            _ => false,
+        }
+    }
+
+    fn auto_zero_strategy(&self) -> io::Result<ZeroStrategy> {
+        let file_type = self.metadata()?.file_type();
+        Ok(if file_type.is_file() {
+            ZeroStrategy::PunchHole
+        } else if file_type.is_block_device() && blkdev::discard_zeroes_data(self) {
+            ZeroStrategy::Discard
+        } else if file_type.is_block_device() {
+            ZeroStrategy::Zeroout
+        } else {
+            ZeroStrategy::Write
+        })
+    }
+
+    fn next_data(&self, offset: u64) -> io::Result<Option<Range<u64>>> {
+        match self.metadata()?.file_type().is_file() {
+            true => blkdev::next_data(self, offset),
+            false => Ok(Some(offset..u64::MAX)),
+        }
+    }
+
+    fn block_sizes(&self) -> io::Result<(u64, u64)> {
+        blkdev::block_sizes(self)
+    }
+
+    fn queue_limits(&self) -> Option<QueueLimits> {
+        blkdev::queue_limits(self)
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for &D {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        (**self).write_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn length(&self) -> io::Result<u64> {
+        (**self).length()
+    }
+
+    fn zero(&self, strategy: ZeroStrategy, range: &Range<u64>) -> io::Result<()> {
+        (**self).zero(strategy, range)
+    }
+
+    fn discard(&self, range: &Range<u64>) -> io::Result<()> {
+        (**self).discard(range)
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        (**self).discard_zeroes_data()
+    }
+
+    fn auto_zero_strategy(&self) -> io::Result<ZeroStrategy> {
+        (**self).auto_zero_strategy()
+    }
+
+    fn next_data(&self, offset: u64) -> io::Result<Option<Range<u64>>> {
+        (**self).next_data(offset)
+    }
+
+    fn block_sizes(&self) -> io::Result<(u64, u64)> {
+        (**self).block_sizes()
+    }
+
+    fn queue_limits(&self) -> Option<QueueLimits> {
+        (**self).queue_limits()
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+}
+
+/// Checks that `range` lies within a device of `length` bytes, giving the
+/// error a short read or write would.
+fn check_range(range: &Range<u64>, length: u64, write: bool) -> io::Result<()> {
+    if range.end <= length {
+        return Ok(());
+    }
+    Err(match write {
+        true => io::Error::new(
+            io::ErrorKind::WriteZero,
+            "write beyond the end of the device",
+        ),
+        false => io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "read beyond the end of the device",
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        tests::{init_logger, scratch_file},
+        utils::ZeroStrategy,
+        ResultType,
+    };
+
+    use super::{BlockDevice, FaultyDevice, MemDevice, OverlayDevice};
+
+    #[test]
+    fn devices_agree() -> ResultType<()> {
+        init_logger();
+        let f = scratch_file("device")?;
+
+        let data: Vec<u8> = (0..(64 * 1024)).map(|i| (i % 251) as u8).collect();
+        f.set_len(data.len() as u64)?;
+        f.write_at(&data, 0)?;
+        let mem = MemDevice::from(data.clone());
+        let overlay = OverlayDevice::new(MemDevice::from(data.clone()));
+
+        let devices: [&dyn BlockDevice; 3] = [&f, &mem, &overlay];
+        for d in devices {
+            assert_eq!(d.length()?, 64 * 1024);
+            d.write_at(&[0xaa; 5000], 3000)?;
+            d.zero(d.auto_zero_strategy()?, &(16384..24576))
+                .or_else(|_| d.write_at(&[0; 8192], 16384))?;
+            d.flush()?;
+            let mut buf = vec![0u8; 64 * 1024];
+            d.read_at(&mut buf, 0)?;
+            let mut expected = data.clone();
+            expected[3000..8000].fill(0xaa);
+            expected[16384..24576].fill(0);
+            assert!(buf == expected);
+
+            assert!(d.read_at(&mut [0; 2], 64 * 1024 - 1).is_err());
+        }
+        // Unlike regular files, these cannot grow.
+        assert!(mem.write_at(&[0; 2], 64 * 1024 - 1).is_err());
+        assert!(overlay.write_at(&[0; 2], 64 * 1024 - 1).is_err());
+
+        // The overlay's base is untouched.
+        assert_eq!(overlay.changed_bytes(), 4 * 4096);
+        let mut buf = vec![0u8; 64 * 1024];
+        overlay.base().read_at(&mut buf, 0)?;
+        assert!(buf == data);
+        Ok(())
+    }
+
+    #[test]
+    fn faults() -> ResultType<()> {
+        init_logger();
+        let d = FaultyDevice::new(MemDevice::new(8192))
+            .fail_write(2)
+            .fail_range(4096..4608);
+
+        d.write_at(&[1; 512], 0)?;
+        assert!(d.write_at(&[1; 512], 512).is_err());
+        d.write_at(&[1; 512], 1024)?;
+        assert_eq!(d.writes(), 3);
+
+        let e = d.read_at(&mut [0; 512], 4000).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(5));
+        assert!(d.zero(ZeroStrategy::Zeroout, &(4096..8192)).is_err());
+        d.read_at(&mut [0; 512], 4608)?;
+
+        let mut buf = [0u8; 1536];
+        d.inner().read_at(&mut buf, 0)?;
+        assert!(buf[..512] == [1; 512] && buf[512..1024] == [0; 512]);
+
+        // Only flushed writes survive a power loss.
+        let d = FaultyDevice::new(MemDevice::new(4096))
+            .tear_write(2, 100)
+            .lose_power(4);
+        d.write_at(&[1; 512], 0)?;
+        assert!(d.write_at(&[2; 512], 512).is_err());
+        d.flush()?;
+        d.write_at(&[3; 512], 1024)?;
+        assert!(d.write_at(&[4; 512], 1536).is_err());
+        assert!(d.powered_off() && d.read_at(&mut [0; 1], 0).is_err());
+        let content = d.into_inner().into_inner();
+        assert!(content[..512] == [1; 512] && content[512..612] == [2; 100]);
+        assert!(content[612..] == [0; 4096 - 612]);
+        Ok(())
+    }
+}
@@ -0,0 +1,226 @@
+use std::{io, ops::Range, os::fd::RawFd, sync::Mutex};
+
+use crate::utils::ZeroStrategy;
+
+use super::{BlockDevice, QueueLimits};
+
+const EIO: i32 = 5;
+
+/// Wraps a device, injecting failures, for testing how failures part way
+/// through a lift are handled.
+///
+/// Reads and writes are numbered from one, in the order they are attempted.
+/// Zeroing and discards count as writes.  Everything else is passed through,
+/// except that there is no file descriptor, so that io_uring cannot bypass
+/// the faults.
+#[derive(Debug)]
+pub struct FaultyDevice<D> {
+    inner: D,
+    fail_read: Option<u64>,
+    fail_write: Option<u64>,
+    tear_write: Option<(u64, u64)>,
+    power_loss: Option<u64>,
+    fail_ranges: Vec<Range<u64>>,
+    state: Mutex<FaultState>,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    reads: u64,
+    writes: u64,
+    /// Previous content of each range written since the last flush, when
+    /// a power loss is due.
+    unflushed: Vec<(u64, Vec<u8>)>,
+    powered_off: bool,
+}
+
+impl<D: BlockDevice> FaultyDevice<D> {
+    /// Passes everything through, until faults are added.
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            fail_read: None,
+            fail_write: None,
+            tear_write: None,
+            power_loss: None,
+            fail_ranges: Vec::new(),
+            state: Mutex::new(FaultState::default()),
+        }
+    }
+
+    /// Fails the `n`th read.
+    pub fn fail_read(mut self, n: u64) -> Self {
+        self.fail_read = Some(n);
+        self
+    }
+
+    /// Fails the `n`th write, which writes nothing.
+    pub fn fail_write(mut self, n: u64) -> Self {
+        self.fail_write = Some(n);
+        self
+    }
+
+    /// Fails the `n`th write after only its first `length` bytes are written.
+    pub fn tear_write(mut self, n: u64, length: u64) -> Self {
+        self.tear_write = Some((n, length));
+        self
+    }
+
+    /// Loses power instead of performing the `n`th write: every write since
+    /// the last flush is undone, and all IO fails from then on.
+    pub fn lose_power(mut self, n: u64) -> Self {
+        self.power_loss = Some(n);
+        self
+    }
+
+    /// Fails every read and write touching `range`.
+    pub fn fail_range(mut self, range: Range<u64>) -> Self {
+        self.fail_ranges.push(range);
+        self
+    }
+
+    /// Number of reads attempted so far, including the failed ones.
+    pub fn reads(&self) -> u64 {
+        self.state.lock().unwrap().reads
+    }
+
+    /// Number of writes attempted so far, including the failed ones.
+    pub fn writes(&self) -> u64 {
+        self.state.lock().unwrap().writes
+    }
+
+    /// True once power has been lost.
+    pub fn powered_off(&self) -> bool {
+        self.state.lock().unwrap().powered_off
+    }
+
+    /// The wrapped device.
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Unwraps the device.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn check_range(&self, range: &Range<u64>) -> io::Result<()> {
+        match self
+            .fail_ranges
+            .iter()
+            .any(|r| r.start < range.end && range.start < r.end)
+        {
+            true => Err(eio()),
+            false => Ok(()),
+        }
+    }
+
+    fn read(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.reads += 1;
+        if state.powered_off || self.fail_read == Some(state.reads) {
+            return Err(eio());
+        }
+        self.check_range(&(offset..(offset + buf.len() as u64)))?;
+        self.inner.read_at(buf, offset)
+    }
+
+    /// Runs a write to `range` with `write`, unless it is to fail.  A torn
+    /// write writes a prefix of `buf`, or zeros if there is no `buf`.
+    fn write(
+        &self,
+        range: &Range<u64>,
+        buf: Option<&[u8]>,
+        write: impl FnOnce() -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.writes += 1;
+        let n = state.writes;
+        if state.powered_off || self.fail_write == Some(n) {
+            return Err(eio());
+        }
+        if self.power_loss == Some(n) {
+            for (offset, old) in state.unflushed.drain(..).rev() {
+                self.inner.write_at(&old, offset)?;
+            }
+            state.powered_off = true;
+            return Err(eio());
+        }
+        self.check_range(range)?;
+
+        if self.power_loss.is_some_and(|p| p > n) {
+            let mut old = vec![0u8; (range.end - range.start) as usize];
+            self.inner.read_at(&mut old, range.start)?;
+            state.unflushed.push((range.start, old));
+        }
+
+        if let Some((_, length)) = self.tear_write.filter(|&(t, _)| t == n) {
+            let length = length.min(range.end - range.start) as usize;
+            match buf {
+                Some(buf) => self.inner.write_at(&buf[..length], range.start)?,
+                None => self.inner.write_at(&vec![0; length], range.start)?,
+            }
+            return Err(eio());
+        }
+        write()
+    }
+}
+
+fn eio() -> io::Error {
+    io::Error::from_raw_os_error(EIO)
+}
+
+impl<D: BlockDevice> BlockDevice for FaultyDevice<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write(&(offset..(offset + buf.len() as u64)), Some(buf), || {
+            self.inner.write_at(buf, offset)
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.powered_off {
+            return Err(eio());
+        }
+        state.unflushed.clear();
+        self.inner.flush()
+    }
+
+    fn length(&self) -> io::Result<u64> {
+        self.inner.length()
+    }
+
+    /// Torn zeroing leaves a prefix of the range zeroed.
+    fn zero(&self, strategy: ZeroStrategy, range: &Range<u64>) -> io::Result<()> {
+        self.write(range, None, || self.inner.zero(strategy, range))
+    }
+
+    /// Torn discards leave a prefix of the range zeroed.
+    fn discard(&self, range: &Range<u64>) -> io::Result<()> {
+        self.write(range, None, || self.inner.discard(range))
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        self.inner.discard_zeroes_data()
+    }
+
+    fn auto_zero_strategy(&self) -> io::Result<ZeroStrategy> {
+        self.inner.auto_zero_strategy()
+    }
+
+    fn block_sizes(&self) -> io::Result<(u64, u64)> {
+        self.inner.block_sizes()
+    }
+
+    fn queue_limits(&self) -> Option<QueueLimits> {
+        self.inner.queue_limits()
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
@@ -0,0 +1,87 @@
+use std::{io, ops::Range, sync::RwLock};
+
+use crate::utils::ZeroStrategy;
+
+use super::{check_range, BlockDevice};
+
+/// A device held in memory, for tests and simulations.
+///
+/// Every zeroing strategy is supported, and discarded ranges read as zeros.
+#[derive(Debug, Default)]
+pub struct MemDevice {
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemDevice {
+    /// A device of `length` zero bytes.
+    pub fn new(length: usize) -> Self {
+        Self::from(vec![0; length])
+    }
+
+    /// A copy of the whole content.
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+
+    /// The whole content.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data.into_inner().unwrap()
+    }
+
+    fn fill_zeros(&self, range: &Range<u64>) -> io::Result<()> {
+        let mut data = self.data.write().unwrap();
+        check_range(range, data.len() as u64, true)?;
+        data[(range.start as usize)..(range.end as usize)].fill(0);
+        Ok(())
+    }
+}
+
+impl From<Vec<u8>> for MemDevice {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(data),
+        }
+    }
+}
+
+impl BlockDevice for MemDevice {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.data.read().unwrap();
+        let range = offset..(offset + buf.len() as u64);
+        check_range(&range, data.len() as u64, false)?;
+        buf.copy_from_slice(&data[(range.start as usize)..(range.end as usize)]);
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut data = self.data.write().unwrap();
+        let range = offset..(offset + buf.len() as u64);
+        check_range(&range, data.len() as u64, true)?;
+        data[(range.start as usize)..(range.end as usize)].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn length(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn zero(&self, _strategy: ZeroStrategy, range: &Range<u64>) -> io::Result<()> {
+        self.fill_zeros(range)
+    }
+
+    fn discard(&self, range: &Range<u64>) -> io::Result<()> {
+        self.fill_zeros(range)
+    }
+
+    fn discard_zeroes_data(&self) -> bool {
+        true
+    }
+
+    fn auto_zero_strategy(&self) -> io::Result<ZeroStrategy> {
+        Ok(ZeroStrategy::Zeroout)
+    }
+}
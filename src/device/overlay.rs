@@ -0,0 +1,116 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    io,
+    sync::Mutex,
+};
+
+use super::{check_range, BlockDevice};
+
+/// Granularity at which written data is held.
+const BLOCK_LENGTH: u64 = 4096;
+
+/// A copy-on-write layer over a device: writes are kept in memory, and
+/// reads see them in place of the underlying data, which is never written.
+///
+/// Lets a lift be rehearsed for real against a device which must not yet
+/// be modified.
+#[derive(Debug)]
+pub struct OverlayDevice<D> {
+    base: D,
+    /// Blocks which have been written, by block number.
+    blocks: Mutex<BTreeMap<u64, Box<[u8]>>>,
+}
+
+impl<D: BlockDevice> OverlayDevice<D> {
+    /// An overlay with no changes yet.
+    pub fn new(base: D) -> Self {
+        Self {
+            base,
+            blocks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The underlying device, unchanged.
+    pub fn base(&self) -> &D {
+        &self.base
+    }
+
+    /// Bytes held in memory, a whole block for each one written to.
+    pub fn changed_bytes(&self) -> u64 {
+        self.blocks.lock().unwrap().len() as u64 * BLOCK_LENGTH
+    }
+
+    /// Drops the changes, returning the underlying device.
+    pub fn into_base(self) -> D {
+        self.base
+    }
+
+    /// Splits `offset..(offset + length)` into pieces each within one block,
+    /// as (block number, offset within the block, offset within the range, length).
+    fn pieces(offset: u64, length: u64) -> impl Iterator<Item = (u64, usize, usize, usize)> {
+        let mut pos = offset;
+        let end = offset + length;
+        std::iter::from_fn(move || {
+            if pos >= end {
+                return None;
+            }
+            let block = pos / BLOCK_LENGTH;
+            let piece_end = u64::min(end, (block + 1) * BLOCK_LENGTH);
+            let piece = (
+                block,
+                (pos - block * BLOCK_LENGTH) as usize,
+                (pos - offset) as usize,
+                (piece_end - pos) as usize,
+            );
+            pos = piece_end;
+            Some(piece)
+        })
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for OverlayDevice<D> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let length = self.base.length()?;
+        check_range(&(offset..(offset + buf.len() as u64)), length, false)?;
+        let blocks = self.blocks.lock().unwrap();
+        for (block, in_block, in_buf, piece_length) in Self::pieces(offset, buf.len() as u64) {
+            let piece = &mut buf[in_buf..(in_buf + piece_length)];
+            match blocks.get(&block) {
+                Some(data) => piece.copy_from_slice(&data[in_block..(in_block + piece_length)]),
+                None => self.base.read_at(piece, offset + in_buf as u64)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let length = self.base.length()?;
+        check_range(&(offset..(offset + buf.len() as u64)), length, true)?;
+        let mut blocks = self.blocks.lock().unwrap();
+        for (block, in_block, in_buf, piece_length) in Self::pieces(offset, buf.len() as u64) {
+            let data = match blocks.entry(block) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => {
+                    // The final block may be partial.
+                    let start = block * BLOCK_LENGTH;
+                    let mut data =
+                        vec![0u8; (u64::min(length, start + BLOCK_LENGTH) - start) as usize];
+                    self.base.read_at(&mut data, start)?;
+                    e.insert(data.into_boxed_slice())
+                }
+            };
+            data[in_block..(in_block + piece_length)]
+                .copy_from_slice(&buf[in_buf..(in_buf + piece_length)]);
+        }
+        Ok(())
+    }
+
+    /// Nothing is ever written to the underlying device.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn length(&self) -> io::Result<u64> {
+        self.base.length()
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    fs::{self, File},
+    io,
+    ops::Range,
+    os::unix::fs::MetadataExt,
+    process::Command,
+};
+
+use log::{debug, info};
+
+use crate::{
+    blkdev::{dev_num, DevNum},
+    error::LoopliftError,
+    progress::PhaseProgress,
+    report::{ExtentSource, ReportExtent, ReportReader, ReportSummary, ReportWriter},
+    utils::{validate_device_size, FileOps, Segment},
+    ResultType,
+};
+
+const SECTOR_SIZE: u64 = 512;
+
+/// One line of a device-mapper table, in bytes rather than sectors.
+#[derive(Debug, PartialEq, Eq)]
+struct LinearSegment {
+    logical: Range<u64>,
+    underlying_offset: u64,
+}
+
+/// The table of a device-mapper device made entirely of `linear` targets
+/// on a single underlying device.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct LinearTable {
+    underlying: DevNum,
+    segments: Vec<LinearSegment>,
+}
+
+impl LinearTable {
+    /// Reads the table of the device-mapper device `device` using `dmsetup`.
+    pub fn for_device(device: &File) -> ResultType<Self> {
+        let (major, minor) = dev_num(device.metadata()?.rdev());
+        let name = fs::read_to_string(format!("/sys/dev/block/{}:{}/dm/name", major, minor))
+            .map_err(|_| format!("Device {}:{} is not a device-mapper device.", major, minor))?;
+        let name = name.trim();
+
+        let output = Command::new("dmsetup").args(["table", name]).output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "dmsetup table {} failed: {}",
+                name,
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+        let table = Self::parse(std::str::from_utf8(&output.stdout)?)?;
+        info!(
+            "Device-mapper device {} maps linearly onto device {}:{}.",
+            name, table.underlying.0, table.underlying.1
+        );
+        Ok(table)
+    }
+
+    /// Parses `dmsetup table` output.
+    ///
+    /// Errors unless every target is `linear`, all targets use the same
+    /// underlying device, and the targets cover the device contiguously.
+    fn parse(text: &str) -> ResultType<Self> {
+        let mut underlying: Option<DevNum> = None;
+        let mut segments = Vec::new();
+        let mut expected_start = 0u64;
+
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let bad_line = || format!("Unrecognised device-mapper table line: '{}'.", line);
+            if fields.len() < 3 {
+                return Err(bad_line().into());
+            }
+            let start: u64 = fields[0].parse().map_err(|_| bad_line())?;
+            let length: u64 = fields[1].parse().map_err(|_| bad_line())?;
+            if fields[2] != "linear" {
+                return Err(format!(
+                    "Device-mapper target '{}' is not linear, only linear mappings are supported.",
+                    fields[2]
+                )
+                .into());
+            }
+            if fields.len() != 5 || start != expected_start {
+                return Err(bad_line().into());
+            }
+            let dev: DevNum = fields[3]
+                .split_once(':')
+                .and_then(|(a, b)| Some((a.parse().ok()?, b.parse().ok()?)))
+                .ok_or_else(bad_line)?;
+            let offset: u64 = fields[4].parse().map_err(|_| bad_line())?;
+
+            match underlying {
+                None => underlying = Some(dev),
+                Some(u) if u != dev => {
+                    return Err("Device-mapper device spans several underlying devices.".into())
+                }
+                Some(_) => {}
+            }
+            // With the ends in range, so are the starts and everything between.
+            let end = start.checked_add(length).ok_or_else(bad_line)?;
+            let byte_end = end.checked_mul(SECTOR_SIZE).ok_or_else(bad_line)?;
+            offset
+                .checked_add(length)
+                .and_then(|e| e.checked_mul(SECTOR_SIZE))
+                .ok_or_else(bad_line)?;
+            segments.push(LinearSegment {
+                logical: (start * SECTOR_SIZE)..byte_end,
+                underlying_offset: offset * SECTOR_SIZE,
+            });
+            expected_start = end;
+        }
+
+        Ok(Self {
+            underlying: underlying.ok_or("Device-mapper table is empty.")?,
+            segments,
+        })
+    }
+
+    /// Maps a range of the device-mapper device to ranges of the underlying device.
+    fn map(&self, range: &Range<u64>) -> ResultType<Vec<Range<u64>>> {
+        let mut result: Vec<Range<u64>> = Vec::new();
+        let mut offset = range.start;
+        while offset < range.end {
+            let s = self
+                .segments
+                .iter()
+                .find(|s| s.logical.contains(&offset))
+                .ok_or_else(|| format!("Offset {} is beyond the device-mapper table.", offset))?;
+            let end = u64::min(range.end, s.logical.end);
+            let mapped = (s.underlying_offset + (offset - s.logical.start))
+                ..(s.underlying_offset + (end - s.logical.start));
+            match result.last_mut() {
+                Some(last) if last.end == mapped.start => last.end = mapped.end,
+                _ => result.push(mapped),
+            }
+            offset = end;
+        }
+        Ok(result)
+    }
+}
+
+/// Rewrites a report so that sources refer to the device underlying a
+/// linear device-mapper device, rather than the device-mapper device itself.
+///
+/// Every rewritten extent is verified against `device`, the underlying device.
+pub fn do_resolve_dm(
+    dm_device: &File,
+    device: &File,
+    input: &mut impl io::Read,
+    out: &mut impl io::Write,
+) -> ResultType<()> {
+    let table = LinearTable::for_device(dm_device)?;
+    if dev_num(device.metadata()?.rdev()) != table.underlying {
+        return Err(format!(
+            "Device is not {}:{}, the device underlying the device-mapper device.",
+            table.underlying.0, table.underlying.1
+        )
+        .into());
+    }
+
+    let reader = ReportReader::new(input)?;
+    let device_length = reader.summary().device_length;
+    validate_device_size(device, device_length)?;
+    let mut writer = ReportWriter::new(out);
+    writer.write_summary(&ReportSummary { device_length })?;
+
+    let mut fops = FileOps::new(true);
+    let mut pb = PhaseProgress::new("Resolving", device_length, &fops);
+
+    for e in reader {
+        let e = e?;
+        pb.set(e.destination_offset);
+
+        let (offset, checksum) = match e.source {
+            ExtentSource::Zeros => {
+                writer.write(&e)?;
+                continue;
+            }
+            ExtentSource::Offset { offset, checksum } => (offset, checksum),
+        };
+
+        let end = offset.checked_add(e.length).ok_or_else(|| {
+            LoopliftError::ReportInconsistent(format!(
+                "source of extent at offset {} lies beyond the end of the device.",
+                e.destination_offset
+            ))
+        })?;
+        let pieces = table.map(&(offset..end))?;
+        debug!("Extent {:?} maps to {:?}", e, pieces);
+        let segments: Vec<Segment> = pieces.iter().cloned().map(Segment::Data).collect();
+        if fops.compute_checksum_of_segments(device, &segments)? != checksum {
+            return Err(LoopliftError::ChecksumMismatch {
+                offset,
+                length: e.length,
+            });
+        }
+
+        let mut destination_offset = e.destination_offset;
+        for piece in &pieces {
+            let length = piece.end - piece.start;
+            let checksum = match pieces.len() {
+                1 => checksum,
+                _ => fops.compute_checksum(device, piece.start, length)?,
+            };
+            writer.write(&ReportExtent {
+                destination_offset,
+                length,
+                source: ExtentSource::Offset {
+                    offset: piece.start,
+                    checksum,
+                },
+            })?;
+            destination_offset += length;
+        }
+    }
+    pb.set(device_length);
+    pb.finish(&fops);
+
+    fops.log_stats();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::init_logger;
+
+    use super::LinearTable;
+
+    #[test]
+    fn parse_and_map() {
+        init_logger();
+        let table = LinearTable::parse(
+            "0 2048 linear 8:3 4096\n\
+             2048 1024 linear 8:3 100000\n\
+             3072 1024 linear 8:3 101024\n",
+        )
+        .unwrap();
+
+        assert_eq!(table.underlying, (8, 3));
+        assert_eq!(
+            table.map(&(512..1024)).unwrap(),
+            vec![(4096 * 512 + 512)..(4096 * 512 + 1024)]
+        );
+        assert_eq!(
+            table.map(&((2047 * 512)..(4000 * 512))).unwrap(),
+            vec![(6143 * 512)..(6144 * 512), (100000 * 512)..(101952 * 512),]
+        );
+        assert!(table.map(&((4095 * 512)..(4097 * 512))).is_err());
+
+        assert!(LinearTable::parse("0 2048 striped 2 128 8:3 0 8:4 0\n").is_err());
+        assert!(LinearTable::parse("0 2048 linear 8:3 0\n2048 8 linear 8:4 0\n").is_err());
+        assert!(LinearTable::parse("0 2048 linear 8:3 0\n4096 8 linear 8:3 0\n").is_err());
+        assert!(LinearTable::parse("").is_err());
+        assert!(LinearTable::parse(&format!("0 {} linear 8:3 0\n", u64::MAX)).is_err());
+        assert!(LinearTable::parse(&format!("0 2048 linear 8:3 {}\n", u64::MAX / 512)).is_err());
+    }
+}
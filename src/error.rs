@@ -0,0 +1,224 @@
+use std::{error::Error, fmt, io, num::TryFromIntError, str::Utf8Error};
+
+/// Everything that can go wrong, each kind with its own process exit code
+/// (see `exit_code`), so that wrapper scripts can tell whether the device
+/// may have been modified.
+#[derive(Debug)]
+pub enum LoopliftError {
+    /// The report is not valid JSON, or is truncated.
+    ReportParse(serde_json::Error),
+    /// The report is well formed, but describes an impossible layout.
+    ReportInconsistent(String),
+    /// Data on the device does not match the checksum recorded for it.
+    ChecksumMismatch {
+        /// Where the data is on the device.
+        offset: u64,
+        /// Length of the data.
+        length: u64,
+    },
+    /// While scanning, data read through the file differs from the device.
+    DataMismatch {
+        /// Where the data is on the device.
+        offset: u64,
+        /// Length of the data compared.
+        length: u64,
+    },
+    /// The device is smaller than the report says it should be.
+    DeviceTooSmall {
+        /// Length required by the report.
+        required: u64,
+        /// Length of the device.
+        actual: u64,
+    },
+    /// A read or write failed.
+    Io {
+        /// Where on the device (or file) the IO was, if known.
+        offset: Option<u64>,
+        /// The underlying error.
+        source: io::Error,
+    },
+    /// An extent which cannot be lifted, such as a compressed or inline one.
+    UnsupportedExtent(String),
+    /// The device was being written to when the lift failed, so it may
+    /// hold neither the original nor the lifted data.
+    Incomplete(Box<LoopliftError>),
+    /// Anything else, such as invalid options.
+    Other(String),
+}
+
+impl LoopliftError {
+    /// The process exit code for this error.
+    ///
+    /// | Code | Meaning |
+    /// |------|---------|
+    /// | 1    | Other failures, such as invalid options |
+    /// | 2    | Invalid command line (from clap) |
+    /// | 3    | The report could not be parsed |
+    /// | 4    | The report is inconsistent |
+    /// | 5    | Unsupported extent |
+    /// | 6    | The device is too small |
+    /// | 7    | Checksum or data mismatch |
+    /// | 8    | IO error |
+    /// | 9    | The lift failed after the device was modified |
+    ///
+    /// Every code but 9 means nothing has been written to the device.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            LoopliftError::Other(_) => 1,
+            LoopliftError::ReportParse(_) => 3,
+            LoopliftError::ReportInconsistent(_) => 4,
+            LoopliftError::UnsupportedExtent(_) => 5,
+            LoopliftError::DeviceTooSmall { .. } => 6,
+            LoopliftError::ChecksumMismatch { .. } | LoopliftError::DataMismatch { .. } => 7,
+            LoopliftError::Io { .. } => 8,
+            LoopliftError::Incomplete(_) => 9,
+        }
+    }
+
+    /// Marks the error as having happened once the device had been modified.
+    pub fn incomplete(self) -> Self {
+        match self {
+            LoopliftError::Incomplete(_) => self,
+            e => LoopliftError::Incomplete(Box::new(e)),
+        }
+    }
+}
+
+impl fmt::Display for LoopliftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoopliftError::ReportParse(e) => write!(f, "Failed to parse report: {}", e),
+            LoopliftError::ReportInconsistent(message) => {
+                write!(f, "Inconsistent report: {}", message)
+            }
+            LoopliftError::ChecksumMismatch { offset, length } => write!(
+                f,
+                "Checksum mismatch for {} bytes at offset {}.",
+                length, offset
+            ),
+            LoopliftError::DataMismatch { offset, length } => write!(
+                f,
+                "File data does not match the device for {} bytes at offset {}.",
+                length, offset
+            ),
+            LoopliftError::DeviceTooSmall { required, actual } => write!(
+                f,
+                "Device is {} bytes, smaller than the {} bytes required.",
+                actual, required
+            ),
+            LoopliftError::Io {
+                offset: Some(offset),
+                source,
+            } => write!(f, "IO error at offset {}: {}", offset, source),
+            LoopliftError::Io {
+                offset: None,
+                source,
+            } => write!(f, "IO error: {}", source),
+            LoopliftError::UnsupportedExtent(message) => write!(f, "{}", message),
+            LoopliftError::Incomplete(e) => write!(
+                f,
+                "{} The device was being modified and is now in an inconsistent state.",
+                e
+            ),
+            LoopliftError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for LoopliftError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LoopliftError::ReportParse(e) => Some(e),
+            LoopliftError::Io { source, .. } => Some(source),
+            LoopliftError::Incomplete(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoopliftError {
+    fn from(source: io::Error) -> Self {
+        LoopliftError::Io {
+            offset: None,
+            source,
+        }
+    }
+}
+
+impl From<serde_json::Error> for LoopliftError {
+    fn from(e: serde_json::Error) -> Self {
+        match e.is_io() {
+            true => io::Error::from(e).into(),
+            false => LoopliftError::ReportParse(e),
+        }
+    }
+}
+
+impl From<String> for LoopliftError {
+    fn from(message: String) -> Self {
+        LoopliftError::Other(message)
+    }
+}
+
+impl From<&str> for LoopliftError {
+    fn from(message: &str) -> Self {
+        LoopliftError::Other(message.to_string())
+    }
+}
+
+impl From<TryFromIntError> for LoopliftError {
+    fn from(e: TryFromIntError) -> Self {
+        LoopliftError::Other(e.to_string())
+    }
+}
+
+impl From<Utf8Error> for LoopliftError {
+    fn from(e: Utf8Error) -> Self {
+        LoopliftError::Other(e.to_string())
+    }
+}
+
+/// Attaches an offset to IO errors.
+pub(crate) trait IoContext<T> {
+    fn at(self, offset: u64) -> Result<T, LoopliftError>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn at(self, offset: u64) -> Result<T, LoopliftError> {
+        self.map_err(|source| LoopliftError::Io {
+            offset: Some(offset),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::tests::init_logger;
+
+    use super::{IoContext, LoopliftError};
+
+    #[test]
+    fn exit_codes() {
+        init_logger();
+        let parse: LoopliftError = serde_json::from_str::<u64>("{").unwrap_err().into();
+        assert_eq!(parse.exit_code(), 3);
+
+        let io: Result<(), LoopliftError> = Err(io::Error::other("boom")).at(4096);
+        let io = io.unwrap_err();
+        assert_eq!(io.exit_code(), 8);
+        assert_eq!(io.to_string(), "IO error at offset 4096: boom");
+
+        let incomplete = io.incomplete().incomplete();
+        assert_eq!(incomplete.exit_code(), 9);
+        assert!(matches!(
+            incomplete,
+            LoopliftError::Incomplete(ref e) if matches!(**e, LoopliftError::Io { .. })
+        ));
+
+        let other: LoopliftError = "Bad option.".into();
+        assert_eq!(other.exit_code(), 1);
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    os::fd::FromRawFd,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{warn, Level, Log, Metadata, Record};
+use serde::Serialize;
+
+use crate::{utils::FileOpsCounters, ResultType};
+
+/// Where events go, if `--events` was given.
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// One line of the `--events` stream.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// A phase, such as verifying or copying, has begun.
+    PhaseStart {
+        /// Name of the phase.
+        phase: &'a str,
+        /// Bytes the phase will process.
+        total_bytes: u64,
+    },
+    /// Sent every second during a phase.
+    Progress {
+        /// Name of the phase.
+        phase: &'a str,
+        /// Bytes processed so far.
+        done_bytes: u64,
+        /// Bytes the phase will process.
+        total_bytes: u64,
+        /// Recent rate of progress.
+        bytes_per_second: u64,
+        /// Absent until there is a rate to estimate from.
+        eta_seconds: Option<u64>,
+    },
+    /// A phase has completed.
+    PhaseEnd {
+        /// Name of the phase.
+        phase: &'a str,
+        /// Bytes processed.
+        done_bytes: u64,
+        /// How long the phase took.
+        elapsed_seconds: f64,
+        /// IO performed during the phase.
+        counters: FileOpsCounters,
+    },
+    /// Anything logged at warning level or above.
+    Warning {
+        /// The logged message.
+        message: String,
+    },
+    /// Always the last event.
+    Finished {
+        /// The command succeeded.
+        success: bool,
+        /// The process exit code.
+        error_code: i32,
+        /// The error, on failure.
+        error: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    /// Seconds since the Unix epoch.
+    time: f64,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Sends events to `target`, a file descriptor number (as inherited from
+/// the parent process) or otherwise the path of a file to create.
+pub fn open(target: &str) -> ResultType<()> {
+    let file = match target.parse::<i32>() {
+        Ok(fd) if fd > 2 => {
+            // Safety: nothing else in this process uses descriptors it did
+            // not open itself, so this one is ours to own.
+            let file = unsafe { File::from_raw_fd(fd) };
+            if let Err(e) = file.metadata() {
+                // Not open, so must not be closed either.
+                std::mem::forget(file);
+                return Err(format!("Cannot send events to fd {}: {}", fd, e).into());
+            }
+            file
+        }
+        Ok(fd) => return Err(format!("Refusing to send events to fd {}.", fd).into()),
+        Err(_) => File::create(target)?,
+    };
+    // Each line is written whole, so no buffering is needed.
+    *SINK.lock().unwrap() = Some(Box::new(file));
+    Ok(())
+}
+
+pub(crate) fn enabled() -> bool {
+    SINK.lock().unwrap().is_some()
+}
+
+/// Sends an event, if events are being sent anywhere.
+pub fn emit(event: &Event) {
+    let failed = {
+        let mut sink = SINK.lock().unwrap();
+        let Some(out) = sink.as_mut() else {
+            return;
+        };
+        match write_line(out, event) {
+            Ok(()) => None,
+            Err(e) => {
+                *sink = None;
+                Some(e)
+            }
+        }
+    };
+    // Only once the sink is gone, as the warning itself becomes an event.
+    if let Some(e) = failed {
+        warn!("No longer sending events: {}", e);
+    }
+}
+
+fn write_line(out: &mut impl Write, event: &Event) -> io::Result<()> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let mut line = serde_json::to_vec(&Line { time, event })?;
+    line.push(b'\n');
+    out.write_all(&line)
+}
+
+/// Wraps the real logger, copying warnings and errors into the event stream.
+pub struct EventLogger<L: Log> {
+    inner: L,
+}
+
+impl<L: Log> EventLogger<L> {
+    /// Wraps `inner`, which still logs everything as before.
+    pub fn new(inner: L) -> Self {
+        Self { inner }
+    }
+}
+
+impl<L: Log> Log for EventLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() <= Level::Warn && self.inner.enabled(record.metadata()) {
+            emit(&Event::Warning {
+                message: record.args().to_string(),
+            });
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::init_logger, utils::FileOpsCounters, ResultType};
+
+    use super::{write_line, Event};
+
+    #[test]
+    fn lines() -> ResultType<()> {
+        init_logger();
+        let mut out = Vec::new();
+        write_line(
+            &mut out,
+            &Event::PhaseStart {
+                phase: "Moving",
+                total_bytes: 4096,
+            },
+        )?;
+        write_line(
+            &mut out,
+            &Event::PhaseEnd {
+                phase: "Moving",
+                done_bytes: 4096,
+                elapsed_seconds: 1.5,
+                counters: FileOpsCounters {
+                    read_ops: 1,
+                    read_bytes: 4096,
+                    ..Default::default()
+                },
+            },
+        )?;
+        write_line(
+            &mut out,
+            &Event::Finished {
+                success: false,
+                error_code: 1,
+                error: Some("Checksum mismatch".to_string()),
+            },
+        )?;
+
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&out)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| l["time"].as_f64().unwrap() > 0.0));
+        assert_eq!(lines[0]["event"], "phase-start");
+        assert_eq!(lines[0]["total_bytes"], 4096);
+        assert_eq!(lines[1]["event"], "phase-end");
+        assert_eq!(lines[1]["counters"]["read_bytes"], 4096);
+        assert_eq!(lines[1]["counters"]["write_ops"], 0);
+        assert_eq!(lines[2]["event"], "finished");
+        assert_eq!(lines[2]["success"], false);
+        assert_eq!(lines[2]["error"], "Checksum mismatch");
+        Ok(())
+    }
+}
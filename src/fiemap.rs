@@ -105,6 +105,13 @@ pub fn fs_ioc_fiemap() -> c_ulong {
         (11u64)
 }
 
+/// FIBMAP, mapping a block of a file to a block of its device, or to zero
+/// for a hole.  The argument is a `c_int`, read and written.
+pub const FIBMAP: c_ulong = 1;
+
+/// FIGETBSZ, the block size FIBMAP works in, written to a `c_int`.
+pub const FIGETBSZ: c_ulong = 2;
+
 #[cfg(test)]
 mod tests {
     use assert_hex::assert_eq_hex;
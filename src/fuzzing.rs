@@ -0,0 +1,10 @@
+pub use crate::lift::itree::{IntervalTree, IntervalTreeEntry};
+
+use crate::utils::Checksum;
+
+/// The checksum a report records for `data`.
+pub fn checksum(data: &[u8]) -> u64 {
+    let mut csum = Checksum::new();
+    csum.update(data);
+    csum.finish()
+}
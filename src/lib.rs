@@ -0,0 +1,207 @@
+//! Lifts loop files from within a filesystem onto the block device hosting
+//! that filesystem.
+//!
+//! This is the library behind the `looplift` command, for embedding the
+//! same steps in other programs:
+//!
+//! 1. [`scan::do_scan`] (or [`scan::do_scan_xfs`]) maps a file onto its
+//!    device, producing a report: a [`ReportSummary`] followed by a
+//!    [`ReportExtent`] for each piece of the file, in order.
+//! 2. Reports are stored as a stream of JSON values, written with
+//!    [`ReportWriter`] and read back with [`ReportReader`].
+//! 3. [`lift::do_plan`] estimates the disk head movement of lifting.
+//! 4. [`lift::do_lift`] moves the data into place on the device.
+//!
+//! ```no_run
+//! use std::fs::File;
+//!
+//! use looplift::{lift, scan, IoSize, LiftOptions, ReportWriter};
+//!
+//! # fn main() -> looplift::ResultType<()> {
+//! let mut report = Vec::new();
+//! scan::do_scan(
+//!     &mut File::open("/mnt/disk.img")?,
+//!     &File::open("/dev/sdX")?,
+//!     1 << 20,
+//!     IoSize::Bytes(128 * 1024),
+//!     None,
+//!     &mut ReportWriter::new(&mut report),
+//! )?;
+//!
+//! // ... unmount the filesystem ...
+//!
+//! let options = LiftOptions {
+//!     dry_run: true,
+//!     ..Default::default()
+//! };
+//! let device = lift::open_device("/dev/sdX", &options)?;
+//! lift::do_lift(device, &mut report.as_slice(), &options)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Failures are reported as [`LoopliftError`], whose
+//! [`exit_code`](LoopliftError::exit_code) tells whether the device may
+//! have been modified.
+
+#![warn(missing_docs)]
+
+/// Raw wrappers for block device ioctls and sysfs attributes.
+mod blkdev;
+/// Btrfs chunk tree lookups, for translating logical addresses.
+mod btrfs;
+/// Composition of reports for nested images.
+pub mod compose;
+/// Devices to lift onto, real and in memory.
+pub mod device;
+/// Resolution of device-mapper linear mappings.
+pub mod devmapper;
+/// The error type, and the exit codes it maps to.
+pub mod error;
+/// Machine readable JSON-lines event stream, see `--events`.
+pub mod events;
+/// Raw wrappers for the FIEMAP and FIBMAP ioctls.
+///
+/// Definitions taken from `/usr/include/linux`.
+mod fiemap;
+/// Internals exposed to the fuzz targets in `fuzz/`.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+/// Lifting, and estimating the cost of lifting.
+pub mod lift;
+/// Per phase progress, with throughput and ETA.
+mod progress;
+/// Reports, mapping a file onto the device beneath it.
+pub mod report;
+/// Producing reports.
+pub mod scan;
+/// Rate limiting of reads and writes.
+pub mod throttle;
+/// Pipelined IO through io_uring.
+#[cfg(feature = "io-uring")]
+mod uring;
+mod utils;
+/// Offline reader for XFS filesystem structures.
+mod xfs;
+
+pub use device::BlockDevice;
+pub use error::LoopliftError;
+pub use lift::{LiftOptions, OrderPolicy};
+pub use report::{
+    ExtentSource, Report, ReportExtent, ReportReader, ReportSink, ReportSummary, ReportWriter,
+};
+pub use utils::{FileOpsCounters, IoSize, ZeroStrategy};
+
+/// The result of everything that can fail.
+pub type ResultType<T> = std::result::Result<T, LoopliftError>;
+
+#[cfg(test)]
+mod tests {
+
+    use core::str;
+
+    use log::info;
+    use serde::{Deserialize, Serialize};
+
+    use crate::ResultType;
+
+    pub(crate) fn init_logger() {
+        let _ = env_logger::builder()
+            .is_test(true)
+            .filter_level(log::LevelFilter::Debug)
+            .try_init();
+    }
+
+    /// An empty temporary file, already unlinked so that nothing is left
+    /// behind, named after the test for when it shows up in `lsof`.
+    pub(crate) fn scratch_file(name: &str) -> std::io::Result<std::fs::File> {
+        let path =
+            std::env::temp_dir().join(format!("looplift-{}-{}.img", name, std::process::id()));
+        let f = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        std::fs::remove_file(&path)?;
+        Ok(f)
+    }
+
+    /// A linear congruential generator, giving randomized tests the same
+    /// numbers on every run.
+    pub(crate) struct Lcg(u64);
+
+    impl Lcg {
+        pub(crate) fn new(seed: u64) -> Self {
+            Self(seed)
+        }
+
+        /// A number below `n`.
+        pub(crate) fn below(&mut self, n: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % n
+        }
+    }
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    enum CSum {
+        Zeros(),
+        NonZero(u64),
+    }
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    struct Blah {
+        thing: u64,
+        other_thing: CSum,
+    }
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq)]
+    struct Header {
+        message: String,
+    }
+
+    #[test]
+    fn serde_play() -> ResultType<()> {
+        init_logger();
+
+        let h = Header {
+            message: "Hello world!".to_string(),
+        };
+
+        let a = Blah {
+            thing: 10,
+            other_thing: CSum::NonZero(40),
+        };
+
+        let b = Blah {
+            thing: 20,
+            other_thing: CSum::Zeros(),
+        };
+
+        let mut buf: Vec<u8> = Vec::new();
+        let mut serializer = serde_json::Serializer::new(&mut buf);
+        h.serialize(&mut serializer)?;
+        a.serialize(&mut serializer)?;
+        b.serialize(&mut serializer)?;
+
+        info!("encoded: {}", str::from_utf8(buf.as_slice())?);
+
+        let mut r = serde_json::Deserializer::from_reader(buf.as_slice());
+
+        let h2: Header = Header::deserialize(&mut r)?;
+        let mut r_it = r.into_iter::<Blah>();
+        let a2: Blah = r_it.next().unwrap()?;
+        let b2: Blah = r_it.next().unwrap()?;
+
+        assert_eq!(h, h2);
+        assert_eq!(a, a2);
+        assert_eq!(b, b2);
+        assert!(r_it.next().is_none());
+
+        Ok(())
+    }
+}
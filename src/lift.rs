@@ -1,79 +1,250 @@
 use std::{
     collections::VecDeque,
+    fs::File,
     io::{self, Read},
     ops::Range,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::Arc,
 };
 
+use clap::ValueEnum;
+use indicatif::{HumanBytes, HumanCount};
 use itree::{IntervalTree, IntervalTreeEntry};
 use log::info;
-use serde::Deserialize;
+use parallel::CopyBatch;
 
 use crate::{
-    report::{ReportExtent, ReportSummary},
-    utils::{validate_device_size, FileOps, SimpleProgress},
+    blkdev,
+    device::{BlockDevice, MemDevice},
+    error::LoopliftError,
+    progress::PhaseProgress,
+    report::ReportReader,
+    throttle::Throttle,
+    utils::{validate_device_size, FileOps, IoSize, ZeroStrategy},
     ResultType,
 };
 
-mod itree;
+pub(crate) mod itree;
+mod parallel;
 
 struct OperationQueues {
     zeroing: VecDeque<Range<u64>>,
     csums: VecDeque<CsumOp>,
     copies: IntervalTree<CopyOp>,
-    device_length: u64,
+    /// Total length of the extents in `copies`.
+    copy_bytes: u64,
+}
+
+/// Settings for `do_lift`.
+pub struct LiftOptions {
+    /// Go through every step, reading but not writing anything.
+    pub dry_run: bool,
+    /// How to zero the zero extents.
+    pub zero_strategy: ZeroStrategy,
+    /// Read the zero extents first, and only zero chunks which are not
+    /// already zero.
+    pub check_zeros: bool,
+    /// Discard the zero extents once the lift has been verified.
+    pub discard_zeros: bool,
+    /// The device has been opened with O_DIRECT.
+    pub direct: bool,
+    /// Keep several copies in flight through io_uring, where available.
+    pub io_uring: bool,
+    /// Number of threads running independent copies.
+    pub jobs: usize,
+    /// Size of each read and write.
+    pub io_size: IoSize,
+    /// The order in which to move extents.
+    pub order: OrderPolicy,
+    /// Limits on the rate of IO.
+    pub throttle: Option<Arc<Throttle>>,
+}
+
+impl Default for LiftOptions {
+    /// The defaults of the `lift` command, which include a dry-run.
+    fn default() -> Self {
+        Self {
+            dry_run: true,
+            zero_strategy: ZeroStrategy::Auto,
+            check_zeros: false,
+            discard_zeros: false,
+            direct: false,
+            io_uring: false,
+            jobs: 1,
+            io_size: IoSize::Bytes(128 * 1024),
+            order: OrderPolicy::Source,
+            throttle: None,
+        }
+    }
+}
+
+/// The order in which extents are moved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OrderPolicy {
+    /// Lowest source offset first.
+    Source,
+    /// Sweep across the disk, picking the next source at or after where
+    /// the previous operation finished writing.  Suits rotational disks.
+    Elevator,
 }
 
-pub(crate) fn do_lift(
-    device: std::fs::File,
+/// Estimates the head movement of shuffling the extents of a report with
+/// each `OrderPolicy`, without touching any device.
+pub fn do_plan(input: &mut impl io::Read, out: &mut impl io::Write) -> ResultType<()> {
+    let reader = ReportReader::new(input)?;
+    let device_length = reader.summary().device_length;
+    // Destination offset, source offset and length of each extent to move.
+    let mut copies: Vec<(u64, u64, u64)> = Vec::new();
+    for e in reader {
+        let e = e?;
+        if let crate::report::ExtentSource::Offset { offset, .. } = e.source {
+            copies.push((e.destination_offset, offset, e.length));
+        }
+    }
+    info!("Loaded {} extents to move.", copies.len());
+    let copy_bytes = copies.iter().map(|(_, _, length)| length).sum();
+
+    // Simulated operations never touch the device.
+    let device = MemDevice::default();
+    for order in OrderPolicy::value_variants() {
+        let mut copy_queue = IntervalTree::new(0..device_length);
+        for &(destination_offset, offset, length) in &copies {
+            queue_copy(
+                &mut copy_queue,
+                destination_offset,
+                offset,
+                length,
+                device_length,
+            )?;
+        }
+        let mut fops = FileOps::new_simulation();
+        perform_shuffles(&device, copy_queue, &mut fops, copy_bytes, 1, *order)?;
+
+        let (seeks, seek_distance) = fops.seek_stats();
+        writeln!(
+            out,
+            "{:?}: {} seeks, total seek distance {}",
+            order,
+            HumanCount(seeks),
+            HumanBytes(seek_distance)
+        )?;
+    }
+    Ok(())
+}
+
+/// Opens the device to lift onto, as `do_lift` expects given `options`:
+/// writable unless in a dry-run, and with O_DIRECT if `options.direct`.
+pub fn open_device(path: impl AsRef<Path>, options: &LiftOptions) -> io::Result<File> {
+    File::options()
+        .read(true)
+        .write(!options.dry_run)
+        .custom_flags(match options.direct {
+            true => blkdev::O_DIRECT,
+            false => 0,
+        })
+        .open(path)
+}
+
+/// Lifts according to the report read from `input`, onto `device`, which
+/// should be opened with `open_device`.
+///
+/// Every source extent is verified against its checksum before anything
+/// is written, and the result is verified afterwards (unless in a dry-run).
+pub fn do_lift(
+    device: impl BlockDevice,
     input: &mut impl io::Read,
-    dry_run: bool,
+    options: &LiftOptions,
 ) -> ResultType<()> {
-    let mut fops = FileOps::new(dry_run);
+    let mut fops = FileOps::new(options.dry_run);
+    fops.set_io_size(&device, options.io_size)?;
+    fops.set_throttle(options.throttle.clone());
+    if options.direct {
+        fops.enable_direct_io(&device)?;
+    }
+    if options.io_uring {
+        fops.enable_io_uring()?;
+    }
+    fops.select_zero_strategy(&device, options.zero_strategy)?;
+    fops.set_check_zeros(options.check_zeros);
+    if options.discard_zeros {
+        fops.enable_discard(&device)?;
+    }
 
     let opq: OperationQueues = load_mapping(&device, input, &mut fops)?;
-    perform_shuffles(&device, opq.copies, &mut fops, opq.device_length)?;
-    fill_zeros(&device, opq.zeroing, &mut fops, opq.device_length)?;
-    if !dry_run {
-        validate_csums(&device, opq.csums, &mut fops, opq.device_length)?;
+    let result = apply_mapping(&device, opq, &mut fops, options);
+    fops.log_stats();
+    match result {
+        Ok(()) => {
+            info!("All done.");
+            Ok(())
+        }
+        // Nothing has been written in a dry-run.
+        Err(e) if options.dry_run => Err(e),
+        Err(e) => Err(e.incomplete()),
+    }
+}
+
+/// Moves and zeroes the extents, then verifies the result.  Unless in a
+/// dry-run, this is where the device is modified.
+fn apply_mapping(
+    device: &impl BlockDevice,
+    opq: OperationQueues,
+    fops: &mut FileOps,
+    options: &LiftOptions,
+) -> ResultType<()> {
+    perform_shuffles(
+        device,
+        opq.copies,
+        fops,
+        opq.copy_bytes,
+        options.jobs,
+        options.order,
+    )?;
+    fill_zeros(device, &opq.zeroing, fops)?;
+    if !options.dry_run {
+        device.flush()?;
+        validate_csums(device, opq.csums, fops)?;
+        if options.discard_zeros {
+            discard_zeros(device, &opq.zeroing, fops)?;
+        }
     } else {
         info!("Dry-run, so not confirming final checksums.");
     }
-
-    info!("All done.");
-
-    fops.log_stats();
-
     Ok(())
 }
 
 fn load_mapping(
-    device: &std::fs::File,
+    device: &impl BlockDevice,
     input: &mut impl Read,
     fops: &mut FileOps,
 ) -> ResultType<OperationQueues> {
     info!("Parsing report and validating initial checksums.");
 
-    let mut deserializer = serde_json::Deserializer::from_reader(input);
-    let sr = ReportSummary::deserialize(&mut deserializer)?;
-    validate_device_size(device, sr.device_length)?;
-    let device_length = sr.device_length;
+    let reader = ReportReader::new(input)?;
+    let device_length = reader.summary().device_length;
+    validate_device_size(device, device_length)?;
+    fops.check_direct_io_alignment(device_length)?;
 
     let mut result = OperationQueues {
         zeroing: Default::default(),
         csums: Default::default(),
         copies: IntervalTree::new(0..device_length),
-        device_length,
+        copy_bytes: 0,
     };
 
-    let mut pb = SimpleProgress::new(device_length);
+    // The whole report is read first, to know how much data there is to verify.
+    let extents = reader.collect::<Result<Vec<_>, _>>()?;
+    let data_bytes = extents
+        .iter()
+        .filter(|e| matches!(e.source, crate::report::ExtentSource::Offset { .. }))
+        .map(|e| e.length)
+        .sum();
+    let mut pb = PhaseProgress::new("Verifying source", data_bytes, fops);
 
-    let mut expected_next_offset = 0u64;
-    while expected_next_offset < device_length {
-        pb.update(expected_next_offset);
-
-        let e = ReportExtent::deserialize(&mut deserializer)?;
-        assert_eq!(e.destination_offset, expected_next_offset);
-        expected_next_offset += e.length;
+    for e in extents {
+        fops.check_direct_io_alignment(e.destination_offset)?;
+        fops.check_direct_io_alignment(e.length)?;
 
         match e.source {
             crate::report::ExtentSource::Zeros => {
@@ -82,41 +253,91 @@ fn load_mapping(
                     .push_back(e.destination_offset..(e.destination_offset + e.length));
             }
             crate::report::ExtentSource::Offset { offset, checksum } => {
+                queue_copy(
+                    &mut result.copies,
+                    e.destination_offset,
+                    offset,
+                    e.length,
+                    device_length,
+                )?;
+                fops.check_direct_io_alignment(offset)?;
                 fops.validate_checksum(device, offset, e.length, checksum)?;
+                pb.inc(e.length);
+                result.copy_bytes += e.length;
 
                 result.csums.push_back(CsumOp {
                     offset: e.destination_offset,
                     length: e.length,
                     csum: checksum,
                 });
-
-                assert!(result.copies.insert(CopyOp {
-                    source: offset..(offset + e.length),
-                    destination_offset: e.destination_offset
-                }));
             }
         }
     }
-    pb.finish();
+    pb.finish(fops);
     info!("Extents loaded and csums match");
     Ok(result)
 }
 
+/// Queues the copy moving an extent's data from `offset` into place,
+/// checking that the source lies within the device and overlaps no other.
+fn queue_copy(
+    queue: &mut IntervalTree<CopyOp>,
+    destination_offset: u64,
+    offset: u64,
+    length: u64,
+    device_length: u64,
+) -> ResultType<()> {
+    let source = match offset.checked_add(length) {
+        Some(end) if end <= device_length => offset..end,
+        _ => {
+            return Err(LoopliftError::ReportInconsistent(format!(
+                "source of extent at offset {} lies beyond the end of the device.",
+                destination_offset
+            )))
+        }
+    };
+    if !queue.find(&source).is_empty() {
+        return Err(LoopliftError::ReportInconsistent(format!(
+            "source of extent at offset {} overlaps that of another extent.",
+            destination_offset
+        )));
+    }
+    assert!(queue.insert(CopyOp {
+        source,
+        destination_offset,
+    }));
+    Ok(())
+}
+
 fn perform_shuffles(
-    device: &std::fs::File,
+    device: &impl BlockDevice,
     mut copy_queue: IntervalTree<CopyOp>,
     fops: &mut FileOps,
-    device_length: u64,
+    copy_bytes: u64,
+    jobs: usize,
+    order: OrderPolicy,
 ) -> ResultType<()> {
     info!("Copying extent data");
-    let mut pb = SimpleProgress::new(device_length);
+    // Every byte of every extent is counted once, when it reaches its
+    // destination, whether by a copy, a swap or by already being in place.
+    let mut pb = PhaseProgress::new("Moving", copy_bytes, fops);
+    let mut batch = CopyBatch::new(fops, jobs);
+    // Where the last copy or swap finished writing.
+    let mut head = 0u64;
     'copy_loop: while !copy_queue.is_empty() {
-        let op: CopyOp = copy_queue.first().unwrap().clone();
-        pb.update(op.source.start);
+        let op: CopyOp = match order {
+            OrderPolicy::Source => copy_queue.first(),
+            OrderPolicy::Elevator => copy_queue
+                .first_at_or_after(head)
+                .or_else(|| copy_queue.first_at_or_after(0)),
+        }
+        .unwrap()
+        .clone();
 
         if op.source.start == op.destination_offset {
             // is a no-op op, mark as done.
             assert!(copy_queue.remove(&op));
+            pb.inc(op.source.end - op.source.start);
             continue;
         }
 
@@ -128,7 +349,9 @@ fn perform_shuffles(
         if overlapping_sources.is_empty() {
             // Nothing overlaps, including self which is still in the tree, do the copy
             assert!(copy_queue.remove(&op));
-            fops.copy_segment(device, &op.source, op.destination_offset)?;
+            head = dest_range.end;
+            pb.inc(dest_range.end - dest_range.start);
+            batch.copy(device, fops, op)?;
             continue;
         }
 
@@ -181,7 +404,10 @@ fn perform_shuffles(
 
         // Some things overlap, but they all do so with identical extents.
         assert!(copy_queue.remove(&op));
+        batch.flush_if_overlapping(device, fops, &[op.source.clone(), dest_range.clone()])?;
         fops.swap_segment(device, &op.source, op.destination_offset)?;
+        head = op.source.end;
+        pb.inc(dest_range.end - dest_range.start);
         for other_op in &overlapping_sources {
             assert!(&op != other_op);
             assert!(dest_range == other_op.source);
@@ -191,44 +417,59 @@ fn perform_shuffles(
             assert!(copy_queue.insert(new_op));
         }
     }
-    pb.finish();
+    batch.finish(device, fops)?;
+    fops.drain()?;
+    pb.finish(fops);
 
     Ok(())
 }
 
 fn fill_zeros(
-    device: &std::fs::File,
-    mut zeroing_queue: VecDeque<Range<u64>>,
+    device: &impl BlockDevice,
+    zeroing_queue: &VecDeque<Range<u64>>,
     fops: &mut FileOps,
-    device_length: u64,
 ) -> ResultType<()> {
     info!("Writing zero extents");
-    let mut pb = SimpleProgress::new(device_length);
-    while !zeroing_queue.is_empty() {
-        let range = zeroing_queue.pop_front().unwrap();
-        pb.update(range.start);
+    let total = zeroing_queue.iter().map(|r| r.end - r.start).sum();
+    let mut pb = PhaseProgress::new("Zeroing", total, fops);
+    for range in zeroing_queue {
+        fops.fill_zeros(device, range)?;
+        pb.inc(range.end - range.start);
+    }
+    pb.finish(fops);
+    Ok(())
+}
 
-        fops.fill_zeros(device, &range)?;
+fn discard_zeros(
+    device: &impl BlockDevice,
+    zeroing_queue: &VecDeque<Range<u64>>,
+    fops: &mut FileOps,
+) -> ResultType<()> {
+    info!("Discarding zero extents");
+    let total = zeroing_queue.iter().map(|r| r.end - r.start).sum();
+    let mut pb = PhaseProgress::new("Discarding", total, fops);
+    for range in zeroing_queue {
+        fops.discard(device, range)?;
+        pb.inc(range.end - range.start);
     }
-    pb.finish();
+    pb.finish(fops);
     Ok(())
 }
 
 fn validate_csums(
-    device: &std::fs::File,
+    device: &impl BlockDevice,
     mut csums: VecDeque<CsumOp>,
     fops: &mut FileOps,
-    device_length: u64,
 ) -> ResultType<()> {
     info!("Validating final csums");
-    let mut pb = SimpleProgress::new(device_length);
+    let total = csums.iter().map(|c| c.length).sum();
+    let mut pb = PhaseProgress::new("Verifying result", total, fops);
     while !csums.is_empty() {
         let csum = csums.pop_front().unwrap();
-        pb.update(csum.offset);
-
         fops.validate_checksum(device, csum.offset, csum.length, csum.csum)?;
+        pb.inc(csum.length);
     }
-    pb.finish();
+    pb.finish(fops);
     Ok(())
 }
 
@@ -280,21 +521,32 @@ impl<T: PartialOrd> RangeOps<T> for Range<T> {
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::fs::{FileExt, MetadataExt};
 
-    use crate::tests::init_logger;
+    use crate::{
+        device::{BlockDevice, FaultyDevice, MemDevice, OverlayDevice},
+        error::LoopliftError,
+        report::{ExtentSource, ReportExtent, ReportSummary, ReportWriter},
+        tests::{init_logger, scratch_file, Lcg},
+        utils::{FileOps, IoSize, ZeroStrategy},
+        ResultType,
+    };
 
-    use super::RangeOps;
+    use super::{
+        do_lift, do_plan, itree::IntervalTree, perform_shuffles, CopyOp, LiftOptions, OrderPolicy,
+        RangeOps,
+    };
 
     #[test]
     fn overlaps() {
         init_logger();
-        assert!((0..10).overlaps_range(&(10..20)) == false);
-        assert!((0..11).overlaps_range(&(10..20)) == true);
-        assert!((0..30).overlaps_range(&(10..20)) == true);
-        assert!((10..20).overlaps_range(&(10..20)) == true);
-        assert!((12..18).overlaps_range(&(10..20)) == true);
-        assert!((19..30).overlaps_range(&(10..20)) == true);
-        assert!((20..30).overlaps_range(&(10..20)) == false);
+        assert!(!(0..10).overlaps_range(&(10..20)));
+        assert!((0..11).overlaps_range(&(10..20)));
+        assert!((0..30).overlaps_range(&(10..20)));
+        assert!((10..20).overlaps_range(&(10..20)));
+        assert!((12..18).overlaps_range(&(10..20)));
+        assert!((19..30).overlaps_range(&(10..20)));
+        assert!(!(20..30).overlaps_range(&(10..20)));
     }
 
     #[test]
@@ -318,4 +570,595 @@ mod tests {
             }
         }
     }
+
+    /// Shuffles large random layouts with one thread and with several, in
+    /// each order, every run having to move each data extent into place.
+    ///
+    /// The zero extents have no operation, so whatever ends up in them is
+    /// not compared.
+    #[test]
+    fn parallel_shuffles_match_serial() -> ResultType<()> {
+        init_logger();
+        let mut rng = Lcg::new(8);
+
+        for seed in 0..8 {
+            let pieces = random_pieces(&mut rng, 16 * 1024, 600);
+            let order = shuffled(&mut rng, pieces.len());
+            let layout = place(&pieces, &order, &vec![0; pieces.len()]);
+            let length = pieces.iter().map(|(length, _)| length).sum();
+            let original = pattern(length);
+
+            let mut ops = Vec::new();
+            let mut destination_offset = 0;
+            for &(length, source) in &layout {
+                if let Some(offset) = source {
+                    ops.push(CopyOp {
+                        source: offset..(offset + length),
+                        destination_offset,
+                    });
+                }
+                destination_offset += length;
+            }
+            let copy_bytes = ops.iter().map(|op| op.source.end - op.source.start).sum();
+
+            for (jobs, order) in [
+                (1, OrderPolicy::Source),
+                (4, OrderPolicy::Source),
+                (1, OrderPolicy::Elevator),
+                (4, OrderPolicy::Elevator),
+            ] {
+                let device = MemDevice::from(original.clone());
+                let mut queue = IntervalTree::new(0..length);
+                for op in &ops {
+                    assert!(queue.insert(op.clone()));
+                }
+                let mut fops = FileOps::new(false);
+                perform_shuffles(&device, queue, &mut fops, copy_bytes, jobs, order)?;
+
+                let result = device.into_inner();
+                for op in &ops {
+                    let at = |offset: u64| usize::try_from(offset).unwrap();
+                    let end = op.destination_offset + (op.source.end - op.source.start);
+                    assert!(
+                        result[at(op.destination_offset)..at(end)]
+                            == original[at(op.source.start)..at(op.source.end)],
+                        "seed {} jobs {} order {:?}",
+                        seed,
+                        jobs,
+                        order
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The workers' IO is counted even when one of their copies fails, so
+    /// that the statistics logged after a failed lift are complete.
+    #[test]
+    fn parallel_failure_counts_worker_io() -> ResultType<()> {
+        init_logger();
+        let mut rng = Lcg::new(9);
+        let pieces = random_pieces(&mut rng, 4 * 1024, 64);
+        let order = shuffled(&mut rng, pieces.len());
+        let layout = place(&pieces, &order, &vec![0; pieces.len()]);
+        let length = pieces.iter().map(|(length, _)| length).sum();
+
+        let mut queue = IntervalTree::new(0..length);
+        let mut copy_bytes = 0;
+        let mut destination_offset = 0;
+        for &(length, source) in &layout {
+            if let Some(offset) = source {
+                assert!(queue.insert(CopyOp {
+                    source: offset..(offset + length),
+                    destination_offset,
+                }));
+                copy_bytes += length;
+            }
+            destination_offset += length;
+        }
+
+        let device = FaultyDevice::new(MemDevice::from(pattern(length))).fail_write(100);
+        let mut fops = FileOps::new(false);
+        let result = perform_shuffles(
+            &device,
+            queue,
+            &mut fops,
+            copy_bytes,
+            4,
+            OrderPolicy::Source,
+        );
+        assert!(result.is_err());
+        // Every write but the failed one was counted, less one more if
+        // that was the second of a swap, whose writes are counted together.
+        let uncounted = device.writes() - fops.counters().write_ops;
+        assert!(
+            (1..=2).contains(&uncounted),
+            "{} writes uncounted",
+            uncounted
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejected_reports() -> ResultType<()> {
+        init_logger();
+        let f = scratch_file("reject")?;
+        let data: Vec<u8> = (0..(64 * 1024)).map(|i| (i / 512) as u8).collect();
+        f.write_all_at(&data, 0)?;
+        let checksum = FileOps::new(true).compute_checksum(&f, 0, 32 * 1024)?;
+
+        let options = LiftOptions {
+            dry_run: false,
+            zero_strategy: ZeroStrategy::Write,
+            ..Default::default()
+        };
+        let lift =
+            |report: String| do_lift(f.try_clone().unwrap(), &mut report.as_bytes(), &options);
+        let summary = r#"{"device_length":65536}"#;
+
+        let e = lift(format!(
+            r#"{}{{"destination_offset":0,"length":32768,"source":{{"Offset":{{"offset":32768,"checksum":{}}}}}}}{{"destination_offset":32768,"length":32768,"source":"Zeros"}}"#,
+            summary, checksum
+        ))
+        .unwrap_err();
+        assert!(matches!(
+            e,
+            LoopliftError::ChecksumMismatch {
+                offset: 32768,
+                length: 32768
+            }
+        ));
+        assert_eq!(e.exit_code(), 7);
+
+        let e = lift(format!(
+            r#"{}{{"destination_offset":0,"length":4096,"source":"Zeros"}}{{"destination_offset":8192,"length":4096,"source":"Zeros"}}"#,
+            summary
+        ))
+        .unwrap_err();
+        assert!(matches!(e, LoopliftError::ReportInconsistent(_)));
+
+        let e = lift(format!(
+            r#"{}{{"destination_offset":0,"length":32768,"source":{{"Offset":{{"offset":65536,"checksum":{}}}}}}}{{"destination_offset":32768,"length":32768,"source":"Zeros"}}"#,
+            summary, checksum
+        ))
+        .unwrap_err();
+        assert!(matches!(e, LoopliftError::ReportInconsistent(_)));
+
+        let e = lift(format!("{}{{", summary)).unwrap_err();
+        assert!(matches!(e, LoopliftError::ReportParse(_)));
+
+        let overlapping = format!(
+            r#"{}{{"destination_offset":0,"length":32768,"source":{{"Offset":{{"offset":0,"checksum":{}}}}}}}{{"destination_offset":32768,"length":32768,"source":{{"Offset":{{"offset":16384,"checksum":0}}}}}}"#,
+            summary, checksum
+        );
+        let e = lift(overlapping.clone()).unwrap_err();
+        assert!(matches!(e, LoopliftError::ReportInconsistent(_)));
+        let e = do_plan(&mut overlapping.as_bytes(), &mut Vec::new()).unwrap_err();
+        assert!(matches!(e, LoopliftError::ReportInconsistent(_)));
+
+        // Nothing was written.
+        let mut result = vec![0u8; data.len()];
+        f.read_exact_at(&mut result, 0)?;
+        assert!(result == data);
+        Ok(())
+    }
+
+    #[test]
+    fn lift_in_memory() -> ResultType<()> {
+        init_logger();
+        let data: Vec<u8> = (0..(64 * 1024)).map(|i| (i / 512) as u8).collect();
+        let device = MemDevice::from(data.clone());
+        let checksum = FileOps::new(true).compute_checksum(&device, 32768, 32768)?;
+        let report = format!(
+            r#"{{"device_length":65536}}{{"destination_offset":0,"length":32768,"source":{{"Offset":{{"offset":32768,"checksum":{}}}}}}}{{"destination_offset":32768,"length":32768,"source":"Zeros"}}"#,
+            checksum
+        );
+        let options = LiftOptions {
+            dry_run: false,
+            io_size: IoSize::Bytes(4096),
+            ..Default::default()
+        };
+        let mut expected = data[32768..].to_vec();
+        expected.resize(data.len(), 0);
+
+        // Onto an overlay, leaving the device as it was.
+        let overlay = OverlayDevice::new(&device);
+        do_lift(&overlay, &mut report.as_bytes(), &options)?;
+        let mut result = vec![0u8; data.len()];
+        overlay.read_at(&mut result, 0)?;
+        assert!(result == expected);
+        assert!(device.to_vec() == data);
+
+        // A failed write leaves the device in an unknown state.
+        let faulty = FaultyDevice::new(&device).fail_write(3);
+        let e = do_lift(&faulty, &mut report.as_bytes(), &options).unwrap_err();
+        assert_eq!(e.exit_code(), 9);
+        assert_eq!(faulty.writes(), 3);
+
+        do_lift(&device, &mut report.as_bytes(), &options)?;
+        assert!(device.into_inner() == expected);
+        Ok(())
+    }
+
+    #[test]
+    fn discard_zeros() -> ResultType<()> {
+        init_logger();
+        let layout = [
+            (16384, Some(32768)),
+            (16384, None),
+            (16384, Some(0)),
+            (16384, None),
+        ];
+        let original = pattern(65536);
+        let (report, expected) = make_report(&layout, &original)?;
+        let options = LiftOptions {
+            zero_strategy: ZeroStrategy::Write,
+            discard_zeros: true,
+            ..test_options(1, OrderPolicy::Source, false)
+        };
+
+        // Nothing guarantees that discards on an overlay read as zeros.
+        let device = MemDevice::from(original.clone());
+        let overlay = OverlayDevice::new(&device);
+        assert!(do_lift(&overlay, &mut report.as_slice(), &options).is_err());
+        assert_eq!(overlay.changed_bytes(), 0);
+        drop(overlay);
+        assert!(device.into_inner() == original);
+
+        // On a regular file the zero extents become holes.
+        let f = scratch_file("discard")?;
+        f.write_all_at(&original, 0)?;
+        do_lift(&f, &mut report.as_slice(), &options)?;
+        let mut result = vec![0u8; original.len()];
+        f.read_exact_at(&mut result, 0)?;
+        assert!(result == expected);
+        let allocated = f.metadata()?.blocks() * 512;
+        assert!(allocated <= 32768, "{} bytes still allocated", allocated);
+        Ok(())
+    }
+
+    /// The extents of a report in order of destination, as their length
+    /// and source offset, `None` for zero extents.
+    type Layout = Vec<(u64, Option<u64>)>;
+
+    fn test_options(jobs: usize, order: OrderPolicy, check_zeros: bool) -> LiftOptions {
+        LiftOptions {
+            dry_run: false,
+            check_zeros,
+            jobs,
+            io_size: IoSize::Bytes(4096),
+            order,
+            ..Default::default()
+        }
+    }
+
+    /// Device content with no zeros, in which any misplaced extent shows.
+    fn pattern(length: u64) -> Vec<u8> {
+        (0..length).map(|i| (i % 255 + 1) as u8).collect()
+    }
+
+    /// Builds the report for `layout` on a device holding `original`, and
+    /// the content the device should have once lifted.
+    fn make_report(
+        layout: &[(u64, Option<u64>)],
+        original: &[u8],
+    ) -> ResultType<(Vec<u8>, Vec<u8>)> {
+        let device = MemDevice::from(original.to_vec());
+        let mut fops = FileOps::new(true);
+        let mut report = Vec::new();
+        let mut writer = ReportWriter::new(&mut report);
+        writer.write_summary(&ReportSummary {
+            device_length: device.length()?,
+        })?;
+        let mut expected = Vec::new();
+        let mut destination_offset = 0;
+        for &(length, source) in layout {
+            let source = match source {
+                Some(offset) => {
+                    expected.extend_from_slice(
+                        &original[usize::try_from(offset).unwrap()
+                            ..usize::try_from(offset + length).unwrap()],
+                    );
+                    ExtentSource::Offset {
+                        offset,
+                        checksum: fops.compute_checksum(&device, offset, length)?,
+                    }
+                }
+                None => {
+                    expected.resize(expected.len() + usize::try_from(length).unwrap(), 0);
+                    ExtentSource::Zeros
+                }
+            };
+            writer.write(&ReportExtent {
+                destination_offset,
+                length,
+                source,
+            })?;
+            destination_offset += length;
+        }
+        Ok((report, expected))
+    }
+
+    /// Lifts `layout` onto an in-memory device, checking the result byte for byte.
+    fn check_layout(layout: &[(u64, Option<u64>)], options: &LiftOptions) -> ResultType<()> {
+        let original = pattern(layout.iter().map(|(length, _)| length).sum());
+        let (report, expected) = make_report(layout, &original)?;
+        let device = MemDevice::from(original);
+        do_lift(&device, &mut report.as_slice(), options)?;
+        assert!(
+            device.into_inner() == expected,
+            "layout {:?} jobs {} order {:?}",
+            layout,
+            options.jobs,
+            options.order
+        );
+        Ok(())
+    }
+
+    /// Places the data pieces (those marked `true`) of `pieces` on the
+    /// device in `order`, the `i`th after `gaps[i]` bytes of free space.
+    ///
+    /// Any zero pieces in `order` take up space, but are still zero extents.
+    fn place(pieces: &[(u64, bool)], order: &[usize], gaps: &[u64]) -> Layout {
+        let mut sources = vec![None; pieces.len()];
+        let mut offset = 0;
+        for (&i, &gap) in order.iter().zip(gaps) {
+            offset += gap;
+            sources[i] = Some(offset);
+            offset += pieces[i].0;
+        }
+        pieces
+            .iter()
+            .zip(sources)
+            .map(|(&(length, data), source)| (length, source.filter(|_| data)))
+            .collect()
+    }
+
+    /// Splits `units` 512 byte units into pieces of up to `max_len` units,
+    /// three in four of them data, for `place`.
+    fn random_pieces(rng: &mut Lcg, units: u64, max_len: u64) -> Vec<(u64, bool)> {
+        let mut pieces = Vec::new();
+        let mut unit = 0;
+        while unit < units {
+            let length = u64::min(1 + rng.below(max_len), units - unit);
+            pieces.push((512 * length, rng.below(4) != 0));
+            unit += length;
+        }
+        pieces
+    }
+
+    /// A random permutation of `0..n`.
+    fn shuffled(rng: &mut Lcg, n: usize) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..n).collect();
+        for i in (1..n).rev() {
+            let j = rng.below(u64::try_from(i).unwrap() + 1);
+            order.swap(i, usize::try_from(j).unwrap());
+        }
+        order
+    }
+
+    /// Every way of splitting `n` into an ordered list of positive parts.
+    fn compositions(n: u64) -> Vec<Vec<u64>> {
+        if n == 0 {
+            return vec![vec![]];
+        }
+        (1..=n)
+            .flat_map(|first| {
+                compositions(n - first).into_iter().map(move |mut rest| {
+                    rest.insert(0, first);
+                    rest
+                })
+            })
+            .collect()
+    }
+
+    /// Every way of choosing `parts` non-negative numbers summing to at most `n`.
+    fn gap_choices(n: u64, parts: usize) -> Vec<Vec<u64>> {
+        if parts == 0 {
+            return vec![vec![]];
+        }
+        (0..=n)
+            .flat_map(|first| {
+                gap_choices(n - first, parts - 1)
+                    .into_iter()
+                    .map(move |mut rest| {
+                        rest.insert(0, first);
+                        rest
+                    })
+            })
+            .collect()
+    }
+
+    fn permutations(items: &[usize]) -> Vec<Vec<usize>> {
+        if items.is_empty() {
+            return vec![vec![]];
+        }
+        (0..items.len())
+            .flat_map(|i| {
+                let mut rest = items.to_vec();
+                let first = rest.remove(i);
+                permutations(&rest).into_iter().map(move |mut p| {
+                    p.insert(0, first);
+                    p
+                })
+            })
+            .collect()
+    }
+
+    /// Every report for devices of up to five bytes: each way of splitting
+    /// the file into extents, of choosing which are zeros, and of placing
+    /// the rest on the device.
+    #[test]
+    fn exhaustive_tiny_layouts() -> ResultType<()> {
+        init_logger();
+        let options = [
+            test_options(1, OrderPolicy::Source, false),
+            test_options(1, OrderPolicy::Elevator, true),
+            test_options(2, OrderPolicy::Source, false),
+        ];
+        let mut count = 0;
+        for n in 1..=5 {
+            for lengths in compositions(n) {
+                for mask in 0..(1u32 << lengths.len()) {
+                    let pieces: Vec<(u64, bool)> = lengths
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &length)| (length, mask & (1 << i) != 0))
+                        .collect();
+                    let data: Vec<usize> = (0..pieces.len()).filter(|&i| pieces[i].1).collect();
+                    let free = n - data.iter().map(|&i| pieces[i].0).sum::<u64>();
+                    for order in permutations(&data) {
+                        for gaps in gap_choices(free, order.len()) {
+                            let layout = place(&pieces, &order, &gaps);
+                            for options in &options {
+                                check_layout(&layout, options)?;
+                            }
+                            count += 1;
+                        }
+                    }
+                }
+            }
+        }
+        log::info!("Checked {} layouts.", count);
+        Ok(())
+    }
+
+    /// Random reports, with permutations of the extents chosen to make
+    /// long cycles, many swaps, or nothing to do at all.
+    #[test]
+    fn random_layouts() -> ResultType<()> {
+        init_logger();
+        let mut rng = Lcg::new(42);
+
+        for seed in 0..300 {
+            let units = 1 + rng.below(128);
+            let max_units = match seed % 3 {
+                0 => 1,
+                1 => 8,
+                _ => units,
+            };
+            let pieces = random_pieces(&mut rng, units, max_units);
+
+            let k = pieces.len();
+            let order: Vec<usize> = match seed % 5 {
+                // Every data extent is already in place.
+                0 => (0..k).collect(),
+                1 => (0..k).rev().collect(),
+                // One long cycle.
+                2 => (1..k).chain([0]).collect(),
+                // Neighbours exchanged.
+                3 => (0..k).map(|i| if i ^ 1 < k { i ^ 1 } else { i }).collect(),
+                _ => shuffled(&mut rng, k),
+            };
+            // Sources are contiguous, zero extents leaving free space.
+            let layout = place(&pieces, &order, &vec![0; k]);
+
+            let jobs = usize::try_from(1 + rng.below(3)).unwrap();
+            let order = match rng.below(2) {
+                0 => OrderPolicy::Source,
+                _ => OrderPolicy::Elevator,
+            };
+            check_layout(&layout, &test_options(jobs, order, rng.below(2) == 0))?;
+        }
+        Ok(())
+    }
+
+    /// Lifts with every failure the harness can inject: each read and each
+    /// write failing, each write torn part way or losing power, and each
+    /// block of the device being unreadable.
+    ///
+    /// The error must be an IO error, and unless it says that the device
+    /// may have been modified, the device must be untouched.
+    #[test]
+    fn injected_faults() -> ResultType<()> {
+        init_logger();
+        let mut rng = Lcg::new(7);
+
+        let mut count = 0;
+        for seed in 0..12 {
+            let units = 32;
+            let pieces = random_pieces(&mut rng, units, 12);
+            let order = shuffled(&mut rng, pieces.len());
+            let layout = place(&pieces, &order, &vec![0; pieces.len()]);
+
+            let options = LiftOptions {
+                discard_zeros: true,
+                ..test_options(1 + seed % 2, OrderPolicy::Source, seed % 3 == 0)
+            };
+            let original = pattern(512 * units);
+            let (report, expected) = make_report(&layout, &original)?;
+            let lift = |device: FaultyDevice<MemDevice>| {
+                let result = do_lift(&device, &mut report.as_slice(), &options);
+                (result, device)
+            };
+
+            // A clean run, to count the IO.
+            let (result, clean) = lift(FaultyDevice::new(MemDevice::from(original.clone())));
+            result?;
+            let (reads, writes) = (clean.reads(), clean.writes());
+            assert!(clean.into_inner().into_inner() == expected);
+            // Verifying the sources comes before anything else.
+            let verify_reads: u64 = layout
+                .iter()
+                .filter(|(_, source)| source.is_some())
+                .map(|(length, _)| length.div_ceil(4096))
+                .sum();
+
+            let check = |result: ResultType<()>, device: FaultyDevice<MemDevice>| {
+                let content = device.into_inner().into_inner();
+                match result {
+                    Ok(()) => panic!("Lift of {:?} succeeded despite a fault.", layout),
+                    Err(LoopliftError::Incomplete(e)) => {
+                        assert!(matches!(*e, LoopliftError::Io { .. }), "{}", e)
+                    }
+                    Err(e) => {
+                        assert!(matches!(e, LoopliftError::Io { .. }), "{}", e);
+                        assert!(content == original, "{} but the device was modified", e);
+                    }
+                }
+                content
+            };
+
+            for n in 1..=reads {
+                let (result, device) =
+                    lift(FaultyDevice::new(MemDevice::from(original.clone())).fail_read(n));
+                if n <= verify_reads {
+                    assert_eq!(device.writes(), 0);
+                    assert_eq!(result.as_ref().unwrap_err().exit_code(), 8);
+                }
+                check(result, device);
+            }
+            for n in 1..=writes {
+                let (result, device) =
+                    lift(FaultyDevice::new(MemDevice::from(original.clone())).fail_write(n));
+                assert_eq!(result.as_ref().unwrap_err().exit_code(), 9);
+                check(result, device);
+
+                let (result, device) = lift(
+                    FaultyDevice::new(MemDevice::from(original.clone()))
+                        .tear_write(n, 1 + rng.below(4095)),
+                );
+                assert_eq!(result.as_ref().unwrap_err().exit_code(), 9);
+                check(result, device);
+
+                // Everything is lost, unless it was flushed before verifying the result.
+                let (result, device) =
+                    lift(FaultyDevice::new(MemDevice::from(original.clone())).lose_power(n));
+                assert!(device.powered_off());
+                let content = check(result, device);
+                assert!(content == original || content == expected);
+            }
+            for block in (0..(512 * units)).step_by(512) {
+                let (result, device) = lift(
+                    FaultyDevice::new(MemDevice::from(original.clone()))
+                        .fail_range(block..(block + 512)),
+                );
+                check(result, device);
+            }
+            count += reads + 3 * writes + units;
+        }
+        log::info!("Injected {} faults.", count);
+        Ok(())
+    }
 }
@@ -2,7 +2,9 @@ use std::ops::Range;
 
 use crate::lift::RangeOps;
 
-pub(super) trait IntervalTreeEntry: std::fmt::Debug + std::cmp::Eq {
+/// Something stored in an `IntervalTree`.
+pub trait IntervalTreeEntry: std::fmt::Debug + std::cmp::Eq {
+    /// The interval the entry covers, which must not be empty.
     fn interval(&self) -> Range<u64>;
 }
 
@@ -11,12 +13,13 @@ pub(super) trait IntervalTreeEntry: std::fmt::Debug + std::cmp::Eq {
 /// Entries are values that have an associated interval.  Multiple
 /// entries can have the same exact interval, but must be unique
 /// as a whole.
-pub(super) struct IntervalTree<T: IntervalTreeEntry> {
+pub struct IntervalTree<T: IntervalTreeEntry> {
     span: Range<u64>,
     root_node: NodeType<T>,
 }
 
 impl<T: IntervalTreeEntry> IntervalTree<T> {
+    /// An empty tree, for entries lying within `span`.
     pub fn new(span: Range<u64>) -> Self {
         assert!(span.start < span.end);
         Self {
@@ -31,6 +34,7 @@ impl<T: IntervalTreeEntry> IntervalTree<T> {
         self.root_node.insert(&self.span, entry, false)
     }
 
+    /// Returns the entries overlapping `query_span`.
     pub fn find<'a>(&'a self, query_span: &Range<u64>) -> Vec<&'a T> {
         assert!(!query_span.is_empty());
         let mut result: Vec<&T> = Vec::new();
@@ -38,11 +42,13 @@ impl<T: IntervalTreeEntry> IntervalTree<T> {
         result
     }
 
+    /// Removes entry, returns true if it was present.
     pub fn remove(&mut self, entry: &T) -> bool {
         assert!(!entry.interval().is_empty());
         self.root_node.remove(&self.span, entry)
     }
 
+    /// True if there are no entries.
     pub fn is_empty(&self) -> bool {
         self.root_node.is_empty()
     }
@@ -51,6 +57,11 @@ impl<T: IntervalTreeEntry> IntervalTree<T> {
     pub fn first(&self) -> Option<&T> {
         self.root_node.first()
     }
+
+    /// Returns an entry with the lowest interval start at or after `position`.
+    pub fn first_at_or_after(&self, position: u64) -> Option<&T> {
+        self.root_node.first_at_or_after(&self.span, position)
+    }
 }
 
 #[derive(Debug)]
@@ -79,6 +90,10 @@ impl<T: IntervalTreeEntry> NodeType<T> {
             }
             NodeType::Populated(p) => {
                 let was_inline_single = !suppress_inline_singleton_flag && p.is_inline_singleton();
+                if was_inline_single && p.here[0] == entry {
+                    // Already present, though possibly not where it would be placed.
+                    return false;
+                }
 
                 let mid = (self_span.start + self_span.end) / 2;
                 let left_span = self_span.start..mid;
@@ -196,17 +211,17 @@ impl<T: IntervalTreeEntry> NodeType<T> {
                         (true, NodeType::Empty, NodeType::Empty) => {
                             panic!("Node is entirely empty, should be unreachable")
                         }
-                        (true, NodeType::Populated(lp), NodeType::Empty) => {
-                            if lp.is_inline_singleton() {
-                                p.here.push(lp.here.swap_remove(0));
-                                p.left_node = NodeType::Empty;
-                            }
+                        (true, NodeType::Populated(lp), NodeType::Empty)
+                            if lp.is_inline_singleton() =>
+                        {
+                            p.here.push(lp.here.swap_remove(0));
+                            p.left_node = NodeType::Empty;
                         }
-                        (true, NodeType::Empty, NodeType::Populated(rp)) => {
-                            if rp.is_inline_singleton() {
-                                p.here.push(rp.here.swap_remove(0));
-                                p.right_node = NodeType::Empty;
-                            }
+                        (true, NodeType::Empty, NodeType::Populated(rp))
+                            if rp.is_inline_singleton() =>
+                        {
+                            p.here.push(rp.here.swap_remove(0));
+                            p.right_node = NodeType::Empty;
                         }
                         _ => {}
                     }
@@ -239,6 +254,37 @@ impl<T: IntervalTreeEntry> NodeType<T> {
                 .or_else(|| p.right_node.first()),
         }
     }
+
+    fn first_at_or_after(&self, self_span: &Range<u64>, position: u64) -> Option<&T> {
+        match self {
+            NodeType::Empty => None,
+            NodeType::Populated(_) if self_span.end <= position => None,
+            NodeType::Populated(p) => {
+                let here = p
+                    .here
+                    .iter()
+                    .filter(|h| h.interval().start >= position)
+                    .min_by_key(|h| h.interval().start);
+                if (self_span.end - self_span.start) < 2 {
+                    return here;
+                }
+
+                // Left entries all start before right entries.
+                let mid = (self_span.start + self_span.end) / 2;
+                let child = p
+                    .left_node
+                    .first_at_or_after(&(self_span.start..mid), position)
+                    .or_else(|| {
+                        p.right_node
+                            .first_at_or_after(&(mid..self_span.end), position)
+                    });
+                [here, child]
+                    .into_iter()
+                    .flatten()
+                    .min_by_key(|e| e.interval().start)
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -261,7 +307,7 @@ impl<T: IntervalTreeEntry> BinaryNode<T> {
 mod tests {
     use std::ops::Range;
 
-    use crate::tests::init_logger;
+    use crate::tests::{init_logger, Lcg};
 
     use super::{IntervalTree, IntervalTreeEntry, RangeOps};
 
@@ -274,7 +320,7 @@ mod tests {
     impl Entry {
         fn new(span: Range<u64>, value: &str) -> Self {
             Self {
-                span: span,
+                span,
                 value: value.to_string(),
             }
         }
@@ -353,4 +399,51 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn duplicate_inline_singleton() {
+        init_logger();
+
+        // Held inline at the root, though it belongs in the left child.
+        let mut tree: IntervalTree<Entry> = IntervalTree::new(0..100);
+        let entry = Entry::new(10..20, "A");
+        assert!(tree.insert(entry.clone()));
+        assert!(!tree.insert(entry.clone()));
+        assert_eq!(tree.find(&(0..100)), vec![&entry]);
+        assert!(tree.remove(&entry));
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn first_at_or_after() {
+        init_logger();
+
+        let max = 64u64;
+        let mut tree: IntervalTree<Entry> = IntervalTree::new(0..max);
+        let mut entries: Vec<Entry> = Vec::new();
+        let mut rng = Lcg::new(7);
+        for i in 0..200 {
+            let start = rng.below(max - 1);
+            let end = start + 1 + rng.below((max - start - 1).max(1));
+            let entry = Entry::new(start..u64::min(end, max), &i.to_string());
+            if i % 3 == 2 {
+                let victim = entries.swap_remove(
+                    usize::try_from(rng.below(entries.len().try_into().unwrap())).unwrap(),
+                );
+                assert!(tree.remove(&victim));
+            }
+            assert!(tree.insert(entry.clone()));
+            entries.push(entry);
+
+            for position in 0..=max {
+                let expected = entries
+                    .iter()
+                    .map(|e| e.span.start)
+                    .filter(|s| *s >= position)
+                    .min();
+                let actual = tree.first_at_or_after(position).map(|e| e.span.start);
+                assert_eq!(expected, actual);
+            }
+        }
+    }
 }
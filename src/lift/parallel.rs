@@ -0,0 +1,129 @@
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use log::debug;
+
+use crate::{device::BlockDevice, utils::FileOps, ResultType};
+
+use super::{CopyOp, RangeOps};
+
+/// Number of copies gathered per worker before they are run.
+const OPS_PER_WORKER: usize = 64;
+
+/// Defers copies which `perform_shuffles` has found to be ready, so that
+/// several can be run at once on worker threads.
+///
+/// A ready copy's destination overlaps no source still waiting in the
+/// queue, so ready copies never interfere with one another unless a later
+/// one writes over an earlier one's source.  Such a copy waits for the
+/// batch to be run first, as must anything else touching the same ranges
+/// (such as swaps).  The end result is the same as running each copy
+/// immediately.
+pub(super) struct CopyBatch {
+    workers: Vec<FileOps>,
+    pending: Vec<CopyOp>,
+}
+
+impl CopyBatch {
+    /// With fewer than two jobs, copies are run immediately on the caller's `FileOps`.
+    pub fn new(fops: &FileOps, jobs: usize) -> Self {
+        let workers = match jobs {
+            0 | 1 => Vec::new(),
+            _ => (0..jobs).map(|_| fops.fork()).collect(),
+        };
+        Self {
+            workers,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Performs a ready copy, possibly later.
+    pub fn copy(
+        &mut self,
+        device: &impl BlockDevice,
+        fops: &mut FileOps,
+        op: CopyOp,
+    ) -> ResultType<()> {
+        if self.workers.is_empty() {
+            return fops.copy_segment(device, &op.source, op.destination_offset);
+        }
+
+        let dest_range =
+            op.destination_offset..(op.destination_offset + op.source.end - op.source.start);
+        if self
+            .pending
+            .iter()
+            .any(|p| p.source.overlaps_range(&dest_range))
+        {
+            self.flush(device, fops)?;
+        }
+        self.pending.push(op);
+        if self.pending.len() >= OPS_PER_WORKER * self.workers.len() {
+            self.flush(device, fops)?;
+        }
+        Ok(())
+    }
+
+    /// Runs the deferred copies if any of them touch `ranges`, which are
+    /// about to be read or written by something else.
+    pub fn flush_if_overlapping(
+        &mut self,
+        device: &impl BlockDevice,
+        fops: &mut FileOps,
+        ranges: &[Range<u64>],
+    ) -> ResultType<()> {
+        let overlapping = self.pending.iter().any(|p| {
+            let dest_range =
+                p.destination_offset..(p.destination_offset + p.source.end - p.source.start);
+            ranges
+                .iter()
+                .any(|r| r.overlaps_range(&p.source) || r.overlaps_range(&dest_range))
+        });
+        match overlapping {
+            true => self.flush(device, fops),
+            false => Ok(()),
+        }
+    }
+
+    /// Runs all deferred copies, spread across the workers, and folds the
+    /// workers' statistics into `fops`, whether or not every copy succeeded.
+    pub fn flush(&mut self, device: &impl BlockDevice, fops: &mut FileOps) -> ResultType<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        fops.drain()?;
+        debug!("Running {} copies in parallel.", self.pending.len());
+
+        let next = AtomicUsize::new(0);
+        let pending = &self.pending;
+        let results: Vec<ResultType<()>> = thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .workers
+                .iter_mut()
+                .map(|worker| {
+                    let next = &next;
+                    scope.spawn(move || {
+                        while let Some(op) = pending.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            worker.copy_segment(device, &op.source, op.destination_offset)?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        self.pending.clear();
+        for worker in &mut self.workers {
+            fops.take_stats(worker);
+        }
+        results.into_iter().collect()
+    }
+
+    /// Runs any remaining copies.
+    pub fn finish(mut self, device: &impl BlockDevice, fops: &mut FileOps) -> ResultType<()> {
+        self.flush(device, fops)
+    }
+}
@@ -1,22 +1,17 @@
 use std::{
-    error::Error,
     fs::{self},
     io::{BufReader, BufWriter},
+    process::ExitCode,
 };
 
-use clap::{command, Parser, Subcommand};
-use log::{info, warn};
-
-/// Raw wrapper for FIEMAP ioctl.
-///
-/// Definitions taken from `/usr/include/linux`.
-mod fiemap;
-mod lift;
-mod report;
-mod scan;
-mod utils;
-
-pub(crate) type ResultType<T> = std::result::Result<T, Box<dyn Error>>;
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
+use looplift::{
+    compose, devmapper, events, lift,
+    scan::{self, ImportFormat},
+    throttle::ThrottleArgs,
+    IoSize, LiftOptions, OrderPolicy, ReportWriter, ResultType, ZeroStrategy,
+};
 
 /// Lift loop files from within a filesystem to the block device hosting that filesystem.
 ///
@@ -25,6 +20,13 @@ pub(crate) type ResultType<T> = std::result::Result<T, Box<dyn Error>>;
 #[derive(Parser)]
 #[command(version, about)]
 struct Cli {
+    /// Send JSON-lines events to this file, or to this inherited file descriptor number.
+    ///
+    /// Events report the start, progress and end of each phase, the IO
+    /// performed in each, warnings, and finally success or failure.
+    #[clap(long, global = true)]
+    events: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -49,6 +51,93 @@ enum Commands {
         /// correctly from the underlying device, and are consistent
         /// with the file content.
         device: String,
+
+        /// Minimum length of a run of zeros within the file's data to report as a zero extent.
+        ///
+        /// Such runs are zero filled during the lift instead of being moved.
+        /// Runs are detected in aligned 4 KiB blocks.  Zero disables detection.
+        #[clap(long, default_value_t = 1024 * 1024)]
+        zero_threshold: u64,
+
+        /// Size of each read, in bytes (K, M and G suffixes accepted).
+        ///
+        /// `auto` picks a size from the device's queue limits, larger for
+        /// spinning disks and RAID arrays.
+        #[clap(long, default_value = "128K")]
+        io_size: IoSize,
+
+        #[command(flatten)]
+        throttle: ThrottleArgs,
+
+        /// Map the file with the older FIBMAP ioctl instead of FIEMAP.
+        ///
+        /// For filesystems without FIEMAP.  Requires root, and is slow for
+        /// large files.
+        #[clap(long)]
+        fibmap: bool,
+
+        /// Takes the file's extents from this file, of JSON `FileExtent`
+        /// values, instead of FIEMAP.  For testing on plain files.
+        ///
+        /// The map is checked against the file like any other, data and
+        /// holes alike, so a wrong one fails the scan.
+        #[clap(long, hide = true, conflicts_with = "fibmap")]
+        extent_map: Option<String>,
+    },
+    /// Scans a file on an unmounted XFS filesystem in preperation for lifting.
+    ///
+    /// Like `scan`, but the extents of the file are read directly from
+    /// the XFS structures on the device instead of via FIEMAP.  Useful
+    /// when the host filesystem cannot be mounted.  The filesystem must
+    /// have been cleanly unmounted, which is checked.
+    ScanXfs {
+        /// The device holding the XFS filesystem, also the device intended
+        /// to be lifted to.
+        device: String,
+
+        /// Path of the file to be lifted, relative to the root of the filesystem.
+        path: String,
+
+        /// Size of each read, in bytes (K, M and G suffixes accepted).
+        ///
+        /// `auto` picks a size from the device's queue limits, larger for
+        /// spinning disks and RAID arrays.
+        #[clap(long, default_value = "128K")]
+        io_size: IoSize,
+
+        #[command(flatten)]
+        throttle: ThrottleArgs,
+    },
+    /// Converts the extents of a file captured by `filefrag -v` or
+    /// `xfs_bmap -vp` into a report, for when `scan` cannot be run.
+    ///
+    /// The captured output is expected on stdin, and the report is sent to
+    /// stdout.  Checksums are computed by reading the device, which should
+    /// not have changed since the output was captured.  Unwritten extents,
+    /// and any others which cannot be represented safely, are refused.
+    Import {
+        /// The tool the output came from.
+        #[clap(long, value_enum)]
+        format: ImportFormat,
+
+        /// Length of the file in bytes, required for `xfs_bmap`, which
+        /// does not record it.
+        #[clap(long)]
+        file_length: Option<u64>,
+
+        /// Size of each read, in bytes (K, M and G suffixes accepted).
+        ///
+        /// `auto` picks a size from the device's queue limits, larger for
+        /// spinning disks and RAID arrays.
+        #[clap(long, default_value = "128K")]
+        io_size: IoSize,
+
+        #[command(flatten)]
+        throttle: ThrottleArgs,
+
+        /// The device holding the filesystem, also the device intended to
+        /// be lifted to.
+        device: String,
     },
     /// Lifts a previously scanned file to the device.
     ///
@@ -69,117 +158,277 @@ enum Commands {
         /// some other unforseen issue on the particular data.
         #[clap(long, default_value_t = true)]
         dry_run: std::primitive::bool,
+
+        /// How to zero the ranges of the device which the report says should be zero.
+        #[clap(long, value_enum, default_value_t = ZeroStrategy::Auto)]
+        zero_strategy: ZeroStrategy,
+
+        /// Read ranges that should be zero first, and only zero the parts that aren't already.
+        ///
+        /// Turns writes into reads, which is faster on freshly trimmed SSDs
+        /// and avoids needless allocation on thinly provisioned storage.
+        #[clap(long)]
+        check_zeros: bool,
+
+        /// Once the lift has been verified, discard (TRIM) all the zero extents.
+        ///
+        /// Only permitted where discarded data is guaranteed to read as zeros:
+        /// regular files, and block devices supporting write-zeroes (which is
+        /// issued with unmapping permitted, rather than BLKDISCARD).
+        #[clap(long)]
+        discard_zeros: bool,
+
+        /// Open the device with O_DIRECT, bypassing the page cache.
+        ///
+        /// Every extent in the report must then be aligned to the device's
+        /// logical block size.
+        #[clap(long)]
+        direct: bool,
+
+        /// Pipeline extent copies through io_uring, keeping several reads and writes in flight.
+        ///
+        /// Falls back to synchronous IO if the kernel does not permit io_uring.
+        #[clap(long)]
+        io_uring: bool,
+
+        /// Number of threads used to run independent extent copies in parallel.
+        #[clap(long, default_value_t = 1)]
+        jobs: usize,
+
+        /// Size of each read and write, in bytes (K, M and G suffixes accepted).
+        ///
+        /// `auto` picks a size from the device's queue limits, larger for
+        /// spinning disks and RAID arrays.
+        #[clap(long, default_value = "128K")]
+        io_size: IoSize,
+
+        /// The order in which to move extents, see the `plan` command.
+        #[clap(long, value_enum, default_value_t = OrderPolicy::Source)]
+        order: OrderPolicy,
+
+        #[command(flatten)]
+        throttle: ThrottleArgs,
+
         /// The device to lift onto.
         device: String,
     },
+    /// Estimates the disk head movement of lifting with each `--order` policy.
+    ///
+    /// Reads a report from stdin and simulates the lift without any IO.
+    Plan,
+    /// Transforms previously captured reports.
+    Report {
+        #[command(subcommand)]
+        command: ReportCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReportCommands {
+    /// Rewrites a report to lift onto the device underneath an LVM / dm-linear device.
+    ///
+    /// The report is read from stdin, and the rewritten report is sent to stdout.
+    /// Only linear mappings onto a single underlying device are supported.
+    /// All data is verified against the underlying device.
+    ResolveDm {
+        /// The device-mapper device the report was scanned against.
+        dm_device: String,
+
+        /// The device underlying the device-mapper device, which will be lifted to.
+        device: String,
+    },
+    /// Combines reports for an image nested inside another loop file.
+    ///
+    /// Takes a report for the innermost file (scanned against the loop device of
+    /// the intermediate image), and a report for the intermediate image (scanned
+    /// against the real device).  Produces a single report, sent to stdout,
+    /// mapping the innermost file directly onto the device.  All data is
+    /// verified against the device.
+    Compose {
+        /// Report for the innermost file.
+        inner: String,
+
+        /// Report for the intermediate loop file.
+        outer: String,
+
+        /// The device the outer report was scanned against, which will be lifted to.
+        device: String,
+    },
 }
 
-fn main() -> ResultType<()> {
-    env_logger::builder()
+fn main() -> ExitCode {
+    let logger = env_logger::builder()
         .filter_level(log::LevelFilter::Info)
-        .init();
+        .build();
+    log::set_max_level(logger.filter());
+    log::set_boxed_logger(Box::new(events::EventLogger::new(logger)))
+        .expect("No logger is set yet.");
 
     let cli = Cli::parse();
+    let result = match &cli.events {
+        Some(target) => events::open(target),
+        None => Ok(()),
+    }
+    .and_then(|()| run(cli.command));
+
+    let code = match &result {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("{}", e);
+            e.exit_code()
+        }
+    };
+    events::emit(&events::Event::Finished {
+        success: result.is_ok(),
+        error_code: code.into(),
+        error: result.err().map(|e| e.to_string()),
+    });
+    ExitCode::from(code)
+}
 
-    match cli.command {
-        Commands::Scan { file, device } => scan::do_scan(
-            &mut fs::OpenOptions::new().read(true).open(file)?,
+fn run(command: Commands) -> ResultType<()> {
+    match command {
+        Commands::Scan {
+            file,
+            device,
+            zero_threshold,
+            io_size,
+            throttle,
+            fibmap,
+            extent_map,
+        } => {
+            let file = fs::OpenOptions::new().read(true).open(file)?;
+            let device = fs::OpenOptions::new().read(true).open(device)?;
+            let throttle = throttle.build()?;
+            let out = &mut ReportWriter::new(BufWriter::new(std::io::stdout()));
+            match (fibmap, extent_map) {
+                (true, _) => scan::do_scan_with(
+                    &mut scan::FibmapExtents::new(&file)?,
+                    Some(&file),
+                    &device,
+                    zero_threshold,
+                    io_size,
+                    throttle,
+                    out,
+                )?,
+                (false, Some(path)) => scan::do_scan_with(
+                    &mut scan::ExtentList {
+                        file_length: file.metadata()?.len(),
+                        extents: serde_json::Deserializer::from_reader(BufReader::new(
+                            fs::File::open(path)?,
+                        ))
+                        .into_iter()
+                        .collect::<Result<_, _>>()?,
+                    },
+                    Some(&file),
+                    &device,
+                    zero_threshold,
+                    io_size,
+                    throttle,
+                    out,
+                )?,
+                (false, None) => scan::do_scan_with(
+                    &mut scan::FiemapExtents::new(&file)?,
+                    Some(&file),
+                    &device,
+                    zero_threshold,
+                    io_size,
+                    throttle,
+                    out,
+                )?,
+            }
+        }
+        Commands::ScanXfs {
+            device,
+            path,
+            io_size,
+            throttle,
+        } => scan::do_scan_xfs(
             &mut fs::OpenOptions::new().read(true).open(device)?,
-            &mut BufWriter::new(std::io::stdout()),
+            &path,
+            io_size,
+            throttle.build()?,
+            &mut ReportWriter::new(BufWriter::new(std::io::stdout())),
         )?,
-        Commands::Lift { device, dry_run } => {
+        Commands::Import {
+            format,
+            file_length,
+            io_size,
+            throttle,
+            device,
+        } => scan::do_scan_with(
+            &mut scan::import_extents(
+                format,
+                &std::io::read_to_string(std::io::stdin())?,
+                file_length,
+            )?,
+            None,
+            &fs::OpenOptions::new().read(true).open(device)?,
+            0,
+            io_size,
+            throttle.build()?,
+            &mut ReportWriter::new(BufWriter::new(std::io::stdout())),
+        )?,
+        Commands::Lift {
+            device,
+            dry_run,
+            zero_strategy,
+            check_zeros,
+            discard_zeros,
+            direct,
+            io_uring,
+            jobs,
+            io_size,
+            order,
+            throttle,
+        } => {
             if dry_run {
                 info!("Dry-run mode.");
             } else {
                 warn!("Real mode, not a dry-run!");
             }
 
+            let options = LiftOptions {
+                dry_run,
+                zero_strategy,
+                check_zeros,
+                discard_zeros,
+                direct,
+                io_uring,
+                jobs,
+                io_size,
+                order,
+                throttle: throttle.build()?,
+            };
             lift::do_lift(
-                fs::OpenOptions::new()
-                    .read(true)
-                    .write(!dry_run)
-                    .open(device)?,
+                lift::open_device(device, &options)?,
                 &mut BufReader::new(std::io::stdin()),
-                dry_run,
+                &options,
             )?
         }
+        Commands::Plan => lift::do_plan(
+            &mut BufReader::new(std::io::stdin()),
+            &mut std::io::stdout(),
+        )?,
+        Commands::Report { command } => match command {
+            ReportCommands::ResolveDm { dm_device, device } => devmapper::do_resolve_dm(
+                &fs::OpenOptions::new().read(true).open(dm_device)?,
+                &fs::OpenOptions::new().read(true).open(device)?,
+                &mut BufReader::new(std::io::stdin()),
+                &mut BufWriter::new(std::io::stdout()),
+            )?,
+            ReportCommands::Compose {
+                inner,
+                outer,
+                device,
+            } => compose::do_compose(
+                &fs::OpenOptions::new().read(true).open(device)?,
+                &mut BufReader::new(fs::File::open(inner)?),
+                &mut BufReader::new(fs::File::open(outer)?),
+                &mut BufWriter::new(std::io::stdout()),
+            )?,
+        },
     }
 
     Ok(())
 }
-
-#[cfg(test)]
-mod tests {
-
-    use core::str;
-
-    use log::info;
-    use serde::{Deserialize, Serialize};
-
-    use crate::ResultType;
-
-    pub(crate) fn init_logger() {
-        let _ = env_logger::builder()
-            .is_test(true)
-            .filter_level(log::LevelFilter::Debug)
-            .try_init();
-    }
-
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
-    enum CSum {
-        Zeros(),
-        NonZero(u64),
-    }
-
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
-    struct Blah {
-        thing: u64,
-        other_thing: CSum,
-    }
-
-    #[derive(Deserialize, Serialize, Debug, PartialEq)]
-    struct Header {
-        message: String,
-    }
-
-    #[test]
-    fn serde_play() -> ResultType<()> {
-        init_logger();
-
-        let h = Header {
-            message: "Hello world!".to_string(),
-        };
-
-        let a = Blah {
-            thing: 10,
-            other_thing: CSum::NonZero(40),
-        };
-
-        let b = Blah {
-            thing: 20,
-            other_thing: CSum::Zeros(),
-        };
-
-        let mut buf: Vec<u8> = Vec::new();
-        let mut serializer = serde_json::Serializer::new(&mut buf);
-        h.serialize(&mut serializer)?;
-        a.serialize(&mut serializer)?;
-        b.serialize(&mut serializer)?;
-
-        info!("encoded: {}", str::from_utf8(buf.as_slice())?);
-
-        let mut r = serde_json::Deserializer::from_reader(buf.as_slice());
-
-        let h2: Header = Header::deserialize(&mut r)?;
-        let mut r_it = r.into_iter::<Blah>();
-        let a2: Blah = r_it.next().unwrap()?;
-        let b2: Blah = r_it.next().unwrap()?;
-
-        assert_eq!(h, h2);
-        assert_eq!(a, a2);
-        assert_eq!(b, b2);
-        assert!(r_it.next().is_none());
-
-        Ok(())
-    }
-}
@@ -0,0 +1,183 @@
+use std::{
+    io::IsTerminal,
+    time::{Duration, Instant},
+};
+
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+use log::info;
+
+use crate::{
+    events::{self, Event},
+    utils::{FileOps, FileOpsCounters},
+};
+
+/// How often progress is logged when there is no terminal to draw a bar on.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often progress events are sent, see `events`.
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Progress through one phase of work, measured in bytes processed out of
+/// the total planned for the phase.
+///
+/// Draws a bar with throughput and ETA on a terminal, otherwise logs a line
+/// every `LOG_INTERVAL`.  Also reported in the event stream, along with
+/// the IO performed during the phase.
+pub(crate) struct PhaseProgress {
+    name: &'static str,
+    total: u64,
+    done: u64,
+    started: Instant,
+    last_logged: Instant,
+    bar: Option<ProgressBar>,
+    /// Set while sending events.
+    last_event: Option<Instant>,
+    counters: FileOpsCounters,
+}
+
+impl PhaseProgress {
+    pub fn new(name: &'static str, total: u64, fops: &FileOps) -> Self {
+        // Quiet along with the log, such as for library users without a logger.
+        let show_bar = std::io::stderr().is_terminal() && log::log_enabled!(log::Level::Info);
+        let bar = show_bar.then(|| {
+            let bar = ProgressBar::new(total).with_prefix(name);
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "{prefix}: [{elapsed_precise}] [{wide_bar}] {binary_bytes}/{binary_total_bytes} {binary_bytes_per_sec} ETA {eta}",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+            );
+            bar
+        });
+        let now = Instant::now();
+        let last_event = events::enabled().then(|| {
+            events::emit(&Event::PhaseStart {
+                phase: name,
+                total_bytes: total,
+            });
+            now
+        });
+        Self {
+            name,
+            total,
+            done: 0,
+            started: now,
+            last_logged: now,
+            bar,
+            last_event,
+            counters: fops.counters(),
+        }
+    }
+
+    /// Records that `bytes` more have been processed.
+    pub fn inc(&mut self, bytes: u64) {
+        self.set(self.done + bytes);
+    }
+
+    /// Records that `done` bytes in total have been processed.
+    pub fn set(&mut self, done: u64) {
+        self.done = u64::min(done, self.total);
+        if let Some(last_event) = self.last_event {
+            if last_event.elapsed() >= EVENT_INTERVAL {
+                self.last_event = Some(Instant::now());
+                let rate = self.rate();
+                events::emit(&Event::Progress {
+                    phase: self.name,
+                    done_bytes: self.done,
+                    total_bytes: self.total,
+                    bytes_per_second: rate,
+                    eta_seconds: self.eta().map(|eta| eta.as_secs()),
+                });
+            }
+        }
+        match &self.bar {
+            Some(bar) => bar.set_position(self.done),
+            None if self.last_logged.elapsed() >= LOG_INTERVAL => {
+                self.last_logged = Instant::now();
+                let rate = self.rate();
+                let eta = match self.eta() {
+                    Some(eta) => HumanDuration(eta).to_string(),
+                    None => "unknown".to_string(),
+                };
+                info!(
+                    "{}: {} of {} ({}%), {}/s, ETA {}",
+                    self.name,
+                    HumanBytes(self.done),
+                    HumanBytes(self.total),
+                    match self.total {
+                        0 => 100,
+                        total => self.done * 100 / total,
+                    },
+                    HumanBytes(rate),
+                    eta
+                );
+            }
+            None => {}
+        }
+    }
+
+    /// Bytes per second so far.
+    fn rate(&self) -> u64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        match elapsed > 0.0 {
+            true => (self.done as f64 / elapsed) as u64,
+            false => 0,
+        }
+    }
+
+    /// Time remaining at the rate so far.
+    fn eta(&self) -> Option<Duration> {
+        match self.rate() {
+            0 => None,
+            rate => Some(Duration::from_secs((self.total - self.done) / rate)),
+        }
+    }
+
+    /// Ends the phase, `fops` being the same as was passed to `new`.
+    pub fn finish(self, fops: &FileOps) {
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+        info!(
+            "{}: {} in {} ({}/s)",
+            self.name,
+            HumanBytes(self.done),
+            HumanDuration(self.started.elapsed()),
+            HumanBytes(self.rate())
+        );
+        if self.last_event.is_some() {
+            events::emit(&Event::PhaseEnd {
+                phase: self.name,
+                done_bytes: self.done,
+                elapsed_seconds: self.started.elapsed().as_secs_f64(),
+                counters: fops.counters().since(&self.counters),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::init_logger, utils::FileOps};
+
+    use super::PhaseProgress;
+
+    #[test]
+    fn counts_bytes() {
+        init_logger();
+        let fops = FileOps::new(true);
+        let mut pb = PhaseProgress::new("Testing", 1000, &fops);
+        pb.inc(300);
+        pb.inc(300);
+        assert_eq!(pb.done, 600);
+        pb.set(5000);
+        assert_eq!(pb.done, 1000);
+        pb.finish(&fops);
+
+        // Phases with nothing to do are fine.
+        let mut pb = PhaseProgress::new("Testing", 0, &fops);
+        pb.set(0);
+        pb.finish(&fops);
+    }
+}
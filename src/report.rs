@@ -1,19 +1,217 @@
+use std::io;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct ReportSummary {
+use crate::{error::LoopliftError, ResultType};
+
+/// The first value of a report.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportSummary {
+    /// Length of the file being lifted, and so of the device it lifts onto.
     pub device_length: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct ReportExtent {
+/// Where the data for a range of the lifted file comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportExtent {
+    /// Offset within the file, which becomes the offset on the device.
     pub destination_offset: u64,
+    /// Length in bytes.
     pub length: u64,
+    /// Where the data is now.
     pub source: ExtentSource,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) enum ExtentSource {
+/// The current location of an extent's data.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtentSource {
+    /// The range reads as zeros, so needs zero filling rather than moving.
     Zeros,
-    Offset { offset: u64, checksum: u64 },
+    /// The data is on the device at `offset`.
+    Offset {
+        /// Offset on the device.
+        offset: u64,
+        /// Checksum of the data, verified before and after moving it.
+        checksum: u64,
+    },
+}
+
+/// Receives a report as it is produced, see `scan::do_scan`.
+pub trait ReportSink {
+    /// Called once, before any extents.
+    fn summary(&mut self, summary: &ReportSummary) -> ResultType<()>;
+
+    /// Called for each extent, in order of `destination_offset`.
+    fn extent(&mut self, extent: &ReportExtent) -> ResultType<()>;
+}
+
+/// A whole report held in memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// The summary.
+    pub summary: ReportSummary,
+    /// The extents, in order.
+    pub extents: Vec<ReportExtent>,
+}
+
+impl ReportSink for Report {
+    fn summary(&mut self, summary: &ReportSummary) -> ResultType<()> {
+        self.summary = summary.clone();
+        Ok(())
+    }
+
+    fn extent(&mut self, extent: &ReportExtent) -> ResultType<()> {
+        self.extents.push(extent.clone());
+        Ok(())
+    }
+}
+
+/// Reads a report, yielding extents in order and checking that they
+/// tile the whole device without gaps.  Iteration ends after an error.
+pub struct ReportReader<R: io::Read> {
+    deserializer: serde_json::Deserializer<serde_json::de::IoRead<R>>,
+    summary: ReportSummary,
+    expected_next_offset: u64,
+    failed: bool,
+}
+
+impl<R: io::Read> ReportReader<R> {
+    /// Reads the summary, leaving the extents to be iterated over.
+    pub fn new(input: R) -> ResultType<Self> {
+        let mut deserializer = serde_json::Deserializer::from_reader(input);
+        let summary = ReportSummary::deserialize(&mut deserializer)?;
+        if summary.device_length == 0 {
+            return Err(LoopliftError::ReportInconsistent(
+                "the device length is zero.".to_string(),
+            ));
+        }
+        Ok(Self {
+            deserializer,
+            summary,
+            expected_next_offset: 0,
+            failed: false,
+        })
+    }
+
+    /// The summary at the start of the report.
+    pub fn summary(&self) -> &ReportSummary {
+        &self.summary
+    }
+}
+
+impl<R: io::Read> ReportReader<R> {
+    fn read_extent(&mut self) -> ResultType<ReportExtent> {
+        let e = ReportExtent::deserialize(&mut self.deserializer)?;
+        if e.destination_offset != self.expected_next_offset {
+            return Err(LoopliftError::ReportInconsistent(format!(
+                "extent at offset {} was expected at offset {}.",
+                e.destination_offset, self.expected_next_offset
+            )));
+        }
+        if e.length == 0 || e.length > self.summary.device_length - e.destination_offset {
+            return Err(LoopliftError::ReportInconsistent(format!(
+                "extent at offset {} of length {} does not fit within the device.",
+                e.destination_offset, e.length
+            )));
+        }
+        self.expected_next_offset += e.length;
+        Ok(e)
+    }
+}
+
+impl<R: io::Read> Iterator for ReportReader<R> {
+    type Item = ResultType<ReportExtent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.expected_next_offset >= self.summary.device_length {
+            return None;
+        }
+        let result = self.read_extent();
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+/// Writes a report as a stream of JSON values, the summary first.
+pub struct ReportWriter<W: io::Write> {
+    serializer: serde_json::Serializer<W>,
+}
+
+impl<W: io::Write> ReportWriter<W> {
+    /// Nothing is written until the summary is.
+    pub fn new(out: W) -> Self {
+        Self {
+            serializer: serde_json::Serializer::new(out),
+        }
+    }
+
+    /// Writes the summary, which must come before any extents.
+    pub fn write_summary(&mut self, summary: &ReportSummary) -> ResultType<()> {
+        summary.serialize(&mut self.serializer)?;
+        Ok(())
+    }
+
+    /// Writes the next extent.
+    pub fn write(&mut self, extent: &ReportExtent) -> ResultType<()> {
+        extent.serialize(&mut self.serializer)?;
+        Ok(())
+    }
+}
+
+impl<W: io::Write> ReportSink for ReportWriter<W> {
+    fn summary(&mut self, summary: &ReportSummary) -> ResultType<()> {
+        self.write_summary(summary)
+    }
+
+    fn extent(&mut self, extent: &ReportExtent) -> ResultType<()> {
+        self.write(extent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{tests::init_logger, ResultType};
+
+    use super::{
+        ExtentSource, Report, ReportExtent, ReportReader, ReportSink, ReportSummary, ReportWriter,
+    };
+
+    #[test]
+    fn round_trip() -> ResultType<()> {
+        init_logger();
+        let mut report = Report::default();
+        report.summary(&ReportSummary {
+            device_length: 8192,
+        })?;
+        for extent in [
+            ReportExtent {
+                destination_offset: 0,
+                length: 4096,
+                source: ExtentSource::Offset {
+                    offset: 4096,
+                    checksum: 1234,
+                },
+            },
+            ReportExtent {
+                destination_offset: 4096,
+                length: 4096,
+                source: ExtentSource::Zeros,
+            },
+        ] {
+            report.extent(&extent)?;
+        }
+
+        let mut json = Vec::new();
+        let mut writer = ReportWriter::new(&mut json);
+        writer.write_summary(&report.summary)?;
+        for extent in &report.extents {
+            writer.write(extent)?;
+        }
+
+        let reader = ReportReader::new(json.as_slice())?;
+        assert_eq!(reader.summary(), &report.summary);
+        let extents = reader.collect::<ResultType<Vec<_>>>()?;
+        assert_eq!(extents, report.extents);
+        Ok(())
+    }
 }
@@ -1,126 +1,521 @@
-use std::{
-    io::{self},
-    os::fd::AsRawFd,
-};
+use std::{ops::Range, sync::Arc};
 
 use log::debug;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    fiemap::{fs_ioc_fiemap, ioctl, FiemapExtentFlag, FiemapFlag, FiemapRequestFull},
-    report::{ExtentSource, ReportExtent, ReportSummary},
-    utils::{validate_device_size, FileOps, SimpleProgress},
+    device::BlockDevice,
+    error::LoopliftError,
+    progress::PhaseProgress,
+    report::{ExtentSource, ReportExtent, ReportSink, ReportSummary},
+    throttle::Throttle,
+    utils::{validate_device_size, FileOps, IoSize, ScannedPiece},
+    xfs::XfsFilesystem,
     ResultType,
 };
 
-pub(crate) fn do_scan(
+mod fibmap;
+mod fiemap;
+mod import;
+
+pub use fibmap::FibmapExtents;
+pub use fiemap::FiemapExtents;
+pub use import::{import_extents, ImportFormat};
+
+/// What an extent of a file holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExtentKind {
+    /// Data, stored on the device at the extent's `physical` offset.
+    #[default]
+    Data,
+    /// Space allocated but never written, which reads as zeros.
+    Unwritten,
+    /// Data stored in a form which cannot be lifted, such as compressed or
+    /// inline in metadata.
+    Encoded,
+}
+
+/// A piece of a file, and where its data is on the device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileExtent {
+    /// Offset within the file.
+    pub logical: u64,
+    /// Offset on the device, meaningful only for data.
+    pub physical: u64,
+    /// Length in bytes, which must not be zero.
+    pub length: u64,
+    /// What the extent holds.
+    #[serde(default)]
+    pub kind: ExtentKind,
+}
+
+/// Finds where a file's extents are on the device, for `do_scan_with`.
+///
+/// Ranges of the file without an extent are holes, which read as zeros.
+pub trait ExtentProvider {
+    /// Length of the file.
+    fn file_length(&self) -> u64;
+
+    /// Returns the next extents, in order, the first starting at or after
+    /// `offset` (the end of the previous extent), or none once there are
+    /// no more.
+    fn next_extents(&mut self, offset: u64) -> ResultType<Vec<FileExtent>>;
+}
+
+/// Extents known up front, such as from an offline filesystem parser, an
+/// imported extent map, or a test.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtentList {
+    /// Length of the file.
+    pub file_length: u64,
+    /// The extents, in order of `logical`.
+    pub extents: Vec<FileExtent>,
+}
+
+impl ExtentProvider for ExtentList {
+    fn file_length(&self) -> u64 {
+        self.file_length
+    }
+
+    fn next_extents(&mut self, _offset: u64) -> ResultType<Vec<FileExtent>> {
+        Ok(std::mem::take(&mut self.extents))
+    }
+}
+
+/// Scans `file`, which must be on `device`, using FIEMAP, sending the
+/// report to `out`.  The data is verified by reading it through both.
+///
+/// Runs of zeros within data extents at least `zero_threshold` bytes long are
+/// reported as zero extents, zero disables this.
+pub fn do_scan(
     file: &mut std::fs::File,
-    device: &mut std::fs::File,
-    out: &mut impl io::Write,
+    device: &impl BlockDevice,
+    zero_threshold: u64,
+    io_size: IoSize,
+    throttle: Option<Arc<Throttle>>,
+    out: &mut impl ReportSink,
+) -> ResultType<()> {
+    do_scan_with(
+        &mut FiemapExtents::new(file)?,
+        Some(&*file),
+        device,
+        zero_threshold,
+        io_size,
+        throttle,
+        out,
+    )
+}
+
+/// Scans a file whose extents are found by `extents`, sending the report to
+/// `out`.
+///
+/// Where the file can be read as `file` (which need not be on `device`, nor
+/// a file at all), data is verified by reading it through both, holes and
+/// unwritten extents must read as zeros through it, and runs of zeros at
+/// least `zero_threshold` bytes long are reported as zero extents.
+/// Otherwise checksums are taken from the device alone.
+pub fn do_scan_with(
+    extents: &mut impl ExtentProvider,
+    file: Option<&dyn BlockDevice>,
+    device: &impl BlockDevice,
+    zero_threshold: u64,
+    io_size: IoSize,
+    throttle: Option<Arc<Throttle>>,
+    out: &mut impl ReportSink,
 ) -> ResultType<()> {
-    let file_length = file.metadata()?.len();
+    let file_length = extents.file_length();
     validate_device_size(device, file_length)?;
 
     let mut fops = FileOps::new(
         true, /* flag doesn't matter, as we don't attempt writes during scan. */
     );
+    fops.set_io_size(device, io_size)?;
+    fops.set_throttle(throttle);
 
-    let mut serializer = serde_json::Serializer::new(out);
-    ReportSummary {
+    out.summary(&ReportSummary {
         device_length: file_length,
-    }
-    .serialize(&mut serializer)?;
+    })?;
 
-    let mut pb = SimpleProgress::new(file_length);
+    let mut pb = PhaseProgress::new("Scanning", file_length, &fops);
 
     let mut file_offset = 0u64;
-    while file_offset < file_length {
-        assert!(file_offset < file_length);
-        pb.update(file_offset);
-
-        let mut fr = Box::new(FiemapRequestFull::default());
-        fr.request.fm_start = file_offset;
-        fr.request.fm_length = file_length - file_offset;
-        fr.request.fm_flags = FiemapFlag::SYNC.bits();
-        fr.request.fm_mapped_extents = 0;
-        fr.request.fm_extent_count = fr.fm_extents.len().try_into().unwrap();
-
-        let result = unsafe {
-            ioctl(
-                file.as_raw_fd(),
-                fs_ioc_fiemap(),
-                (&mut *fr) as *mut FiemapRequestFull,
-            )
+    'batches: while file_offset < file_length {
+        let batch = extents.next_extents(file_offset)?;
+        if batch.is_empty() {
+            break;
+        }
+        for e in batch {
+            debug!("Extent: {:?}", e);
+            if e.length == 0 {
+                return Err(format!("Extent at offset {} is empty.", e.logical).into());
+            }
+            if e.logical < file_offset {
+                return Err(
+                    format!("Extent at offset {} overlaps the previous one.", e.logical).into(),
+                );
+            }
+            if e.logical >= file_length {
+                break 'batches;
+            }
+            pb.set(e.logical);
+
+            if e.logical > file_offset {
+                scan_zeros(&mut fops, file, file_offset..e.logical, out)?;
+            }
+
+            let e = FileExtent {
+                length: u64::min(file_length - e.logical, e.length),
+                ..e
+            };
+            match e.kind {
+                ExtentKind::Data => scan_data(&mut fops, file, device, &e, zero_threshold, out)?,
+                ExtentKind::Unwritten => {
+                    scan_zeros(&mut fops, file, e.logical..(e.logical + e.length), out)?
+                }
+                ExtentKind::Encoded => {
+                    return Err(LoopliftError::UnsupportedExtent(format!(
+                        "Extent at offset {} is encoded (e.g. compressed) or inline, which cannot be lifted.",
+                        e.logical
+                    )));
+                }
+            }
+
+            file_offset = e.logical + e.length;
+        }
+    }
+
+    if file_offset < file_length {
+        scan_zeros(&mut fops, file, file_offset..file_length, out)?;
+    }
+    pb.set(file_length);
+    pb.finish(&fops);
+
+    fops.log_stats();
+
+    Ok(())
+}
+
+/// Reports a hole or unwritten extent of the file as zeros, having checked
+/// that it reads as zeros where there is a `file`.  Extent maps can miss
+/// data, such as FIBMAP with inline data, which this catches.
+fn scan_zeros(
+    fops: &mut FileOps,
+    file: Option<&dyn BlockDevice>,
+    range: Range<u64>,
+    out: &mut impl ReportSink,
+) -> ResultType<()> {
+    if let Some(file) = file {
+        if !fops.reads_as_zeros(&file, &range)? {
+            return Err(LoopliftError::UnsupportedExtent(format!(
+                "The file has no extent for offset {} to {}, yet it does not read as zeros.",
+                range.start, range.end
+            )));
+        }
+    }
+    let re = ReportExtent {
+        destination_offset: range.start,
+        length: range.end - range.start,
+        source: ExtentSource::Zeros,
+    };
+    out.extent(&re)
+}
+
+/// Reports a data extent of the file, verified against `file` and split
+/// around runs of zeros where there is a `file`.
+fn scan_data(
+    fops: &mut FileOps,
+    file: Option<&dyn BlockDevice>,
+    device: &impl BlockDevice,
+    e: &FileExtent,
+    zero_threshold: u64,
+    out: &mut impl ReportSink,
+) -> ResultType<()> {
+    let pieces = match file {
+        Some(file) => fops.check_equality_and_split_zeros(
+            &file,
+            e.logical,
+            device,
+            e.physical,
+            e.length,
+            zero_threshold,
+        )?,
+        None => vec![ScannedPiece::Data {
+            length: e.length,
+            checksum: fops.compute_checksum(device, e.physical, e.length)?,
+        }],
+    };
+
+    let mut piece_offset = 0u64;
+    for piece in pieces {
+        let (length, source) = match piece {
+            ScannedPiece::Data { length, checksum } => (
+                length,
+                ExtentSource::Offset {
+                    offset: e.physical + piece_offset,
+                    checksum,
+                },
+            ),
+            ScannedPiece::Zeros { length } => (length, ExtentSource::Zeros),
         };
+        let re = ReportExtent {
+            destination_offset: e.logical + piece_offset,
+            length,
+            source,
+        };
+        out.extent(&re)?;
+        piece_offset += length;
+    }
+    Ok(())
+}
+
+/// Scans a file on an unmounted XFS filesystem by reading the filesystem
+/// structures directly from the device, rather than asking the kernel.
+/// `path` is relative to the root of the filesystem.
+pub fn do_scan_xfs(
+    device: &mut std::fs::File,
+    path: &str,
+    io_size: IoSize,
+    throttle: Option<Arc<Throttle>>,
+    out: &mut impl ReportSink,
+) -> ResultType<()> {
+    let fs = XfsFilesystem::open(device)?;
+    let inode = fs.read_inode(fs.lookup(path)?)?;
+    if !inode.is_regular_file() {
+        return Err(format!("'{}' is not a regular file.", path).into());
+    }
+    if inode.is_realtime() {
+        return Err(format!(
+            "'{}' is on the realtime device, which is unsupported.",
+            path
+        )
+        .into());
+    }
+
+    let mut extents = ExtentList {
+        file_length: inode.size,
+        extents: Vec::new(),
+    };
+    for e in fs.extents(&inode)? {
+        extents.extents.push(FileExtent {
+            logical: e.startoff * fs.blocksize(),
+            physical: fs.fsb_to_bytes(e.startblock),
+            length: e.blockcount * fs.blocksize(),
+            kind: match e.unwritten {
+                true => ExtentKind::Unwritten,
+                false => ExtentKind::Data,
+            },
+        });
+    }
+
+    do_scan_with(&mut extents, None, &*device, 0, io_size, throttle, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+
+    use crate::{
+        device::MemDevice,
+        error::LoopliftError,
+        report::{ExtentSource, Report},
+        tests::{init_logger, scratch_file},
+        utils::IoSize,
+        ResultType,
+    };
 
-        if result != 0 {
-            return Err(Box::new(io::Error::last_os_error()));
+    use super::{
+        do_scan_with, ExtentKind, ExtentList, ExtentProvider, FibmapExtents, FiemapExtents,
+        FileExtent,
+    };
+
+    fn extent(logical: u64, physical: u64, length: u64, kind: ExtentKind) -> FileExtent {
+        FileExtent {
+            logical,
+            physical,
+            length,
+            kind,
         }
+    }
+
+    fn scan(
+        extents: Vec<FileExtent>,
+        file: Option<&MemDevice>,
+        device: &MemDevice,
+    ) -> ResultType<Report> {
+        let mut report = Report::default();
+        do_scan_with(
+            &mut ExtentList {
+                file_length: 40,
+                extents,
+            },
+            file.map(|f| f as _),
+            device,
+            0,
+            IoSize::Bytes(4096),
+            None,
+            &mut report,
+        )?;
+        Ok(report)
+    }
 
-        for e in &fr.fm_extents[..fr.request.fm_mapped_extents.try_into().unwrap()] {
-            debug!("Extent: {:?}", *e);
-            let flags = FiemapExtentFlag::from_bits(e.fe_flags).expect("Unknown extend bits set.");
+    #[test]
+    fn shared_validation() -> ResultType<()> {
+        init_logger();
 
-            assert!(e.fe_logical >= file_offset);
-            assert!(e.fe_logical < file_length);
+        // Data at 5..15 and 30..40, zeros elsewhere.
+        let file = MemDevice::from(
+            (0..40)
+                .map(|i| match i {
+                    5..15 | 30..40 => i + 1,
+                    _ => 0,
+                })
+                .collect::<Vec<u8>>(),
+        );
+        let mut content = vec![0u8; 64];
+        content[20..30].copy_from_slice(&file.to_vec()[5..15]);
+        content[50..60].copy_from_slice(&file.to_vec()[30..40]);
+        let device = MemDevice::from(content);
 
-            if e.fe_logical > file_offset {
-                let re = ReportExtent {
-                    destination_offset: file_offset,
-                    length: e.fe_logical - file_offset,
-                    source: ExtentSource::Zeros,
+        // Gaps and unwritten extents become zeros, and the last extent is
+        // cut short at the end of the file.
+        let extents = vec![
+            extent(5, 20, 10, ExtentKind::Data),
+            extent(15, 0, 5, ExtentKind::Unwritten),
+            extent(30, 50, 16, ExtentKind::Data),
+            extent(48, 0, 8, ExtentKind::Data),
+        ];
+        let verified = scan(extents.clone(), Some(&file), &device)?;
+        let sources: Vec<(u64, u64, Option<u64>)> = verified
+            .extents
+            .iter()
+            .map(|e| {
+                let offset = match e.source {
+                    ExtentSource::Zeros => None,
+                    ExtentSource::Offset { offset, .. } => Some(offset),
                 };
-                re.serialize(&mut serializer)?;
-            }
+                (e.destination_offset, e.length, offset)
+            })
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                (0, 5, None),
+                (5, 10, Some(20)),
+                (15, 5, None),
+                (20, 10, None),
+                (30, 10, Some(50))
+            ]
+        );
+        assert_eq!(verified.summary.device_length, 40);
 
-            debug!("Flags: {:?}", flags);
-            assert!(!flags.contains(FiemapExtentFlag::ENCODED));
+        // Without the file, checksums come from the device alone.
+        assert_eq!(scan(extents, None, &device)?, verified);
 
-            let readable_length = u64::min(file_length - e.fe_logical, e.fe_length);
+        let wrong = vec![extent(5, 21, 10, ExtentKind::Data)];
+        assert!(matches!(
+            scan(wrong, Some(&file), &device),
+            Err(LoopliftError::DataMismatch { .. })
+        ));
+        let encoded = vec![extent(5, 20, 10, ExtentKind::Encoded)];
+        assert!(matches!(
+            scan(encoded, None, &device),
+            Err(LoopliftError::UnsupportedExtent(_))
+        ));
+        let overlapping = vec![
+            extent(5, 20, 10, ExtentKind::Data),
+            extent(10, 25, 10, ExtentKind::Data),
+        ];
+        assert!(scan(overlapping, None, &device).is_err());
+        let empty = vec![extent(5, 20, 0, ExtentKind::Data)];
+        assert!(scan(empty, None, &device).is_err());
 
-            if flags.contains(FiemapExtentFlag::UNWRITTEN) {
-                let re = ReportExtent {
-                    destination_offset: e.fe_logical,
-                    length: readable_length,
-                    source: ExtentSource::Zeros,
-                };
-                re.serialize(&mut serializer)?;
-            } else {
-                let csum = fops.check_equality_and_compute_checksum(
-                    file,
-                    e.fe_logical,
-                    device,
-                    e.fe_physical,
-                    readable_length,
-                )?;
-
-                let re = ReportExtent {
-                    destination_offset: e.fe_logical,
-                    length: readable_length,
-                    source: ExtentSource::Offset {
-                        offset: e.fe_physical,
-                        checksum: csum,
-                    },
-                };
-                re.serialize(&mut serializer)?;
-            }
+        // Holes and unwritten extents must read as zeros through the file.
+        for missing in [
+            vec![extent(5, 20, 10, ExtentKind::Data)],
+            vec![
+                extent(5, 20, 10, ExtentKind::Data),
+                extent(30, 0, 10, ExtentKind::Unwritten),
+            ],
+        ] {
+            assert!(scan(missing.clone(), None, &device).is_ok());
+            assert!(matches!(
+                scan(missing, Some(&file), &device),
+                Err(LoopliftError::UnsupportedExtent(_))
+            ));
+        }
 
-            file_offset = e.fe_logical + readable_length;
+        // An empty file gives no report, as there would be nothing to lift.
+        let mut report = Report::default();
+        let e = do_scan_with(
+            &mut ExtentList {
+                file_length: 0,
+                extents: Vec::new(),
+            },
+            Some(&file),
+            &device,
+            0,
+            IoSize::Bytes(4096),
+            None,
+            &mut report,
+        )
+        .unwrap_err();
+        assert_eq!(e.exit_code(), 1);
+        assert_eq!(report, Report::default());
+        Ok(())
+    }
 
-            if flags.contains(FiemapExtentFlag::LAST) {
-                let re = ReportExtent {
-                    destination_offset: file_offset,
-                    length: file_length - file_offset,
-                    source: ExtentSource::Zeros,
-                };
-                re.serialize(&mut serializer)?;
-                file_offset = file_length;
+    /// The data blocks of a file, as (block in the file, block on the device).
+    fn data_blocks(extents: &mut impl ExtentProvider) -> ResultType<Vec<(u64, u64)>> {
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        loop {
+            let batch = extents.next_extents(offset)?;
+            if batch.is_empty() {
+                return Ok(blocks);
+            }
+            for e in batch {
+                assert_eq!(e.kind, ExtentKind::Data);
+                let length = u64::min(e.length, extents.file_length() - e.logical);
+                blocks.extend(
+                    (0..length.div_ceil(4096))
+                        .map(|b| (e.logical / 4096 + b, e.physical / 4096 + b)),
+                );
+                offset = e.logical + e.length;
             }
         }
     }
-    pb.finish();
 
-    fops.log_stats();
+    #[test]
+    fn fibmap_matches_fiemap() -> ResultType<()> {
+        init_logger();
 
-    Ok(())
+        let f = scratch_file("fibmap")?;
+        for block in [0u64, 1, 2, 7, 8, 30] {
+            f.write_all_at(&[block as u8 + 1; 4096], block * 4096)?;
+        }
+        f.set_len(40 * 4096 + 100)?;
+        f.sync_all()?;
+
+        let mut fibmap = match FibmapExtents::new(&f) {
+            Ok(fibmap) => fibmap,
+            Err(e) => {
+                log::warn!("Skipping, FIBMAP is unavailable: {}", e);
+                return Ok(());
+            }
+        };
+        let by_fibmap = match data_blocks(&mut fibmap) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                log::warn!("Skipping, FIBMAP is unavailable: {}", e);
+                return Ok(());
+            }
+        };
+        let by_fiemap = data_blocks(&mut FiemapExtents::new(&f)?)?;
+        assert_eq!(by_fibmap, by_fiemap);
+        assert_eq!(
+            by_fibmap.iter().map(|(b, _)| *b).collect::<Vec<_>>(),
+            [0, 1, 2, 7, 8, 30]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,81 @@
+use std::{ffi::c_int, fs::File, io, os::fd::AsRawFd};
+
+use crate::{
+    fiemap::{ioctl, FIBMAP, FIGETBSZ},
+    ResultType,
+};
+
+use super::{ExtentKind, ExtentProvider, FileExtent};
+
+/// The extents of a file, from the older FIBMAP ioctl, for filesystems
+/// without FIEMAP.
+///
+/// Requires root.  Each block is mapped separately, so this is slow for
+/// large files.  Unwritten blocks map to zero, as do blocks of inline data
+/// on ext4, so both come back as holes; `do_scan_with` catches the latter
+/// by checking that holes read as zeros.
+pub struct FibmapExtents<'a> {
+    file: &'a File,
+    file_length: u64,
+    block_size: u64,
+}
+
+impl<'a> FibmapExtents<'a> {
+    /// Prepares to map `file`.
+    pub fn new(file: &'a File) -> ResultType<Self> {
+        let mut block_size: c_int = 0;
+        if unsafe { ioctl(file.as_raw_fd(), FIGETBSZ, &mut block_size as *mut c_int) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self {
+            file,
+            file_length: file.metadata()?.len(),
+            block_size: u64::try_from(block_size)?,
+        })
+    }
+
+    /// The device block holding `block` of the file, zero for a hole.
+    fn map(&self, block: u64) -> ResultType<u64> {
+        let mut arg: c_int = block
+            .try_into()
+            .map_err(|_| format!("Block {} of the file is beyond the reach of FIBMAP.", block))?;
+        if unsafe { ioctl(self.file.as_raw_fd(), FIBMAP, &mut arg as *mut c_int) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(u64::try_from(arg)?)
+    }
+}
+
+impl ExtentProvider for FibmapExtents<'_> {
+    fn file_length(&self) -> u64 {
+        self.file_length
+    }
+
+    /// Returns a single extent, of the physically contiguous blocks
+    /// following any hole at `offset`.
+    fn next_extents(&mut self, offset: u64) -> ResultType<Vec<FileExtent>> {
+        let blocks = self.file_length.div_ceil(self.block_size);
+        let mut block = offset / self.block_size;
+        let start_physical = loop {
+            if block >= blocks {
+                return Ok(Vec::new());
+            }
+            match self.map(block)? {
+                0 => block += 1,
+                physical => break physical,
+            }
+        };
+
+        let start = block;
+        block += 1;
+        while block < blocks && self.map(block)? == start_physical + (block - start) {
+            block += 1;
+        }
+        Ok(vec![FileExtent {
+            logical: start * self.block_size,
+            physical: start_physical * self.block_size,
+            length: (block - start) * self.block_size,
+            kind: ExtentKind::Data,
+        }])
+    }
+}
@@ -0,0 +1,129 @@
+use std::{fs::File, io, os::fd::AsRawFd};
+
+use log::debug;
+
+use crate::{
+    btrfs::ChunkMap,
+    error::LoopliftError,
+    fiemap::{fs_ioc_fiemap, ioctl, FiemapExtentFlag, FiemapFlag, FiemapRequestFull},
+    ResultType,
+};
+
+use super::{ExtentKind, ExtentProvider, FileExtent};
+
+/// The extents of a file, from the FIEMAP ioctl.
+///
+/// On btrfs, physical offsets are translated through the chunk tree, which
+/// requires root.
+pub struct FiemapExtents<'a> {
+    file: &'a File,
+    file_length: u64,
+    chunk_map: Option<ChunkMap>,
+    /// The last extent has been returned.
+    done: bool,
+}
+
+impl<'a> FiemapExtents<'a> {
+    /// Prepares to map `file`.
+    pub fn new(file: &'a File) -> ResultType<Self> {
+        Ok(Self {
+            file,
+            file_length: file.metadata()?.len(),
+            chunk_map: ChunkMap::for_file(file)?,
+            done: false,
+        })
+    }
+}
+
+impl ExtentProvider for FiemapExtents<'_> {
+    fn file_length(&self) -> u64 {
+        self.file_length
+    }
+
+    fn next_extents(&mut self, offset: u64) -> ResultType<Vec<FileExtent>> {
+        if self.done || offset >= self.file_length {
+            return Ok(Vec::new());
+        }
+
+        let mut fr = Box::new(FiemapRequestFull::default());
+        fr.request.fm_start = offset;
+        fr.request.fm_length = self.file_length - offset;
+        fr.request.fm_flags = FiemapFlag::SYNC.bits();
+        fr.request.fm_mapped_extents = 0;
+        fr.request.fm_extent_count = fr.fm_extents.len().try_into().unwrap();
+
+        let result = unsafe {
+            ioctl(
+                self.file.as_raw_fd(),
+                fs_ioc_fiemap(),
+                (&mut *fr) as *mut FiemapRequestFull,
+            )
+        };
+
+        if result != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mapped = &fr.fm_extents[..fr.request.fm_mapped_extents.try_into().unwrap()];
+        if mapped.is_empty() {
+            self.done = true;
+        }
+
+        let mut extents = Vec::new();
+        for e in mapped {
+            debug!("FIEMAP extent: {:?}", *e);
+            let flags = FiemapExtentFlag::from_bits(e.fe_flags).ok_or_else(|| {
+                LoopliftError::UnsupportedExtent(format!(
+                    "Extent at offset {} has unknown flags 0x{:x}.",
+                    e.fe_logical, e.fe_flags
+                ))
+            })?;
+            debug!("Flags: {:?}", flags);
+
+            if e.fe_logical < offset || e.fe_logical >= self.file_length {
+                return Err(format!(
+                    "FIEMAP returned an extent at offset {}, outside of the range requested.",
+                    e.fe_logical
+                )
+                .into());
+            }
+
+            let kind =
+                if flags.intersects(FiemapExtentFlag::ENCODED | FiemapExtentFlag::DATA_INLINE) {
+                    ExtentKind::Encoded
+                } else if flags.contains(FiemapExtentFlag::UNWRITTEN) {
+                    ExtentKind::Unwritten
+                } else {
+                    ExtentKind::Data
+                };
+            match (&self.chunk_map, kind) {
+                (Some(m), ExtentKind::Data) => {
+                    // One extent per chunk, as btrfs merges across them.
+                    let mut logical = e.fe_logical;
+                    let length = u64::min(self.file_length - e.fe_logical, e.fe_length);
+                    for (physical, length) in m.to_physical(e.fe_physical, length)? {
+                        extents.push(FileExtent {
+                            logical,
+                            physical,
+                            length,
+                            kind,
+                        });
+                        logical += length;
+                    }
+                }
+                _ => extents.push(FileExtent {
+                    logical: e.fe_logical,
+                    physical: e.fe_physical,
+                    length: e.fe_length,
+                    kind,
+                }),
+            }
+
+            if flags.contains(FiemapExtentFlag::LAST) {
+                self.done = true;
+                break;
+            }
+        }
+        Ok(extents)
+    }
+}
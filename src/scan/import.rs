@@ -0,0 +1,414 @@
+use crate::{error::LoopliftError, ResultType};
+
+use super::{ExtentKind, ExtentList, FileExtent};
+
+/// Magic number of btrfs, as `filefrag` prints the filesystem type.
+const BTRFS_MAGIC: &str = "9123683e";
+
+/// Units of `xfs_bmap` offsets and lengths.
+const XFS_BASIC_BLOCK: u64 = 512;
+
+/// `xfs_bmap` flags which don't change what the extent holds: shared, and
+/// not beginning or ending on a stripe unit or width.
+const XFS_HARMLESS_FLAGS: u64 = 0o100000 | 0o001000 | 0o000100 | 0o000010 | 0o000001;
+
+/// `xfs_bmap` flag of unwritten (preallocated) extents.
+const XFS_UNWRITTEN_FLAG: u64 = 0o010000;
+
+/// Tools whose output `import_extents` understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ImportFormat {
+    /// `filefrag -v`, from e2fsprogs.
+    Filefrag,
+    /// `xfs_bmap -vp`, from xfsprogs.
+    XfsBmap,
+}
+
+/// Parses the extents of a single file from the output of an external tool.
+/// `file_length` is required for `xfs_bmap`, which doesn't record it.
+///
+/// There is no file to verify the data against, so only plain data extents
+/// and holes are accepted.  Unwritten extents, and any others which might
+/// not hold the file's data as it reads, are refused.
+pub fn import_extents(
+    format: ImportFormat,
+    text: &str,
+    file_length: Option<u64>,
+) -> ResultType<ExtentList> {
+    match format {
+        ImportFormat::Filefrag => parse_filefrag(text, file_length),
+        ImportFormat::XfsBmap => parse_xfs_bmap(text, file_length),
+    }
+}
+
+/// Parses `first..last`, an inclusive range.
+fn parse_range(s: &str) -> Option<(u64, u64)> {
+    let (first, last) = s.split_once("..")?;
+    let first: u64 = first.trim().parse().ok()?;
+    let last: u64 = last.trim().parse().ok()?;
+    (first <= last).then_some((first, last))
+}
+
+/// Splits off the extent number from lines like `  12: ...`.
+fn extent_line(line: &str) -> Option<(u64, &str)> {
+    let (index, rest) = line.trim_start().split_once(':')?;
+    Some((index.parse().ok()?, rest))
+}
+
+fn parse_filefrag(text: &str, file_length: Option<u64>) -> ResultType<ExtentList> {
+    let mut header: Option<(u64, u64)> = None;
+    let mut found: Option<u64> = None;
+    let mut extents = Vec::new();
+
+    for line in text.lines() {
+        if let Some(fs_type) = line.strip_prefix("Filesystem type is: ") {
+            if fs_type.trim() == BTRFS_MAGIC {
+                return Err(LoopliftError::UnsupportedExtent(
+                    "filefrag reports btrfs logical addresses, not device offsets, use `scan` instead."
+                        .to_string(),
+                ));
+            }
+        } else if line.starts_with("File size of ") {
+            // File size of NAME is SIZE (COUNT blocks of BLOCK_SIZE bytes)
+            let parsed = line.rsplit_once(" (").and_then(|(head, tail)| {
+                let size = head.rsplit_once(" is ")?.1.parse().ok()?;
+                let block_size = tail.strip_suffix(" bytes)")?.rsplit_once(" of ")?.1;
+                Some((size, block_size.parse().ok().filter(|&b| b > 0)?))
+            });
+            match (header, parsed) {
+                (Some(_), _) => return Err("filefrag output lists more than one file.".into()),
+                (None, None) => {
+                    return Err(format!("Unrecognised filefrag line '{}'.", line).into())
+                }
+                (None, parsed) => header = parsed,
+            }
+        } else if let Some((index, rest)) = extent_line(line) {
+            let (_, block_size) =
+                header.ok_or("filefrag output lists extents before the file size.")?;
+            if index != extents.len() as u64 {
+                return Err(format!("filefrag output lacks extent {}.", extents.len()).into());
+            }
+
+            // Logical and physical ranges and length, then the expected
+            // block if it isn't the next one, and the flags.
+            let fields: Vec<&str> = rest.split(':').map(str::trim).collect();
+            let flags = match fields.len() {
+                4 => fields[3],
+                5 => fields[4],
+                _ => return Err(format!("Unrecognised filefrag line '{}'.", line).into()),
+            };
+            let logical = parse_range(fields[0]);
+            let physical = parse_range(fields[1]);
+            let length: Option<u64> = fields[2].parse().ok();
+            let (first, first_physical, length) = match (logical, physical, length) {
+                (Some((first, last)), Some((first_physical, last_physical)), Some(length))
+                    if last - first + 1 == length
+                        && last_physical - first_physical + 1 == length =>
+                {
+                    (first, first_physical, length)
+                }
+                _ => return Err(format!("Unrecognised filefrag line '{}'.", line).into()),
+            };
+
+            for flag in flags.split(',').filter(|f| !f.is_empty()) {
+                if !["last", "eof", "merged", "shared", "not_aligned"].contains(&flag) {
+                    return Err(LoopliftError::UnsupportedExtent(format!(
+                        "Extent {} is '{}', which cannot be imported safely.",
+                        index, flag
+                    )));
+                }
+            }
+
+            extents.push(FileExtent {
+                logical: first * block_size,
+                physical: first_physical * block_size,
+                length: length * block_size,
+                kind: ExtentKind::Data,
+            });
+        } else if let Some(count) = line
+            .strip_suffix(" extents found")
+            .or_else(|| line.strip_suffix(" extent found"))
+        {
+            found = count.rsplit_once(": ").and_then(|(_, c)| c.parse().ok());
+        }
+    }
+
+    let (size, _) = header.ok_or("Not `filefrag -v` output, there is no file size.")?;
+    match found {
+        None => return Err("The filefrag output is truncated.".into()),
+        Some(found) if found != extents.len() as u64 => {
+            return Err(format!(
+                "filefrag found {} extents, but lists {}.",
+                found,
+                extents.len()
+            )
+            .into())
+        }
+        Some(_) => {}
+    }
+    if file_length.is_some_and(|l| l != size) {
+        return Err(format!(
+            "The file length given differs from the {} bytes filefrag reports.",
+            size
+        )
+        .into());
+    }
+
+    Ok(ExtentList {
+        file_length: size,
+        extents,
+    })
+}
+
+fn parse_xfs_bmap(text: &str, file_length: Option<u64>) -> ResultType<ExtentList> {
+    let file_length = file_length
+        .ok_or("xfs_bmap output does not record the file's length, it must be given.")?;
+    let mut header = false;
+    let mut next = 0u64;
+    let mut extents = Vec::new();
+
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.first() == Some(&"EXT:") {
+            if header {
+                return Err("xfs_bmap output lists more than one file.".into());
+            }
+            if fields.contains(&"RT-BLOCK-RANGE") {
+                return Err("The file is on the realtime device, which is unsupported.".into());
+            }
+            if !fields.contains(&"BLOCK-RANGE") || !fields.contains(&"AG") {
+                return Err("Not `xfs_bmap -v` output, capture it with `xfs_bmap -vp`.".into());
+            }
+            if !fields.contains(&"FLAGS") {
+                return Err(
+                    "The xfs_bmap output has no flags, capture it with `xfs_bmap -vp`.".into(),
+                );
+            }
+            header = true;
+        } else if let Some((index, rest)) = extent_line(line) {
+            if !header {
+                return Err("Not `xfs_bmap -v` output, there is no column header.".into());
+            }
+            // [FIRST..LAST]: then either `hole` and the length, or the block
+            // range, allocation group, offset within it, length and flags.
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            let (first, last) = fields
+                .first()
+                .and_then(|f| f.strip_prefix('[')?.strip_suffix("]:"))
+                .and_then(parse_range)
+                .ok_or_else(|| format!("Unrecognised xfs_bmap line '{}'.", line))?;
+            if first != next {
+                return Err(format!(
+                    "xfs_bmap extent {} does not follow on from the previous one.",
+                    index
+                )
+                .into());
+            }
+            next = last + 1;
+
+            match fields.get(1) {
+                Some(&"hole") => continue,
+                Some(range) => {
+                    let Some((first_physical, last_physical)) = parse_range(range) else {
+                        return Err(LoopliftError::UnsupportedExtent(format!(
+                            "Extent {} is '{}', which cannot be imported safely.",
+                            index, range
+                        )));
+                    };
+                    let (total, flags) = match fields[..] {
+                        [_, _, _, _, total, flags] => (
+                            total.parse::<u64>().ok(),
+                            u64::from_str_radix(flags, 8).ok(),
+                        ),
+                        _ => (None, None),
+                    };
+                    let length = last - first + 1;
+                    let (Some(total), Some(flags)) = (total, flags) else {
+                        return Err(format!("Unrecognised xfs_bmap line '{}'.", line).into());
+                    };
+                    if total != length || last_physical - first_physical + 1 != length {
+                        return Err(format!("Unrecognised xfs_bmap line '{}'.", line).into());
+                    }
+                    if flags & XFS_UNWRITTEN_FLAG != 0 {
+                        return Err(LoopliftError::UnsupportedExtent(format!(
+                            "Extent {} is unwritten, which cannot be imported safely.",
+                            index
+                        )));
+                    }
+                    if flags & !XFS_HARMLESS_FLAGS != 0 {
+                        return Err(LoopliftError::UnsupportedExtent(format!(
+                            "Extent {} has unknown flags {:o}, which cannot be imported safely.",
+                            index, flags
+                        )));
+                    }
+
+                    extents.push(FileExtent {
+                        logical: first * XFS_BASIC_BLOCK,
+                        physical: first_physical * XFS_BASIC_BLOCK,
+                        length: length * XFS_BASIC_BLOCK,
+                        kind: ExtentKind::Data,
+                    });
+                }
+                None => return Err(format!("Unrecognised xfs_bmap line '{}'.", line).into()),
+            }
+        }
+    }
+
+    if !header {
+        return Err("Not `xfs_bmap -v` output, there is no column header.".into());
+    }
+    Ok(ExtentList {
+        file_length,
+        extents,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{error::LoopliftError, tests::init_logger, ResultType};
+
+    use super::{import_extents, ExtentKind, FileExtent, ImportFormat};
+
+    const FILEFRAG: &str = "\
+Filesystem type is: ef53
+File size of ff.img is 8388608 (2048 blocks of 4096 bytes)
+ ext:     logical_offset:        physical_offset: length:   expected: flags:
+   0:        5..       7:    5476613..   5476615:      3:          5:
+   1:      256..     271:    5472784..   5472799:     16:    5476864: unwritten
+   2:      700..     701:    5481414..   5481415:      2:    5473228: last
+ff.img: 3 extents found
+";
+
+    const XFS_BMAP: &str = "\
+disk.img:
+ EXT: FILE-OFFSET      BLOCK-RANGE      AG AG-OFFSET        TOTAL FLAGS
+   0: [0..7]:          96..103           0 (96..103)            8 000000
+   1: [8..15]:         hole                                     8
+   2: [16..23]:        112..119          0 (112..119)           8 100000
+ FLAG Values:
+    0100000 Shared extent
+    0010000 Unwritten preallocated extent
+    0001000 Doesn't begin on stripe unit
+    0000100 Doesn't end   on stripe unit
+    0000010 Doesn't begin on stripe width
+    0000001 Doesn't end   on stripe width
+";
+
+    fn extent(logical: u64, physical: u64, length: u64) -> FileExtent {
+        FileExtent {
+            logical,
+            physical,
+            length,
+            kind: ExtentKind::Data,
+        }
+    }
+
+    fn unsupported(result: ResultType<impl std::fmt::Debug>) -> bool {
+        matches!(result, Err(LoopliftError::UnsupportedExtent(_)))
+    }
+
+    #[test]
+    fn filefrag() -> ResultType<()> {
+        init_logger();
+
+        // As captured, with an unwritten extent.
+        assert!(unsupported(import_extents(
+            ImportFormat::Filefrag,
+            FILEFRAG,
+            None
+        )));
+
+        let written = FILEFRAG.replace(" unwritten", "");
+        let list = import_extents(ImportFormat::Filefrag, &written, None)?;
+        assert_eq!(list.file_length, 8388608);
+        assert_eq!(
+            list.extents,
+            [
+                extent(5 * 4096, 5476613 * 4096, 3 * 4096),
+                extent(256 * 4096, 5472784 * 4096, 16 * 4096),
+                extent(700 * 4096, 5481414 * 4096, 2 * 4096),
+            ]
+        );
+        assert!(import_extents(ImportFormat::Filefrag, &written, Some(8388608)).is_ok());
+        assert!(import_extents(ImportFormat::Filefrag, &written, Some(4096)).is_err());
+
+        // Flags after a blank expected block.
+        let inline = written.replace(
+            "     2:    5473228: last",
+            "     2:             inline,last",
+        );
+        assert!(unsupported(import_extents(
+            ImportFormat::Filefrag,
+            &inline,
+            None
+        )));
+
+        // Incomplete or otherwise mangled output.
+        for (from, to) in [
+            ("   1:      256..", "   4:      256.."),
+            ("ff.img: 3 extents found\n", ""),
+            ("3 extents found", "4 extents found"),
+            ("     16:    5476864", "     17:    5476864"),
+            ("(2048 blocks of 4096 bytes)", "(2048 blocks)"),
+        ] {
+            assert!(
+                import_extents(ImportFormat::Filefrag, &written.replace(from, to), None).is_err()
+            );
+        }
+        let btrfs = written.replace("ef53", "9123683e");
+        assert!(unsupported(import_extents(
+            ImportFormat::Filefrag,
+            &btrfs,
+            None
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn xfs_bmap() -> ResultType<()> {
+        init_logger();
+
+        assert!(import_extents(ImportFormat::XfsBmap, XFS_BMAP, None).is_err());
+        let list = import_extents(ImportFormat::XfsBmap, XFS_BMAP, Some(12000))?;
+        assert_eq!(list.file_length, 12000);
+        assert_eq!(
+            list.extents,
+            [
+                extent(0, 96 * 512, 8 * 512),
+                extent(16 * 512, 112 * 512, 8 * 512)
+            ]
+        );
+
+        let unwritten = XFS_BMAP.replace("8 100000", "8 010000");
+        assert!(unsupported(import_extents(
+            ImportFormat::XfsBmap,
+            &unwritten,
+            Some(12000)
+        )));
+        let delalloc = XFS_BMAP.replace("112..119          0 (112..119)", "delalloc");
+        assert!(unsupported(import_extents(
+            ImportFormat::XfsBmap,
+            &delalloc,
+            Some(12000)
+        )));
+
+        for (from, to) in [
+            (
+                "   1: [8..15]:         hole                                     8\n",
+                "",
+            ),
+            (" TOTAL FLAGS", " TOTAL"),
+            ("  BLOCK-RANGE", "  RT-BLOCK-RANGE"),
+            ("            8 000000", "            9 000000"),
+            (" EXT:", " XT:"),
+        ] {
+            assert!(import_extents(
+                ImportFormat::XfsBmap,
+                &XFS_BMAP.replace(from, to),
+                Some(12000)
+            )
+            .is_err());
+        }
+        Ok(())
+    }
+}
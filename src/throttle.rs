@@ -0,0 +1,379 @@
+use std::{
+    fs,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use clap::Args;
+use indicatif::{HumanBytes, HumanCount};
+use log::{info, warn};
+
+use crate::{utils::parse_size, ResultType};
+
+/// How often the control file is checked for changes, and the longest
+/// single sleep, so that changes take effect promptly.
+const CONTROL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Unused allowance accumulates for at most this long, permitting bursts.
+const BURST_SECONDS: f64 = 0.5;
+
+/// A per second limit for reads and writes.
+///
+/// Written as one value for both, or `<read>/<write>`.  Each is a number
+/// with an optional K, M or G suffix, or `none` for no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limit {
+    /// Limit for reads, if any.
+    pub read: Option<u64>,
+    /// Limit for writes, if any.
+    pub write: Option<u64>,
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let one = |s: &str| match s.trim() {
+            "none" => Ok(None),
+            value => match parse_size(value) {
+                Some(n) if n > 0 => Ok(Some(n)),
+                _ => Err(format!("'{}' is not a positive limit or 'none'", s)),
+            },
+        };
+        match s.split_once('/') {
+            Some((read, write)) => Ok(Limit {
+                read: one(read)?,
+                write: one(write)?,
+            }),
+            None => {
+                let limit = one(s)?;
+                Ok(Limit {
+                    read: limit,
+                    write: limit,
+                })
+            }
+        }
+    }
+}
+
+/// Command line options for throttling IO.
+#[derive(Args, Debug)]
+pub struct ThrottleArgs {
+    /// Maximum bytes per second, for reads and writes separately.
+    ///
+    /// One value for both, or `<read>/<write>`, accepting K, M and G
+    /// suffixes, or `none`.
+    #[clap(long)]
+    max_bandwidth: Option<Limit>,
+
+    /// Maximum IO operations per second, for reads and writes separately.
+    ///
+    /// One value for both, or `<read>/<write>`, or `none`.
+    #[clap(long)]
+    max_iops: Option<Limit>,
+
+    /// Control file for changing the limits while running, checked every second.
+    ///
+    /// Lines of the form `max-bandwidth = 50M/20M` or `max-iops = 500`
+    /// override the command line.  Limits not mentioned, or a missing file,
+    /// revert to the command line.
+    #[clap(long)]
+    throttle_file: Option<PathBuf>,
+}
+
+impl ThrottleArgs {
+    /// Creates the throttle, or `None` if no limits were asked for.
+    pub fn build(&self) -> ResultType<Option<Arc<Throttle>>> {
+        if self.max_bandwidth.is_none() && self.max_iops.is_none() && self.throttle_file.is_none() {
+            return Ok(None);
+        }
+        let throttle = Throttle::new(
+            self.max_bandwidth.unwrap_or_default(),
+            self.max_iops.unwrap_or_default(),
+            self.throttle_file.clone(),
+        );
+        Ok(Some(Arc::new(throttle)))
+    }
+}
+
+/// A token bucket, where running short of tokens yields a delay.
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: 0.0,
+            updated: now,
+        }
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>, now: Instant) {
+        if rate != self.rate {
+            *self = Self::new(rate, now);
+        }
+    }
+
+    /// Takes `amount` tokens, returning how long to wait to keep within the rate.
+    fn take(&mut self, amount: u64, now: Instant) -> Duration {
+        let Some(rate) = self.rate else {
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = f64::min(self.tokens + elapsed * rate, rate * BURST_SECONDS);
+        self.updated = now;
+        self.tokens -= amount as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    /// Bandwidth and IOPS limits from the command line.
+    base: (Limit, Limit),
+    control_file: Option<PathBuf>,
+    control_checked: Option<Instant>,
+    control_modified: Option<SystemTime>,
+    /// Incremented whenever the limits change, to cut short any waits.
+    generation: u64,
+    read_bytes: Bucket,
+    read_ops: Bucket,
+    write_bytes: Bucket,
+    write_ops: Bucket,
+}
+
+impl ThrottleState {
+    fn apply(&mut self, bandwidth: Limit, iops: Limit, now: Instant) {
+        info!(
+            "Throttling reads to {} and {}, writes to {} and {}.",
+            describe(bandwidth.read, true),
+            describe(iops.read, false),
+            describe(bandwidth.write, true),
+            describe(iops.write, false)
+        );
+        self.read_bytes.set_rate(bandwidth.read, now);
+        self.read_ops.set_rate(iops.read, now);
+        self.write_bytes.set_rate(bandwidth.write, now);
+        self.write_ops.set_rate(iops.write, now);
+        self.generation += 1;
+    }
+
+    /// Applies the control file if it has changed since last time.
+    fn check_control_file(&mut self, now: Instant) {
+        let Some(path) = &self.control_file else {
+            return;
+        };
+        if self
+            .control_checked
+            .is_some_and(|checked| now.saturating_duration_since(checked) < CONTROL_INTERVAL)
+        {
+            return;
+        }
+        self.control_checked = Some(now);
+
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified == self.control_modified {
+            return;
+        }
+        self.control_modified = modified;
+
+        let (mut bandwidth, mut iops) = self.base;
+        if modified.is_some() {
+            match fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| parse_control_file(&text))
+            {
+                Ok((b, i)) => {
+                    bandwidth = b.unwrap_or(bandwidth);
+                    iops = i.unwrap_or(iops);
+                }
+                Err(e) => {
+                    warn!("Ignoring throttle file {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+        self.apply(bandwidth, iops, now);
+    }
+}
+
+fn describe(limit: Option<u64>, bytes: bool) -> String {
+    match (limit, bytes) {
+        (None, true) => "unlimited bandwidth".to_string(),
+        (None, false) => "unlimited IOPS".to_string(),
+        (Some(n), true) => format!("{}/s", HumanBytes(n)),
+        (Some(n), false) => format!("{} IOPS", HumanCount(n)),
+    }
+}
+
+/// Parses the contents of a throttle control file, giving the bandwidth
+/// and IOPS limits it sets, if any.
+fn parse_control_file(text: &str) -> Result<(Option<Limit>, Option<Limit>), String> {
+    let mut bandwidth = None;
+    let mut iops = None;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' is not of the form key = value", line))?;
+        match key.trim() {
+            "max-bandwidth" => bandwidth = Some(value.parse()?),
+            "max-iops" => iops = Some(value.parse()?),
+            key => return Err(format!("unknown setting '{}'", key)),
+        }
+    }
+    Ok((bandwidth, iops))
+}
+
+/// Limits the rate of reads and writes, shared by everything doing IO.
+#[derive(Debug)]
+pub struct Throttle {
+    state: Mutex<ThrottleState>,
+}
+
+impl Throttle {
+    /// Limits bandwidth and IOPS, which `control_file` may later override.
+    pub fn new(bandwidth: Limit, iops: Limit, control_file: Option<PathBuf>) -> Self {
+        let now = Instant::now();
+        let mut state = ThrottleState {
+            base: (bandwidth, iops),
+            control_file,
+            control_checked: None,
+            control_modified: None,
+            generation: 0,
+            read_bytes: Bucket::new(None, now),
+            read_ops: Bucket::new(None, now),
+            write_bytes: Bucket::new(None, now),
+            write_ops: Bucket::new(None, now),
+        };
+        state.apply(bandwidth, iops, now);
+        state.check_control_file(now);
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Accounts for reads, sleeping if they exceed the limits.
+    pub fn read(&self, ops: u64, bytes: u64) {
+        self.wait(|state, now| {
+            Duration::max(
+                state.read_ops.take(ops, now),
+                state.read_bytes.take(bytes, now),
+            )
+        })
+    }
+
+    /// Accounts for writes, sleeping if they exceed the limits.
+    pub fn write(&self, ops: u64, bytes: u64) {
+        self.wait(|state, now| {
+            Duration::max(
+                state.write_ops.take(ops, now),
+                state.write_bytes.take(bytes, now),
+            )
+        })
+    }
+
+    fn wait(&self, take: impl FnOnce(&mut ThrottleState, Instant) -> Duration) {
+        let (deadline, generation) = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            state.check_control_file(now);
+            (now + take(&mut state, now), state.generation)
+        };
+
+        // Sleep in steps, so that new limits take effect during long waits.
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            thread::sleep(Duration::min(deadline - now, CONTROL_INTERVAL));
+
+            let mut state = self.state.lock().unwrap();
+            state.check_control_file(Instant::now());
+            if state.generation != generation {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::tests::init_logger;
+
+    use super::{parse_control_file, Bucket, Limit};
+
+    #[test]
+    fn limits() {
+        init_logger();
+        assert_eq!(
+            "10M".parse(),
+            Ok(Limit {
+                read: Some(10 << 20),
+                write: Some(10 << 20)
+            })
+        );
+        assert_eq!(
+            "none/500".parse(),
+            Ok(Limit {
+                read: None,
+                write: Some(500)
+            })
+        );
+        assert!("0".parse::<Limit>().is_err());
+        assert!("1/2/3".parse::<Limit>().is_err());
+
+        let (bandwidth, iops) =
+            parse_control_file("# Business hours\nmax-bandwidth = 50M/20M\n\n").unwrap();
+        assert_eq!(
+            bandwidth,
+            Some(Limit {
+                read: Some(50 << 20),
+                write: Some(20 << 20)
+            })
+        );
+        assert_eq!(iops, None);
+        assert!(parse_control_file("max-speed = 1").is_err());
+        assert!(parse_control_file("max-iops").is_err());
+    }
+
+    #[test]
+    fn bucket() {
+        init_logger();
+        let start = Instant::now();
+        let mut bucket = Bucket::new(Some(1000), start);
+
+        // Taking more than is available asks for a wait until it would be.
+        assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+        assert_eq!(
+            bucket.take(500, start + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+
+        // Idle time builds up a limited allowance.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take(500, later), Duration::ZERO);
+        assert_eq!(bucket.take(250, later), Duration::from_millis(250));
+
+        let mut unlimited = Bucket::new(None, start);
+        assert_eq!(unlimited.take(u64::MAX, start), Duration::ZERO);
+    }
+}
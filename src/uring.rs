@@ -0,0 +1,362 @@
+use std::{collections::VecDeque, io, ops::Range, os::fd::RawFd};
+
+use io_uring::{opcode, types, IoUring};
+use log::debug;
+
+use crate::utils::AlignedBuffer;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Waiting for earlier operations it depends on.
+    Blocked,
+    InFlight,
+    Done,
+}
+
+#[derive(Debug)]
+struct Op {
+    kind: Kind,
+    fd: RawFd,
+    range: Range<u64>,
+    buffer: usize,
+    /// Bytes already transferred, after a short read or write.
+    transferred: u64,
+    /// For writes, the sequence number of the read which fills the buffer.
+    after: Option<u64>,
+    state: State,
+}
+
+impl Op {
+    /// True if the two operations must not be in flight together, or reordered.
+    fn conflicts(&self, other: &Op) -> bool {
+        (self.kind == Kind::Write || other.kind == Kind::Write)
+            && self.fd == other.fd
+            && self.range.start < other.range.end
+            && other.range.start < self.range.end
+    }
+}
+
+/// Keeps several chunk copies and swaps in flight through io_uring.
+///
+/// Operations are queued in program order, and each read or write is only
+/// issued once no earlier, still incomplete, operation touches the same
+/// bytes in a conflicting way.  Reads wait for earlier overlapping writes,
+/// and writes wait for earlier overlapping reads and writes.  The end
+/// result is therefore the same as performing everything synchronously in
+/// order.
+pub(crate) struct Pipeline {
+    ring: IoUring,
+    buffers: Vec<AlignedBuffer>,
+    free: Vec<usize>,
+    /// Number of queued operations using each buffer.
+    users: Vec<u32>,
+    ops: VecDeque<Op>,
+    /// Sequence number of `ops[0]`, used as the io_uring user data.
+    first_seq: u64,
+    in_flight: usize,
+    error: Option<io::Error>,
+}
+
+impl Pipeline {
+    pub fn new(buffers: Vec<AlignedBuffer>) -> io::Result<Self> {
+        let entries = u32::try_from(2 * buffers.len())
+            .unwrap()
+            .next_power_of_two();
+        Ok(Self {
+            ring: IoUring::new(entries)?,
+            free: (0..buffers.len()).rev().collect(),
+            users: vec![0; buffers.len()],
+            buffers,
+            ops: VecDeque::new(),
+            first_seq: 0,
+            in_flight: 0,
+            error: None,
+        })
+    }
+
+    /// Queues a copy of `length` bytes, which must fit in one buffer.
+    ///
+    /// Only the read is performed unless `write` is set.
+    pub fn copy(
+        &mut self,
+        fd: RawFd,
+        source: u64,
+        destination: u64,
+        length: u64,
+        write: bool,
+    ) -> io::Result<()> {
+        let buffer = self.acquire_buffers(1)?[0];
+        let read = self.push(Kind::Read, fd, source..(source + length), buffer, None);
+        if write {
+            self.push(
+                Kind::Write,
+                fd,
+                destination..(destination + length),
+                buffer,
+                Some(read),
+            );
+        }
+        self.pump(0)
+    }
+
+    /// Queues an exchange of `length` bytes between `a` and `b`, which must fit in one buffer.
+    ///
+    /// Only the reads are performed unless `write` is set.
+    pub fn swap(&mut self, fd: RawFd, a: u64, b: u64, length: u64, write: bool) -> io::Result<()> {
+        let buffers = self.acquire_buffers(2)?;
+        let read_a = self.push(Kind::Read, fd, a..(a + length), buffers[0], None);
+        let read_b = self.push(Kind::Read, fd, b..(b + length), buffers[1], None);
+        if write {
+            self.push(Kind::Write, fd, b..(b + length), buffers[0], Some(read_a));
+            self.push(Kind::Write, fd, a..(a + length), buffers[1], Some(read_b));
+        }
+        self.pump(0)
+    }
+
+    /// Waits for every queued operation to complete.
+    pub fn drain(&mut self) -> io::Result<()> {
+        while !self.ops.is_empty() {
+            self.pump(1)?;
+        }
+        Ok(())
+    }
+
+    fn acquire_buffers(&mut self, count: usize) -> io::Result<Vec<usize>> {
+        while self.free.len() < count {
+            self.pump(1)?;
+        }
+        Ok(self.free.split_off(self.free.len() - count))
+    }
+
+    fn push(
+        &mut self,
+        kind: Kind,
+        fd: RawFd,
+        range: Range<u64>,
+        buffer: usize,
+        after: Option<u64>,
+    ) -> u64 {
+        self.users[buffer] += 1;
+        self.ops.push_back(Op {
+            kind,
+            fd,
+            range,
+            buffer,
+            transferred: 0,
+            after,
+            state: State::Blocked,
+        });
+        self.first_seq + u64::try_from(self.ops.len()).unwrap() - 1
+    }
+
+    /// True if the blocked operation at `index` may now be issued.
+    fn ready(&self, index: usize) -> bool {
+        let op = &self.ops[index];
+        if let Some(after) = op.after {
+            if after >= self.first_seq
+                && self.ops[usize::try_from(after - self.first_seq).unwrap()].state != State::Done
+            {
+                return false;
+            }
+        }
+        self.ops
+            .range(0..index)
+            .all(|earlier| earlier.state == State::Done || !earlier.conflicts(op))
+    }
+
+    /// Issues every ready operation.
+    fn issue(&mut self) -> io::Result<()> {
+        for index in 0..self.ops.len() {
+            if self.ops[index].state != State::Blocked || !self.ready(index) {
+                continue;
+            }
+            let op = &self.ops[index];
+            let offset = op.range.start + op.transferred;
+            let length = u32::try_from(op.range.end - offset).unwrap();
+            let buf =
+                self.buffers[op.buffer][usize::try_from(op.transferred).unwrap()..].as_mut_ptr();
+            let user_data = self.first_seq + u64::try_from(index).unwrap();
+            let entry = match op.kind {
+                Kind::Read => opcode::Read::new(types::Fd(op.fd), buf, length)
+                    .offset(offset)
+                    .build(),
+                Kind::Write => opcode::Write::new(types::Fd(op.fd), buf, length)
+                    .offset(offset)
+                    .build(),
+            }
+            .user_data(user_data);
+
+            if self.ring.submission().is_full() {
+                self.ring.submit()?;
+            }
+            // Safety: the buffer stays allocated, and untouched by us, until
+            // the completion is reaped (`Drop` waits for stragglers).
+            unsafe { self.ring.submission().push(&entry) }
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            self.ops[index].state = State::InFlight;
+            self.in_flight += 1;
+        }
+        Ok(())
+    }
+
+    /// Issues what it can, then reaps completions, waiting for at least `wait`.
+    fn pump(&mut self, wait: usize) -> io::Result<()> {
+        if self.error.is_none() {
+            if let Err(e) = self.issue() {
+                self.error = Some(e);
+            }
+        }
+        if self.error.is_some() {
+            return self.abort();
+        }
+        self.ring
+            .submit_and_wait(usize::min(wait, self.in_flight))?;
+        self.reap();
+        if self.error.is_some() {
+            return self.abort();
+        }
+        Ok(())
+    }
+
+    fn reap(&mut self) {
+        let completions: Vec<(u64, i32)> = self
+            .ring
+            .completion()
+            .map(|c| (c.user_data(), c.result()))
+            .collect();
+        for (user_data, result) in completions {
+            self.in_flight -= 1;
+            let index = usize::try_from(user_data - self.first_seq).unwrap();
+            let op = &mut self.ops[index];
+            if result < 0 {
+                self.error
+                    .get_or_insert(io::Error::from_raw_os_error(-result));
+            } else if result == 0 {
+                self.error.get_or_insert(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("{:?} of {:?} made no progress", op.kind, op.range),
+                ));
+            } else {
+                op.transferred += u64::try_from(result).unwrap();
+                if op.range.start + op.transferred < op.range.end {
+                    debug!("Short {:?} of {:?}, reissuing.", op.kind, op.range);
+                    op.state = State::Blocked;
+                    continue;
+                }
+            }
+            op.state = State::Done;
+            self.users[op.buffer] -= 1;
+            if self.users[op.buffer] == 0 {
+                self.free.push(op.buffer);
+            }
+        }
+        while self.ops.front().is_some_and(|op| op.state == State::Done) {
+            self.ops.pop_front();
+            self.first_seq += 1;
+        }
+    }
+
+    /// Abandons everything after a failure, once nothing is in flight.
+    fn abort(&mut self) -> io::Result<()> {
+        self.wait_in_flight();
+        self.ops.clear();
+        self.users.fill(0);
+        self.free = (0..self.buffers.len()).rev().collect();
+        Err(self.error.take().unwrap())
+    }
+
+    fn wait_in_flight(&mut self) {
+        while self.in_flight > 0 {
+            if self.ring.submit_and_wait(1).is_err() {
+                // Nothing sensible to do, and the buffers must not be freed.
+                std::process::abort();
+            }
+            self.reap();
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.wait_in_flight();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::{fd::AsRawFd, unix::fs::FileExt};
+
+    use crate::{
+        tests::{init_logger, scratch_file, Lcg},
+        utils::AlignedBuffer,
+        ResultType,
+    };
+
+    use super::Pipeline;
+
+    const CHUNK: u64 = 4096;
+
+    #[test]
+    fn matches_synchronous_order() -> ResultType<()> {
+        init_logger();
+
+        let buffers = (0..4).map(|_| AlignedBuffer::new(4096, 4096)).collect();
+        let mut pipeline = match Pipeline::new(buffers) {
+            Ok(p) => p,
+            Err(e) => {
+                log::warn!("Skipping, io_uring is unavailable: {}", e);
+                return Ok(());
+            }
+        };
+
+        let f = scratch_file("uring")?;
+
+        let mut model: Vec<u8> = (0..(64 * CHUNK)).map(|i| (i / 512) as u8).collect();
+        f.write_all_at(&model, 0)?;
+
+        // Chains of operations which read what earlier ones wrote.
+        let mut rng = Lcg::new(12345);
+        let mut random = |n: u64| rng.below(n);
+        for _ in 0..2000 {
+            let a = random(63 * 8) * 512;
+            let b = random(63 * 8) * 512;
+            let length = 512 * (1 + random(8));
+            let (a_range, b_range) = (
+                usize::try_from(a).unwrap()..usize::try_from(a + length).unwrap(),
+                usize::try_from(b).unwrap()..usize::try_from(b + length).unwrap(),
+            );
+            if random(2) == 0 {
+                pipeline.copy(f.as_raw_fd(), a, b, length, true)?;
+                model.copy_within(a_range, b_range.start);
+            } else {
+                pipeline.swap(f.as_raw_fd(), a, b, length, true)?;
+                let (data_a, data_b) = (
+                    model[a_range.clone()].to_vec(),
+                    model[b_range.clone()].to_vec(),
+                );
+                model[b_range].copy_from_slice(&data_a);
+                model[a_range].copy_from_slice(&data_b);
+            }
+        }
+        pipeline.drain()?;
+
+        let mut result = vec![0u8; model.len()];
+        f.read_exact_at(&mut result, 0)?;
+        assert!(result == model);
+
+        // Failures are reported, and leave the pipeline usable.
+        assert!(pipeline
+            .copy(f.as_raw_fd(), 64 * CHUNK, 0, CHUNK, true)
+            .and_then(|_| pipeline.drain())
+            .is_err());
+        pipeline.copy(f.as_raw_fd(), 0, CHUNK, CHUNK, true)?;
+        pipeline.drain()?;
+        Ok(())
+    }
+}
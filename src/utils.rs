@@ -1,105 +1,344 @@
 use std::{
-    fs::File,
+    alloc::{self, Layout},
+    collections::BTreeMap,
     hash::{DefaultHasher, Hash, Hasher},
-    ops::Range,
-    os::unix::fs::FileExt,
+    ops::{Deref, DerefMut, Range},
+    ptr::NonNull,
+    str::FromStr,
+    sync::Arc,
 };
 
-use indicatif::{HumanBytes, HumanCount, ProgressBar};
-use log::info;
+use indicatif::{HumanBytes, HumanCount};
+use log::{info, warn};
+use serde::Serialize;
 
-use crate::ResultType;
+#[cfg(feature = "io-uring")]
+use crate::uring;
+use crate::{
+    blkdev,
+    device::BlockDevice,
+    error::{IoContext, LoopliftError},
+    throttle::Throttle,
+    ResultType,
+};
+
+/// Length of each read and write, unless chosen otherwise with `set_io_size`.
+const DEFAULT_IO_SIZE: usize = 128 * 1024;
+
+/// Largest permitted IO size, per buffer.
+const MAX_IO_SIZE: usize = 64 * 1024 * 1024;
+
+/// Data is checksummed in pieces of this size.  This is part of the report
+/// format, changing it would invalidate existing reports.
+const CHECKSUM_CHUNK_LENGTH: usize = 128 * 1024;
+
+/// Default alignment of IO buffers, sufficient for most devices.
+const DEFAULT_BUFFER_ALIGNMENT: usize = 4096;
+
+/// Number of buffers, and so chunks in flight, when using io_uring.
+#[cfg(feature = "io-uring")]
+const URING_BUFFERS: usize = 16;
+
+/// A zero initialised heap buffer with a chosen alignment, as O_DIRECT requires.
+pub(crate) struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+impl AlignedBuffer {
+    pub fn new(length: usize, alignment: usize) -> Self {
+        let layout = Layout::from_size_align(length, alignment).unwrap();
+        assert!(layout.size() > 0);
+        let ptr = NonNull::new(unsafe { alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, layout }
+    }
+
+    pub fn alignment(&self) -> usize {
+        self.layout.align()
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()) }
+    }
+}
 
-const BUFFER_LENGTH: usize = 128 * 1024;
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
 
-/// Creates a buffer, no longer than `max_len`.
-fn make_buffer() -> Vec<u8> {
-    let mut result = Vec::<u8>::new();
-    result.resize_with(BUFFER_LENGTH, Default::default);
-    result
+// The buffer is uniquely owned, like a `Vec<u8>`.
+unsafe impl Send for AlignedBuffer {}
+
+/// Creates an IO buffer.
+fn make_buffer(length: usize, alignment: usize) -> AlignedBuffer {
+    AlignedBuffer::new(length, alignment)
 }
 
 /// Structure to own some IO buffers and provide IO operations.
 pub(crate) struct FileOps {
     dry_run: bool,
-    buf_a: Vec<u8>,
-    buf_b: Vec<u8>,
+    buf_a: AlignedBuffer,
+    buf_b: AlignedBuffer,
+    /// Length of each read and write, and of the buffers.
+    io_size: usize,
+    /// When non-zero, O_DIRECT is in use and all IO must be aligned to this.
+    direct_io_alignment: u64,
     read_ops: u64,
     read_bytes: u64,
     write_ops: u64,
     write_bytes: u64,
+    zero_strategy: ZeroStrategy,
+    /// Whether to fall back to writing zeros if `zero_strategy` turns out to be unsupported.
+    zero_strategy_fallback: bool,
+    /// Operations and bytes zeroed, by strategy.
+    zeroed: BTreeMap<ZeroStrategy, (u64, u64)>,
+    check_zeros: bool,
+    zero_check_skipped_bytes: u64,
+    discard_ops: u64,
+    discard_bytes: u64,
+    /// Copies and swaps only track statistics, performing no IO at all.
+    simulate: bool,
+    /// Offset following the last copy or swap IO.
+    head: Option<u64>,
+    seeks: u64,
+    seek_distance: u64,
+    /// Shared with any forks.
+    throttle: Option<Arc<Throttle>>,
+    #[cfg(feature = "io-uring")]
+    uring: Option<uring::Pipeline>,
 }
 
 impl FileOps {
     pub fn new(dry_run: bool) -> Self {
         Self {
             dry_run,
-            buf_a: make_buffer(),
-            buf_b: make_buffer(),
+            buf_a: make_buffer(DEFAULT_IO_SIZE, DEFAULT_BUFFER_ALIGNMENT),
+            buf_b: make_buffer(DEFAULT_IO_SIZE, DEFAULT_BUFFER_ALIGNMENT),
+            io_size: DEFAULT_IO_SIZE,
+            direct_io_alignment: 0,
             read_ops: 0,
             read_bytes: 0,
             write_ops: 0,
             write_bytes: 0,
+            zero_strategy: ZeroStrategy::Write,
+            zero_strategy_fallback: false,
+            zeroed: BTreeMap::new(),
+            check_zeros: false,
+            zero_check_skipped_bytes: 0,
+            discard_ops: 0,
+            discard_bytes: 0,
+            simulate: false,
+            head: None,
+            seeks: 0,
+            seek_distance: 0,
+            throttle: None,
+            #[cfg(feature = "io-uring")]
+            uring: None,
+        }
+    }
+
+    /// Creates a `FileOps` whose copies and swaps perform no IO, but count
+    /// the reads, writes and seeks that they would have made.
+    pub fn new_simulation() -> Self {
+        Self {
+            simulate: true,
+            ..Self::new(false)
         }
     }
 
     pub fn check_equality_and_compute_checksum(
         &mut self,
-        a: &File,
+        a: &impl BlockDevice,
         a_offset: u64,
-        b: &File,
+        b: &impl BlockDevice,
         b_offset: u64,
         length: u64,
     ) -> ResultType<u64> {
-        let mut hasher_a = DefaultHasher::new();
-        let mut hasher_b = DefaultHasher::new();
+        let mut csum = Checksum::new();
 
         let mut read = 0u64;
         while read < length {
-            let chunk_len = u64::min(BUFFER_LENGTH.try_into().unwrap(), length - read);
+            let chunk_len = u64::min(self.chunk_length(), length - read);
             let a_chunk = &mut self.buf_a[0..chunk_len.try_into().unwrap()];
             let b_chunk = &mut self.buf_b[0..chunk_len.try_into().unwrap()];
-            a.read_exact_at(a_chunk, a_offset + read)?;
-            b.read_exact_at(b_chunk, b_offset + read)?;
+            a.read_at(a_chunk, a_offset + read).at(a_offset + read)?;
+            b.read_at(b_chunk, b_offset + read).at(b_offset + read)?;
 
-            assert_eq!(a_chunk, b_chunk);
+            if a_chunk != b_chunk {
+                return Err(LoopliftError::DataMismatch {
+                    offset: b_offset + read,
+                    length: chunk_len,
+                });
+            }
 
-            a_chunk.hash(&mut hasher_a);
-            b_chunk.hash(&mut hasher_b);
+            csum.update(a_chunk);
 
             read += chunk_len;
 
-            self.read_ops += 2;
-            self.read_bytes += 2 * chunk_len;
+            self.count_reads(2, 2 * chunk_len);
+        }
+
+        Ok(csum.finish())
+    }
+
+    /// True if all of `range` of `f` reads as zeros.  Holes are skipped
+    /// rather than read, where `f` knows of them.
+    pub fn reads_as_zeros(&mut self, f: &impl BlockDevice, range: &Range<u64>) -> ResultType<bool> {
+        let mut offset = range.start;
+        while offset < range.end {
+            let Some(data) = f.next_data(offset).at(offset)? else {
+                break;
+            };
+            offset = u64::max(offset, data.start);
+            let data_end = u64::min(data.end, range.end);
+            while offset < data_end {
+                let chunk_len = u64::min(self.chunk_length(), data_end - offset);
+                self.count_reads(1, chunk_len);
+                let chunk = &mut self.buf_a[0..chunk_len.try_into().unwrap()];
+                f.read_at(chunk, offset).at(offset)?;
+                if !is_zero(chunk) {
+                    return Ok(false);
+                }
+                offset += chunk_len;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Like `check_equality_and_compute_checksum`, but also looks for runs of
+    /// zeros at least `zero_threshold` long (and aligned to `ZERO_BLOCK_LENGTH`
+    /// in `a`), splitting them out of the data.
+    ///
+    /// Returns the pieces of the range in order.  A threshold of zero
+    /// disables the search.
+    pub fn check_equality_and_split_zeros(
+        &mut self,
+        a: &impl BlockDevice,
+        a_offset: u64,
+        b: &impl BlockDevice,
+        b_offset: u64,
+        length: u64,
+        zero_threshold: u64,
+    ) -> ResultType<Vec<ScannedPiece>> {
+        if zero_threshold == 0 {
+            let checksum =
+                self.check_equality_and_compute_checksum(a, a_offset, b, b_offset, length)?;
+            return Ok(vec![ScannedPiece::Data { length, checksum }]);
+        }
+
+        let mut result = Vec::new();
+        let mut data_length = 0u64;
+        let mut data_csum = Checksum::new();
+        let mut zeros_length = 0u64;
+
+        let mut read = 0u64;
+        while read < length {
+            let chunk_len = u64::min(self.chunk_length(), length - read);
+            self.count_reads(2, 2 * chunk_len);
+            let a_chunk = &mut self.buf_a[0..chunk_len.try_into().unwrap()];
+            let b_chunk = &mut self.buf_b[0..chunk_len.try_into().unwrap()];
+            a.read_at(a_chunk, a_offset + read).at(a_offset + read)?;
+            b.read_at(b_chunk, b_offset + read).at(b_offset + read)?;
+
+            if a_chunk != b_chunk {
+                return Err(LoopliftError::DataMismatch {
+                    offset: b_offset + read,
+                    length: chunk_len,
+                });
+            }
+
+            let mut pos = 0u64;
+            while pos < chunk_len {
+                let block_start = a_offset + read + pos;
+                let block_end = u64::min(
+                    (block_start / ZERO_BLOCK_LENGTH + 1) * ZERO_BLOCK_LENGTH,
+                    a_offset + read + chunk_len,
+                );
+                let block_len = block_end - block_start;
+                let block =
+                    &a_chunk[pos.try_into().unwrap()..(pos + block_len).try_into().unwrap()];
+
+                if block_len == ZERO_BLOCK_LENGTH && is_zero(block) {
+                    zeros_length += block_len;
+                } else {
+                    if zeros_length >= zero_threshold {
+                        if data_length > 0 {
+                            result.push(ScannedPiece::Data {
+                                length: data_length,
+                                checksum: std::mem::replace(&mut data_csum, Checksum::new())
+                                    .finish(),
+                            });
+                            data_length = 0;
+                        }
+                        result.push(ScannedPiece::Zeros {
+                            length: zeros_length,
+                        });
+                    } else {
+                        // Too short to be worth splitting out, keep it as data.
+                        data_csum.update_zeros(zeros_length);
+                        data_length += zeros_length;
+                    }
+                    zeros_length = 0;
+
+                    data_csum.update(block);
+                    data_length += block_len;
+                }
+                pos += block_len;
+            }
+
+            read += chunk_len;
         }
 
-        let hash_a = hasher_a.finish();
-        let hash_b = hasher_b.finish();
-        assert_eq!(hash_a, hash_b);
+        if zeros_length >= zero_threshold {
+            if data_length > 0 {
+                result.push(ScannedPiece::Data {
+                    length: data_length,
+                    checksum: data_csum.finish(),
+                });
+            }
+            result.push(ScannedPiece::Zeros {
+                length: zeros_length,
+            });
+        } else {
+            data_csum.update_zeros(zeros_length);
+            data_length += zeros_length;
+            result.push(ScannedPiece::Data {
+                length: data_length,
+                checksum: data_csum.finish(),
+            });
+        }
 
-        Ok(hash_a)
+        Ok(result)
     }
 
     pub fn copy_segment(
         &mut self,
-        f: &File,
+        f: &impl BlockDevice,
         source: &Range<u64>,
         dest_offset: u64,
     ) -> ResultType<()> {
         let length = source.end - source.start;
         let mut read = 0u64;
         while read < length {
-            let chunk_len = u64::min(BUFFER_LENGTH.try_into().unwrap(), length - read);
-            let chunk = &mut self.buf_a[0..chunk_len.try_into().unwrap()];
-
-            f.read_exact_at(chunk, source.start + read)?;
-            self.read_ops += 1;
-            self.read_bytes += chunk_len;
+            let chunk_len = u64::min(self.chunk_length(), length - read);
+            self.copy_chunk(f, source.start + read, dest_offset + read, chunk_len)?;
+            self.count_reads(1, chunk_len);
 
             if !self.dry_run {
-                f.write_all_at(chunk, dest_offset + read)?;
-                self.write_ops += 1;
-                self.write_bytes += chunk_len;
+                self.count_writes(1, chunk_len);
             }
 
             read += chunk_len;
@@ -107,29 +346,49 @@ impl FileOps {
         Ok(())
     }
 
+    fn copy_chunk(
+        &mut self,
+        f: &impl BlockDevice,
+        source: u64,
+        dest_offset: u64,
+        length: u64,
+    ) -> ResultType<()> {
+        self.track_seek(source, length);
+        if !self.dry_run {
+            self.track_seek(dest_offset, length);
+        }
+        if self.simulate {
+            return Ok(());
+        }
+
+        #[cfg(feature = "io-uring")]
+        if let (Some(uring), Some(fd)) = (&mut self.uring, f.raw_fd()) {
+            return Ok(uring.copy(fd, source, dest_offset, length, !self.dry_run)?);
+        }
+
+        let chunk = &mut self.buf_a[0..length.try_into().unwrap()];
+        f.read_at(chunk, source).at(source)?;
+        if !self.dry_run {
+            f.write_at(chunk, dest_offset).at(dest_offset)?;
+        }
+        Ok(())
+    }
+
     pub fn swap_segment(
         &mut self,
-        f: &File,
+        f: &impl BlockDevice,
         source: &Range<u64>,
         dest_offset: u64,
     ) -> ResultType<()> {
         let length = source.end - source.start;
         let mut read = 0u64;
         while read < length {
-            let chunk_len = u64::min(BUFFER_LENGTH.try_into().unwrap(), length - read);
-            let chunk_a = &mut self.buf_a[0..chunk_len.try_into().unwrap()];
-            let chunk_b = &mut self.buf_b[0..chunk_len.try_into().unwrap()];
-
-            f.read_exact_at(chunk_a, source.start + read)?;
-            f.read_exact_at(chunk_b, dest_offset + read)?;
-            self.read_ops += 2;
-            self.read_bytes += 2 * chunk_len;
+            let chunk_len = u64::min(self.chunk_length(), length - read);
+            self.swap_chunk(f, source.start + read, dest_offset + read, chunk_len)?;
+            self.count_reads(2, 2 * chunk_len);
 
             if !self.dry_run {
-                f.write_all_at(chunk_a, dest_offset + read)?;
-                f.write_all_at(chunk_b, source.start + read)?;
-                self.write_ops += 2;
-                self.write_bytes += 2 * chunk_len;
+                self.count_writes(2, 2 * chunk_len);
             }
 
             read += chunk_len;
@@ -137,55 +396,473 @@ impl FileOps {
         Ok(())
     }
 
-    pub fn fill_zeros(&mut self, f: &File, range: &Range<u64>) -> ResultType<()> {
+    fn swap_chunk(
+        &mut self,
+        f: &impl BlockDevice,
+        source: u64,
+        dest_offset: u64,
+        length: u64,
+    ) -> ResultType<()> {
+        self.track_seek(source, length);
+        self.track_seek(dest_offset, length);
+        if !self.dry_run {
+            self.track_seek(dest_offset, length);
+            self.track_seek(source, length);
+        }
+        if self.simulate {
+            return Ok(());
+        }
+
+        #[cfg(feature = "io-uring")]
+        if let (Some(uring), Some(fd)) = (&mut self.uring, f.raw_fd()) {
+            return Ok(uring.swap(fd, source, dest_offset, length, !self.dry_run)?);
+        }
+
+        let chunk_a = &mut self.buf_a[0..length.try_into().unwrap()];
+        let chunk_b = &mut self.buf_b[0..length.try_into().unwrap()];
+        f.read_at(chunk_a, source).at(source)?;
+        f.read_at(chunk_b, dest_offset).at(dest_offset)?;
+        if !self.dry_run {
+            f.write_at(chunk_a, dest_offset).at(dest_offset)?;
+            f.write_at(chunk_b, source).at(source)?;
+        }
+        Ok(())
+    }
+
+    /// Creates another `FileOps` for use by a worker thread, with its own
+    /// buffers but the same settings for copying.
+    ///
+    /// Worker statistics are folded back in with `take_stats`.
+    pub fn fork(&self) -> Self {
+        let alignment = self.buf_a.alignment();
+        Self {
+            buf_a: make_buffer(self.io_size, alignment),
+            buf_b: make_buffer(self.io_size, alignment),
+            io_size: self.io_size,
+            direct_io_alignment: self.direct_io_alignment,
+            simulate: self.simulate,
+            throttle: self.throttle.clone(),
+            ..Self::new(self.dry_run)
+        }
+    }
+
+    /// Moves the read and write statistics of a worker created by `fork`
+    /// into these, leaving the worker's at zero.
+    pub fn take_stats(&mut self, other: &mut Self) {
+        self.read_ops += std::mem::take(&mut other.read_ops);
+        self.read_bytes += std::mem::take(&mut other.read_bytes);
+        self.write_ops += std::mem::take(&mut other.write_ops);
+        self.write_bytes += std::mem::take(&mut other.write_bytes);
+        self.seeks += std::mem::take(&mut other.seeks);
+        self.seek_distance += std::mem::take(&mut other.seek_distance);
+    }
+
+    /// Sets the limits on the rate of reads and writes.
+    pub fn set_throttle(&mut self, throttle: Option<Arc<Throttle>>) {
+        self.throttle = throttle;
+    }
+
+    fn count_reads(&mut self, ops: u64, bytes: u64) {
+        self.read_ops += ops;
+        self.read_bytes += bytes;
+        if let (Some(throttle), false) = (&self.throttle, self.simulate) {
+            throttle.read(ops, bytes);
+        }
+    }
+
+    fn count_writes(&mut self, ops: u64, bytes: u64) {
+        self.write_ops += ops;
+        self.write_bytes += bytes;
+        self.throttle_writes(ops, bytes);
+    }
+
+    /// Charges writes to the throttle, including zeroing and discards,
+    /// which the device may carry out as writes.
+    fn throttle_writes(&self, ops: u64, bytes: u64) {
+        if let (Some(throttle), false) = (&self.throttle, self.simulate) {
+            throttle.write(ops, bytes);
+        }
+    }
+
+    /// Records the head movement needed to reach `offset` on a rotational
+    /// disk, for copies and swaps.
+    fn track_seek(&mut self, offset: u64, length: u64) {
+        if let Some(head) = self.head {
+            if head != offset {
+                self.seeks += 1;
+                self.seek_distance += head.abs_diff(offset);
+            }
+        }
+        self.head = Some(offset + length);
+    }
+
+    /// Number of seeks and their total distance in bytes, for copies and swaps.
+    pub fn seek_stats(&self) -> (u64, u64) {
+        (self.seeks, self.seek_distance)
+    }
+
+    /// A snapshot of the statistics, for the event stream.
+    pub fn counters(&self) -> FileOpsCounters {
+        FileOpsCounters {
+            read_ops: self.read_ops,
+            read_bytes: self.read_bytes,
+            write_ops: self.write_ops,
+            write_bytes: self.write_bytes,
+            zero_ops: self.zeroed.values().map(|(ops, _)| ops).sum(),
+            zero_bytes: self.zeroed.values().map(|(_, bytes)| bytes).sum(),
+            zero_check_skipped_bytes: self.zero_check_skipped_bytes,
+            discard_ops: self.discard_ops,
+            discard_bytes: self.discard_bytes,
+            seeks: self.seeks,
+            seek_distance: self.seek_distance,
+        }
+    }
+
+    /// Switches `copy_segment` and `swap_segment` to queueing chunks on an
+    /// io_uring, keeping several in flight at once.
+    ///
+    /// Must follow `enable_direct_io`, if used.  Other operations wait for
+    /// the queue to drain first.  If io_uring is unavailable, a warning is
+    /// logged and IO remains synchronous.
+    pub fn enable_io_uring(&mut self) -> ResultType<()> {
+        #[cfg(feature = "io-uring")]
+        {
+            let buffers = (0..URING_BUFFERS)
+                .map(|_| make_buffer(self.io_size, self.buf_a.alignment()))
+                .collect();
+            match uring::Pipeline::new(buffers) {
+                Ok(pipeline) => {
+                    info!("Using io_uring with {} buffers.", URING_BUFFERS);
+                    self.uring = Some(pipeline);
+                }
+                Err(e) => warn!("io_uring is unavailable ({}), using synchronous IO.", e),
+            }
+            Ok(())
+        }
+        #[cfg(not(feature = "io-uring"))]
+        Err("looplift was built without the io-uring feature.".into())
+    }
+
+    /// Waits for any queued copies and swaps to complete.
+    pub fn drain(&mut self) -> ResultType<()> {
+        #[cfg(feature = "io-uring")]
+        if let Some(uring) = &mut self.uring {
+            uring.drain()?;
+        }
+        Ok(())
+    }
+
+    /// Sets the length of each read and write, resolving `IoSize::Auto` from
+    /// the queue limits of `f`.
+    ///
+    /// Must come before `enable_direct_io`, `enable_io_uring` and `fork`.
+    pub fn set_io_size(&mut self, f: &impl BlockDevice, io_size: IoSize) -> ResultType<()> {
+        let length = match io_size {
+            IoSize::Bytes(length) => length,
+            IoSize::Auto => match f.queue_limits() {
+                Some(limits) => {
+                    let length = auto_io_size(&limits);
+                    info!(
+                        "Automatic IO size {} for {}, optimal IO size {}, maximum request {}",
+                        HumanBytes(length),
+                        match limits.rotational {
+                            true => "rotational device",
+                            false => "non-rotational device",
+                        },
+                        HumanBytes(limits.optimal_io_size),
+                        HumanBytes(limits.max_sectors_kb * 1024)
+                    );
+                    length
+                }
+                None => {
+                    info!("Not a block device, using the default IO size.");
+                    DEFAULT_IO_SIZE.try_into().unwrap()
+                }
+            },
+        };
+        if length == 0
+            || !length.is_multiple_of(DEFAULT_BUFFER_ALIGNMENT.try_into().unwrap())
+            || length > MAX_IO_SIZE.try_into().unwrap()
+        {
+            return Err(format!(
+                "IO size {} must be a multiple of {} no larger than {}.",
+                length, DEFAULT_BUFFER_ALIGNMENT, MAX_IO_SIZE
+            )
+            .into());
+        }
+
+        self.io_size = length.try_into().unwrap();
+        let alignment = self.buf_a.alignment();
+        self.buf_a = make_buffer(self.io_size, alignment);
+        self.buf_b = make_buffer(self.io_size, alignment);
+        Ok(())
+    }
+
+    /// The IO size, as a convenient type for clamping chunks.
+    fn chunk_length(&self) -> u64 {
+        self.io_size.try_into().unwrap()
+    }
+
+    /// Prepares for IO on `f`, which has been opened with O_DIRECT.
+    ///
+    /// Buffers are reallocated to suit the device's block sizes, and
+    /// `check_direct_io_alignment` must be used to ensure that all IO will
+    /// be aligned to its logical block size.
+    pub fn enable_direct_io(&mut self, f: &impl BlockDevice) -> ResultType<()> {
+        let (logical, physical) = f.block_sizes()?;
+        let alignment = usize::try_from(u64::max(logical, physical))
+            .unwrap()
+            .max(DEFAULT_BUFFER_ALIGNMENT);
+        if !self.io_size.is_multiple_of(alignment) {
+            return Err(format!(
+                "IO size {} is not a multiple of the {} byte block size, which O_DIRECT requires.",
+                self.io_size, alignment
+            )
+            .into());
+        }
+        info!(
+            "Direct IO, logical block size {}, physical block size {}",
+            logical, physical
+        );
+        self.buf_a = make_buffer(self.io_size, alignment);
+        self.buf_b = make_buffer(self.io_size, alignment);
+        self.direct_io_alignment = logical;
+        Ok(())
+    }
+
+    /// Errors if direct IO is enabled and `offset` isn't suitably aligned for it.
+    pub fn check_direct_io_alignment(&self, offset: u64) -> ResultType<()> {
+        if self.direct_io_alignment != 0 && !offset.is_multiple_of(self.direct_io_alignment) {
+            return Err(format!(
+                "Offset {} is not aligned to the {} byte logical block size, which direct IO requires.",
+                offset, self.direct_io_alignment
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Resolves and sets the strategy used by `fill_zeros` for the device `f`.
+    pub fn select_zero_strategy(
+        &mut self,
+        f: &impl BlockDevice,
+        requested: ZeroStrategy,
+    ) -> ResultType<()> {
+        let strategy = match requested {
+            ZeroStrategy::Auto => f.auto_zero_strategy()?,
+            ZeroStrategy::Discard if !f.discard_zeroes_data() => {
+                return Err("Device does not guarantee that discarded data reads as zeros.".into());
+            }
+            s => s,
+        };
+        info!("Zeroing strategy: {:?}", strategy);
+        self.zero_strategy = strategy;
+        self.zero_strategy_fallback = requested == ZeroStrategy::Auto;
+        Ok(())
+    }
+
+    /// When enabled, `fill_zeros` first reads each range and only zeroes
+    /// chunks that are not already zero.
+    pub fn set_check_zeros(&mut self, check_zeros: bool) {
+        info!(
+            "Zero check: {}",
+            match check_zeros {
+                true => "reading ranges first, zeroing only non-zero chunks",
+                false => "zeroing ranges without reading",
+            }
+        );
+        self.check_zeros = check_zeros;
+    }
+
+    /// Checks that `discard` can be used on the device `f`.
+    ///
+    /// Discarding is only allowed where the discarded range is guaranteed to
+    /// read back as zeros: regular files (by punching holes), and block devices
+    /// supporting write-zeroes or reporting `discard_zeroes_data`.
+    pub fn enable_discard(&mut self, f: &impl BlockDevice) -> ResultType<()> {
+        if !f.discard_zeroes_data() {
+            return Err(
+                "Device does not guarantee that discarded data reads as zeros, refusing to discard."
+                    .into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Discards the whole blocks within `range`, which must already read as zeros.
+    ///
+    /// `enable_discard` must have been called first.
+    pub fn discard(&mut self, f: &impl BlockDevice, range: &Range<u64>) -> ResultType<()> {
+        self.drain()?;
+        if self.dry_run {
+            return Ok(());
+        }
+        let aligned = range.start.next_multiple_of(ZERO_ALIGNMENT)
+            ..(range.end / ZERO_ALIGNMENT * ZERO_ALIGNMENT);
+        if aligned.start >= aligned.end {
+            return Ok(());
+        }
+        f.discard(&aligned).at(aligned.start)?;
+        self.discard_ops += 1;
+        self.discard_bytes += aligned.end - aligned.start;
+        self.throttle_writes(1, aligned.end - aligned.start);
+        Ok(())
+    }
+
+    pub fn fill_zeros(&mut self, f: &impl BlockDevice, range: &Range<u64>) -> ResultType<()> {
+        self.drain()?;
+        if !self.check_zeros {
+            return self.zero_range(f, range);
+        }
+
+        let mut pending: Option<Range<u64>> = None;
+        let mut offset = range.start;
+        while offset < range.end {
+            let chunk_len = u64::min(self.chunk_length(), range.end - offset);
+            self.count_reads(1, chunk_len);
+            let chunk = &mut self.buf_a[0..chunk_len.try_into().unwrap()];
+            f.read_at(chunk, offset).at(offset)?;
+
+            if is_zero(chunk) {
+                self.zero_check_skipped_bytes += chunk_len;
+                if let Some(p) = pending.take() {
+                    self.zero_range(f, &p)?;
+                }
+            } else {
+                match &mut pending {
+                    Some(p) => p.end += chunk_len,
+                    None => pending = Some(offset..(offset + chunk_len)),
+                }
+            }
+            offset += chunk_len;
+        }
+        if let Some(p) = pending {
+            self.zero_range(f, &p)?;
+        }
+
+        Ok(())
+    }
+
+    fn zero_range(&mut self, f: &impl BlockDevice, range: &Range<u64>) -> ResultType<()> {
         if self.dry_run {
             return Ok(());
         }
 
+        // Only whole blocks can be zeroed by the device, write the odd ends.
+        let aligned = range.start.next_multiple_of(ZERO_ALIGNMENT)
+            ..(range.end / ZERO_ALIGNMENT * ZERO_ALIGNMENT);
+        if self.zero_strategy == ZeroStrategy::Write || aligned.start >= aligned.end {
+            return self.write_zeros(f, range);
+        }
+
+        self.write_zeros(f, &(range.start..aligned.start))?;
+        match f.zero(self.zero_strategy, &aligned) {
+            Ok(()) => self.count_zeroed(self.zero_strategy, aligned.end - aligned.start),
+            Err(e) if self.zero_strategy_fallback && blkdev::is_unsupported(&e) => {
+                warn!(
+                    "Zeroing with {:?} failed ({}), falling back to writing zeros.",
+                    self.zero_strategy, e
+                );
+                self.zero_strategy = ZeroStrategy::Write;
+                self.write_zeros(f, &aligned)?;
+            }
+            Err(e) => {
+                return Err(LoopliftError::Io {
+                    offset: Some(aligned.start),
+                    source: e,
+                })
+            }
+        }
+        self.write_zeros(f, &(aligned.end..range.end))
+    }
+
+    fn write_zeros(&mut self, f: &impl BlockDevice, range: &Range<u64>) -> ResultType<()> {
         self.buf_a.fill_with(Default::default);
         let mut out_offset = range.start;
         while out_offset < range.end {
-            let chunk_len = u64::min(BUFFER_LENGTH.try_into().unwrap(), range.end - out_offset);
-            f.write_all_at(&self.buf_a[0..chunk_len.try_into().unwrap()], out_offset)?;
+            let chunk_len = u64::min(self.chunk_length(), range.end - out_offset);
+            f.write_at(&self.buf_a[0..chunk_len.try_into().unwrap()], out_offset)
+                .at(out_offset)?;
             out_offset += chunk_len;
 
-            self.write_ops += 1;
-            self.write_bytes += chunk_len;
+            self.count_writes(1, chunk_len);
+            self.count_zeroed(ZeroStrategy::Write, chunk_len);
         }
 
         Ok(())
     }
 
+    fn count_zeroed(&mut self, strategy: ZeroStrategy, length: u64) {
+        let (ops, bytes) = self.zeroed.entry(strategy).or_default();
+        *ops += 1;
+        *bytes += length;
+        // Written zeros have been charged by `count_writes`.
+        if strategy != ZeroStrategy::Write {
+            self.throttle_writes(1, length);
+        }
+    }
+
     pub fn validate_checksum(
         &mut self,
-        f: &File,
+        f: &impl BlockDevice,
         offset: u64,
         length: u64,
         expected_csum: u64,
     ) -> ResultType<()> {
-        let mut hasher = DefaultHasher::new();
+        let hash = self.compute_checksum(f, offset, length)?;
+        if hash != expected_csum {
+            return Err(LoopliftError::ChecksumMismatch { offset, length });
+        }
 
-        let mut read = 0u64;
-        while read < length {
-            let chunk_len = u64::min(BUFFER_LENGTH.try_into().unwrap(), length - read);
-            let chunk = &mut self.buf_a[0..chunk_len.try_into().unwrap()];
+        Ok(())
+    }
+
+    /// Computes the checksum of a range of a single file, as stored in reports.
+    pub fn compute_checksum(
+        &mut self,
+        f: &impl BlockDevice,
+        offset: u64,
+        length: u64,
+    ) -> ResultType<u64> {
+        self.compute_checksum_of_segments(f, &[Segment::Data(offset..(offset + length))])
+    }
 
-            f.read_exact_at(chunk, offset + read)?;
+    /// Computes the checksum of the concatenation of several segments.
+    ///
+    /// Gives the same result as `compute_checksum` would on a file holding
+    /// the segments contiguously.
+    pub fn compute_checksum_of_segments(
+        &mut self,
+        f: &impl BlockDevice,
+        segments: &[Segment],
+    ) -> ResultType<u64> {
+        self.drain()?;
+        let mut csum = Checksum::new();
+        for segment in segments {
+            match segment {
+                Segment::Data(range) => {
+                    let mut offset = range.start;
+                    while offset < range.end {
+                        let chunk_len = u64::min(self.chunk_length(), range.end - offset);
+                        let chunk = &mut self.buf_a[0..chunk_len.try_into().unwrap()];
 
-            chunk.hash(&mut hasher);
-            read += chunk_len;
+                        f.read_at(chunk, offset).at(offset)?;
 
-            self.read_ops += 1;
-            self.read_bytes += chunk_len;
-        }
+                        csum.update(chunk);
+                        offset += chunk_len;
 
-        let hash = hasher.finish();
-        assert_eq!(hash, expected_csum, "Checksums should match");
+                        self.count_reads(1, chunk_len);
+                    }
+                }
+                Segment::Zeros(length) => csum.update_zeros(*length),
+            }
+        }
 
-        Ok(())
+        Ok(csum.finish())
     }
 
     pub(crate) fn log_stats(&self) {
+        info!("IO size {}", HumanBytes(self.chunk_length()));
         if self.read_ops > 0 {
             info!(
                 "Read {} in {} operations ({} per operation)",
@@ -202,57 +879,577 @@ impl FileOps {
                 HumanBytes(self.write_bytes / self.write_ops)
             );
         }
+        if self.check_zeros {
+            info!(
+                "Skipped zeroing {} which already read as zeros",
+                HumanBytes(self.zero_check_skipped_bytes)
+            );
+        }
+        for (strategy, (ops, bytes)) in &self.zeroed {
+            info!(
+                "Zeroed {} in {} operations using {:?}",
+                HumanBytes(*bytes),
+                HumanCount(*ops),
+                strategy
+            );
+        }
+        if self.discard_ops > 0 {
+            info!(
+                "Discarded {} in {} operations",
+                HumanBytes(self.discard_bytes),
+                HumanCount(self.discard_ops)
+            );
+        }
+        if self.seeks > 0 {
+            info!(
+                "Copies and swaps seeked {} times over a total distance of {}",
+                HumanCount(self.seeks),
+                HumanBytes(self.seek_distance)
+            );
+        }
     }
 }
 
-pub(crate) struct SimpleProgress {
-    pb: ProgressBar,
-    max: u64,
-    last: Option<u64>,
+/// The statistics kept by `FileOps`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct FileOpsCounters {
+    /// Reads issued, of data, checksums and zero checks.
+    pub read_ops: u64,
+    /// Bytes read.
+    pub read_bytes: u64,
+    /// Writes issued, including writes of zeros.
+    pub write_ops: u64,
+    /// Bytes written.
+    pub write_bytes: u64,
+    /// Zeroing operations by any strategy, including writing zeros.
+    pub zero_ops: u64,
+    /// Bytes zeroed by any strategy.
+    pub zero_bytes: u64,
+    /// Bytes which already read as zeros, so were not zeroed.
+    pub zero_check_skipped_bytes: u64,
+    /// Discards issued for zero extents.
+    pub discard_ops: u64,
+    /// Bytes discarded.
+    pub discard_bytes: u64,
+    /// Copies and swaps not starting where the previous one ended.
+    pub seeks: u64,
+    /// Total distance of those seeks, in bytes.
+    pub seek_distance: u64,
 }
 
-impl SimpleProgress {
-    pub fn new(max: u64) -> Self {
+impl FileOpsCounters {
+    /// The counts accumulated since `earlier`.
+    pub fn since(&self, earlier: &Self) -> Self {
         Self {
-            pb: ProgressBar::new(100),
-            max,
-            last: None,
+            read_ops: self.read_ops - earlier.read_ops,
+            read_bytes: self.read_bytes - earlier.read_bytes,
+            write_ops: self.write_ops - earlier.write_ops,
+            write_bytes: self.write_bytes - earlier.write_bytes,
+            zero_ops: self.zero_ops - earlier.zero_ops,
+            zero_bytes: self.zero_bytes - earlier.zero_bytes,
+            zero_check_skipped_bytes: self.zero_check_skipped_bytes
+                - earlier.zero_check_skipped_bytes,
+            discard_ops: self.discard_ops - earlier.discard_ops,
+            discard_bytes: self.discard_bytes - earlier.discard_bytes,
+            seeks: self.seeks - earlier.seeks,
+            seek_distance: self.seek_distance - earlier.seek_distance,
         }
     }
+}
+
+/// The `--io-size` setting, bytes (with an optional K, M or G suffix) or `auto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoSize {
+    /// Chosen from the device's queue limits.
+    Auto,
+    /// A multiple of 4 KiB, at most 64 MiB.
+    Bytes(u64),
+}
 
-    pub fn update(&mut self, value: u64) {
-        if value > self.max {
-            return self.update(self.max);
+impl FromStr for IoSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(IoSize::Auto);
         }
+        parse_size(s)
+            .map(IoSize::Bytes)
+            .ok_or_else(|| format!("'{}' is not a size in bytes or 'auto'", s))
+    }
+}
 
-        let value = (value * 100) / self.max;
+/// Parses a number with an optional binary K, M or G suffix.
+pub(crate) fn parse_size(s: &str) -> Option<u64> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
 
-        match self.last {
-            Some(v) if v == value => {
-                // no change
-            }
-            _ => {
-                self.pb.set_position(value);
-                self.last = Some(value);
+/// Picks an IO size from a device's queue limits.
+///
+/// Spinning disks want large IOs to amortise seeks between reading and
+/// writing, and RAID arrays want whole stripes (`optimal_io_size`).  Solid
+/// state devices gain nothing from IOs larger than the biggest request the
+/// queue accepts.
+fn auto_io_size(limits: &blkdev::QueueLimits) -> u64 {
+    let default: u64 = DEFAULT_IO_SIZE.try_into().unwrap();
+    let mut length: u64 = match limits.rotational {
+        true => 4 * 1024 * 1024,
+        false => 1024 * 1024,
+    };
+    if limits.optimal_io_size > 0 {
+        length = u64::max(length, limits.optimal_io_size).next_multiple_of(limits.optimal_io_size);
+    }
+    if !limits.rotational && limits.max_sectors_kb > 0 {
+        length = u64::min(length, limits.max_sectors_kb * 1024);
+    }
+    let alignment: u64 = DEFAULT_BUFFER_ALIGNMENT.try_into().unwrap();
+    (length / alignment * alignment).clamp(default, MAX_IO_SIZE.try_into().unwrap())
+}
+
+/// How `fill_zeros` makes ranges of the device read as zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+pub enum ZeroStrategy {
+    /// Pick based on the device type, falling back to `write` if unsupported.
+    Auto,
+    /// Ask the block device to zero the range (BLKZEROOUT), permitting it to unmap.
+    Zeroout,
+    /// Discard the range, only for devices where discarded data reads as zeros.
+    ///
+    /// BLKDISCARD is used on kernels before 4.12 reporting `discard_zeroes_data`,
+    /// elsewhere a write-zeroes request permitting the device to unmap.
+    Discard,
+    /// Punch a hole, for when the target is a regular file.
+    PunchHole,
+    /// Write buffers full of zeros.
+    Write,
+}
+
+/// Alignment required for zeroing by anything other than writes.
+const ZERO_ALIGNMENT: u64 = 4096;
+
+/// Granularity at which runs of zeros are detected while scanning.
+const ZERO_BLOCK_LENGTH: u64 = 4096;
+
+/// Part of a scanned extent, see `FileOps::check_equality_and_split_zeros`.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ScannedPiece {
+    Data { length: u64, checksum: u64 },
+    Zeros { length: u64 },
+}
+
+/// Returns true if every byte of `buf` is zero.
+pub(crate) fn is_zero(buf: &[u8]) -> bool {
+    // Fixed size blocks without early exit let the compiler vectorise the inner loop.
+    let mut blocks = buf.chunks_exact(64);
+    blocks.all(|b| b.iter().fold(0u8, |acc, x| acc | x) == 0)
+        && blocks.remainder().iter().all(|x| *x == 0)
+}
+
+/// A piece of data to be checksummed, either read from a file or implicitly zero.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum Segment {
+    Data(Range<u64>),
+    Zeros(u64),
+}
+
+/// Incrementally computes the checksum stored in reports.
+///
+/// Data is hashed in `CHECKSUM_CHUNK_LENGTH` pieces, the result does not
+/// depend on how the data is split across calls to `update`.
+pub(crate) struct Checksum {
+    hasher: DefaultHasher,
+    pending: Vec<u8>,
+}
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self {
+            hasher: DefaultHasher::new(),
+            pending: Vec::with_capacity(CHECKSUM_CHUNK_LENGTH),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = usize::min(CHECKSUM_CHUNK_LENGTH - self.pending.len(), data.len());
+            if self.pending.is_empty() && take == CHECKSUM_CHUNK_LENGTH {
+                data[..take].hash(&mut self.hasher);
+            } else {
+                self.pending.extend_from_slice(&data[..take]);
+                if self.pending.len() == CHECKSUM_CHUNK_LENGTH {
+                    self.pending.as_slice().hash(&mut self.hasher);
+                    self.pending.clear();
+                }
             }
+            data = &data[take..];
         }
     }
 
-    pub fn finish(self) {
-        self.pb.finish();
+    /// Equivalent to `update` with `length` zero bytes.
+    pub fn update_zeros(&mut self, mut length: u64) {
+        let zeros = [0u8; 4096];
+        while length > 0 {
+            let take = u64::min(length, zeros.len().try_into().unwrap());
+            self.update(&zeros[0..take.try_into().unwrap()]);
+            length -= take;
+        }
+    }
+
+    pub fn finish(mut self) -> u64 {
+        if !self.pending.is_empty() {
+            self.pending.as_slice().hash(&mut self.hasher);
+        }
+        self.hasher.finish()
     }
 }
 
-/**
- * Verify that the device is at least as big as the provided size.
- *
- * This is performed by attempting to read the very last byte.
- */
-pub(crate) fn validate_device_size(device: &std::fs::File, minimum_size: u64) -> ResultType<()> {
-    assert!(minimum_size >= 1);
-    let mut buf: [u8; 1] = [0u8];
-    device
-        .read_exact_at(&mut buf, minimum_size - 1)
-        .map_err(|_| "Failed to verify that device is at least as large as the file to lift.")?;
+/// Verify that the device is at least as big as the provided size, which
+/// must not be zero.
+pub(crate) fn validate_device_size(device: &impl BlockDevice, minimum_size: u64) -> ResultType<()> {
+    if minimum_size == 0 {
+        return Err("The file is empty, there is nothing to lift.".into());
+    }
+    let length = device.length()?;
+    if length < minimum_size {
+        return Err(LoopliftError::DeviceTooSmall {
+            required: minimum_size,
+            actual: length,
+        });
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    use crate::tests::{init_logger, scratch_file};
+
+    use std::{
+        fs::File,
+        os::{
+            fd::AsRawFd,
+            unix::fs::{FileExt, OpenOptionsExt},
+        },
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        blkdev::{self, QueueLimits},
+        device::MemDevice,
+        throttle::{Limit, Throttle},
+        ResultType,
+    };
+
+    use super::{
+        auto_io_size, validate_device_size, AlignedBuffer, Checksum, FileOps, IoSize, ScannedPiece,
+        ZeroStrategy, CHECKSUM_CHUNK_LENGTH, DEFAULT_IO_SIZE,
+    };
+
+    #[test]
+    fn checksum_independent_of_feed_size() {
+        init_logger();
+        let data: Vec<u8> = (0..(3 * CHECKSUM_CHUNK_LENGTH + 1234))
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        for length in [
+            0,
+            1,
+            CHECKSUM_CHUNK_LENGTH,
+            2 * CHECKSUM_CHUNK_LENGTH + 7,
+            data.len(),
+        ] {
+            let data = &data[..length];
+
+            let mut hasher = DefaultHasher::new();
+            for chunk in data.chunks(CHECKSUM_CHUNK_LENGTH) {
+                chunk.hash(&mut hasher);
+            }
+            let expected = hasher.finish();
+
+            for feed in [
+                1,
+                4096,
+                5000,
+                CHECKSUM_CHUNK_LENGTH,
+                3 * CHECKSUM_CHUNK_LENGTH,
+            ] {
+                let mut csum = Checksum::new();
+                for piece in data.chunks(feed) {
+                    csum.update(piece);
+                }
+                assert_eq!(csum.finish(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn split_zeros() -> ResultType<()> {
+        init_logger();
+
+        let f = scratch_file("zeros")?;
+
+        // 4K data, 12K zeros, 4K zeros with one set byte, 300K zeros, 100 bytes data.
+        let mut data = vec![0u8; 320 * 1024 + 100];
+        data[..4096].fill(1);
+        data[20000] = 1;
+        data[320 * 1024..].fill(2);
+        f.write_all_at(&data, 0)?;
+
+        let csum = |range: std::ops::Range<usize>| {
+            let mut c = Checksum::new();
+            c.update(&data[range]);
+            c.finish()
+        };
+
+        let mut fops = FileOps::new(true);
+        let length = data.len().try_into().unwrap();
+        assert_eq!(
+            fops.check_equality_and_split_zeros(&f, 0, &f, 0, length, 0)?,
+            vec![ScannedPiece::Data {
+                length,
+                checksum: csum(0..data.len())
+            }]
+        );
+        assert_eq!(
+            fops.check_equality_and_split_zeros(&f, 0, &f, 0, length, 8192)?,
+            vec![
+                ScannedPiece::Data {
+                    length: 4096,
+                    checksum: csum(0..4096)
+                },
+                ScannedPiece::Zeros { length: 12288 },
+                ScannedPiece::Data {
+                    length: 4096,
+                    checksum: csum(16384..20480)
+                },
+                ScannedPiece::Zeros { length: 300 * 1024 },
+                ScannedPiece::Data {
+                    length: 100,
+                    checksum: csum((320 * 1024)..data.len())
+                },
+            ]
+        );
+        assert_eq!(
+            fops.check_equality_and_split_zeros(&f, 0, &f, 0, length, 16384)?,
+            vec![
+                ScannedPiece::Data {
+                    length: 20480,
+                    checksum: csum(0..20480)
+                },
+                ScannedPiece::Zeros { length: 300 * 1024 },
+                ScannedPiece::Data {
+                    length: 100,
+                    checksum: csum((320 * 1024)..data.len())
+                },
+            ]
+        );
+        // Unaligned start and end, partial blocks are always data.
+        assert_eq!(
+            fops.check_equality_and_split_zeros(&f, 20001, &f, 20001, 100000, 8192)?,
+            vec![
+                ScannedPiece::Data {
+                    length: 479,
+                    checksum: csum(20001..20480)
+                },
+                ScannedPiece::Zeros { length: 98304 },
+                ScannedPiece::Data {
+                    length: 1217,
+                    checksum: csum(118784..120001)
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn zero_strategies() -> ResultType<()> {
+        init_logger();
+
+        for strategy in [
+            ZeroStrategy::Auto,
+            ZeroStrategy::PunchHole,
+            ZeroStrategy::Write,
+        ] {
+            let f = scratch_file(&format!("fill-{:?}", strategy))?;
+            f.write_all_at(&vec![0xaau8; 64 * 1024], 0)?;
+
+            let mut fops = FileOps::new(false);
+            fops.select_zero_strategy(&f, strategy)?;
+            fops.fill_zeros(&f, &(1000..50000))?;
+
+            let mut result = vec![0u8; 64 * 1024];
+            f.read_exact_at(&mut result, 0)?;
+            assert!(result[..1000].iter().all(|b| *b == 0xaa));
+            assert!(result[1000..50000].iter().all(|b| *b == 0));
+            assert!(result[50000..].iter().all(|b| *b == 0xaa));
+
+            let total: u64 = fops.zeroed.values().map(|(_, bytes)| bytes).sum();
+            assert_eq!(total, 49000);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn check_zeros() -> ResultType<()> {
+        init_logger();
+
+        let f = scratch_file("check")?;
+        let mut data = vec![0u8; 1024 * 1024];
+        data[200 * 1024] = 1;
+        data[900 * 1024] = 1;
+        f.write_all_at(&data, 0)?;
+
+        let mut fops = FileOps::new(false);
+        fops.set_check_zeros(true);
+        fops.fill_zeros(&f, &(0..(1024 * 1024)))?;
+
+        let mut result = vec![0xaau8; 1024 * 1024];
+        f.read_exact_at(&mut result, 0)?;
+        assert!(result.iter().all(|b| *b == 0));
+        assert_eq!(fops.zero_check_skipped_bytes, 6 * 128 * 1024);
+        assert_eq!(fops.zeroed[&ZeroStrategy::Write], (2, 2 * 128 * 1024));
+        Ok(())
+    }
+
+    #[test]
+    fn holes_are_not_read() -> ResultType<()> {
+        init_logger();
+        let f = scratch_file("holes")?;
+        let length = 1 << 40;
+        f.set_len(length)?;
+        f.write_all_at(&[1], length / 2)?;
+
+        let mut fops = FileOps::new(true);
+        assert!(fops.reads_as_zeros(&f, &(0..(length / 2)))?);
+        assert!(!fops.reads_as_zeros(&f, &(0..length))?);
+        assert!(fops.reads_as_zeros(&f, &((length / 2 + 1)..(length / 2 + 4096)))?);
+        // Only around the one byte of data.
+        assert!(
+            fops.read_bytes <= 2 * DEFAULT_IO_SIZE as u64,
+            "{}",
+            fops.read_bytes
+        );
+
+        let device = MemDevice::from(vec![0u8; 4096]);
+        assert!(fops.reads_as_zeros(&device, &(0..4096))?);
+        Ok(())
+    }
+
+    #[test]
+    fn throttled_zeroing() -> ResultType<()> {
+        init_logger();
+        let device = MemDevice::from(vec![0xaau8; 64 * 1024]);
+        let mut fops = FileOps::new(false);
+        fops.set_throttle(Some(Arc::new(Throttle::new(
+            Limit::default(),
+            "none/10".parse().unwrap(),
+            None,
+        ))));
+        fops.select_zero_strategy(&device, ZeroStrategy::Zeroout)?;
+        fops.enable_discard(&device)?;
+
+        // Ten operations a second, with no allowance to begin with.
+        let start = Instant::now();
+        fops.fill_zeros(&device, &(0..16384))?;
+        fops.fill_zeros(&device, &(16384..32768))?;
+        fops.discard(&device, &(0..32768))?;
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(device.into_inner()[..32768].iter().all(|b| *b == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn direct_io() -> ResultType<()> {
+        init_logger();
+
+        let buffer = AlignedBuffer::new(DEFAULT_IO_SIZE, 8192);
+        assert_eq!(buffer.as_ptr() as usize % 8192, 0);
+        assert!(buffer.iter().all(|b| *b == 0));
+
+        // Reopened through /proc, as the scratch file is already unlinked.
+        let scratch = scratch_file("direct")?;
+        let f = File::options()
+            .read(true)
+            .write(true)
+            .custom_flags(blkdev::O_DIRECT)
+            .open(format!("/proc/self/fd/{}", scratch.as_raw_fd()))?;
+        f.set_len(1024 * 1024)?;
+
+        let mut fops = FileOps::new(false);
+        fops.enable_direct_io(&f)?;
+        let block = fops.direct_io_alignment;
+        assert!(fops.check_direct_io_alignment(block * 3).is_ok());
+        assert!(fops.check_direct_io_alignment(block + 1).is_err());
+
+        let mut data = AlignedBuffer::new(DEFAULT_IO_SIZE, 4096);
+        data.fill(0x5a);
+        f.write_all_at(&data, 0)?;
+        fops.copy_segment(&f, &(0..(256 * 1024)), 512 * 1024)?;
+        assert_eq!(
+            fops.compute_checksum(&f, 0, 128 * 1024)?,
+            fops.compute_checksum(&f, 512 * 1024, 128 * 1024)?
+        );
+        validate_device_size(&f, 1024 * 1024)?;
+        assert!(validate_device_size(&f, 1024 * 1024 + 1).is_err());
+        assert!(validate_device_size(&f, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn io_sizes() -> ResultType<()> {
+        init_logger();
+
+        assert_eq!("auto".parse(), Ok(IoSize::Auto));
+        assert_eq!("4096".parse(), Ok(IoSize::Bytes(4096)));
+        assert_eq!("128K".parse(), Ok(IoSize::Bytes(128 * 1024)));
+        assert_eq!("4m".parse(), Ok(IoSize::Bytes(4 * 1024 * 1024)));
+        assert!("4X".parse::<IoSize>().is_err());
+        assert!("M".parse::<IoSize>().is_err());
+
+        let limits = |optimal_io_size, max_sectors_kb, rotational| QueueLimits {
+            optimal_io_size,
+            max_sectors_kb,
+            rotational,
+        };
+        assert_eq!(auto_io_size(&limits(0, 1280, true)), 4 * 1024 * 1024);
+        assert_eq!(
+            auto_io_size(&limits(3 * 1024 * 1024, 1280, true)),
+            6 * 1024 * 1024
+        );
+        assert_eq!(auto_io_size(&limits(0, 512, false)), 512 * 1024);
+        assert_eq!(auto_io_size(&limits(0, 0, false)), 1024 * 1024);
+        assert_eq!(auto_io_size(&limits(0, 4, false)), 128 * 1024);
+
+        // The checksums in reports must not depend on the IO size.
+        let f = scratch_file("io-size")?;
+        let data: Vec<u8> = (0..(3 * 1024 * 1024 + 100))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        f.write_all_at(&data, 0)?;
+
+        let mut small = FileOps::new(true);
+        let mut large = FileOps::new(true);
+        large.set_io_size(&f, IoSize::Bytes(1024 * 1024))?;
+        assert!(large.set_io_size(&f, IoSize::Bytes(1000)).is_err());
+        let length = u64::try_from(data.len()).unwrap() - 7;
+        assert_eq!(
+            small.compute_checksum(&f, 7, length)?,
+            large.compute_checksum(&f, 7, length)?
+        );
+        assert_eq!(
+            small.check_equality_and_compute_checksum(&f, 7, &f, 7, length)?,
+            large.check_equality_and_compute_checksum(&f, 7, &f, 7, length)?
+        );
+        assert_eq!(large.read_ops, 4 + 2 * 4);
+        Ok(())
+    }
+}
@@ -0,0 +1,776 @@
+use std::{fs::File, os::unix::fs::FileExt};
+
+use log::debug;
+
+use crate::ResultType;
+
+const XFS_SB_MAGIC: u32 = 0x58465342; // "XFSB"
+const XFS_AGF_MAGIC: u32 = 0x58414746; // "XAGF"
+const XFS_AGI_MAGIC: u32 = 0x58414749; // "XAGI"
+const XFS_DINODE_MAGIC: u16 = 0x494e; // "IN"
+const XFS_BMAP_MAGIC: u32 = 0x424d4150; // "BMAP"
+const XFS_BMAP_CRC_MAGIC: u32 = 0x424d4133; // "BMA3"
+const XFS_DIR2_BLOCK_MAGIC: u32 = 0x58443242; // "XD2B"
+const XFS_DIR2_DATA_MAGIC: u32 = 0x58443244; // "XD2D"
+const XFS_DIR3_BLOCK_MAGIC: u32 = 0x58444233; // "XDB3"
+const XFS_DIR3_DATA_MAGIC: u32 = 0x58444433; // "XDD3"
+
+const XFS_SB_VERSION_NUMBITS: u16 = 0x000f;
+const XFS_SB_VERSION2_FTYPE: u32 = 0x00000200;
+const XFS_SB_FEAT_INCOMPAT_FTYPE: u32 = 1 << 0;
+const XFS_SB_FEAT_INCOMPAT_NEEDSREPAIR: u32 = 1 << 4;
+const XFS_SB_FEAT_INCOMPAT_NREXT64: u32 = 1 << 5;
+/// Incompatible features that do not change anything this reader looks at.
+const XFS_SB_FEAT_INCOMPAT_UNDERSTOOD: u32 = 0xff & !XFS_SB_FEAT_INCOMPAT_NEEDSREPAIR;
+
+const XLOG_HEADER_MAGIC: u32 = 0xfeedbabe;
+/// Log records are made of these basic blocks.
+const XLOG_BBSIZE: u64 = 512;
+const XLOG_VERSION_2: u32 = 2;
+/// A v2 record header takes a basic block for each this many bytes of record.
+const XLOG_HEADER_CYCLE_SIZE: u64 = 32 * 1024;
+const XLOG_UNMOUNT_TRANS: u8 = 0x20;
+
+const XFS_DINODE_FMT_LOCAL: u8 = 1;
+const XFS_DINODE_FMT_EXTENTS: u8 = 2;
+const XFS_DINODE_FMT_BTREE: u8 = 3;
+const XFS_DIFLAG_REALTIME: u16 = 1 << 0;
+const XFS_DIFLAG2_NREXT64: u64 = 1 << 4;
+
+const S_IFMT: u16 = 0o170000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFREG: u16 = 0o100000;
+
+/// Directory data lives below this byte offset in a directory's address space,
+/// the leaf and free-index blocks live above it.
+const XFS_DIR2_LEAF_OFFSET: u64 = 32 << 30;
+
+/// Subset of the XFS superblock needed to locate inodes and blocks.
+#[derive(Debug)]
+struct Superblock {
+    blocksize: u64,
+    rootino: u64,
+    agblocks: u64,
+    agcount: u64,
+    sectsize: u64,
+    inodesize: u64,
+    blocklog: u8,
+    inopblog: u8,
+    agblklog: u8,
+    dirblklog: u8,
+    has_crc: bool,
+    has_ftype: bool,
+    has_nrext64: bool,
+    /// First block of the internal log, zero for an external log.
+    logstart: u64,
+    logblocks: u64,
+}
+
+/// A single record from an inode's data fork mapping.
+///
+/// Offsets and lengths are in filesystem blocks.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct BmapExtent {
+    pub startoff: u64,
+    pub startblock: u64,
+    pub blockcount: u64,
+    pub unwritten: bool,
+}
+
+/// The parts of an on-disk inode that matter for mapping file data.
+#[derive(Debug)]
+pub(crate) struct Inode {
+    mode: u16,
+    format: u8,
+    flags: u16,
+    pub size: u64,
+    nextents: u64,
+    fork: Vec<u8>,
+}
+
+/// Read-only access to an unmounted XFS filesystem.
+///
+/// Only the structures required to resolve a path to an inode, and that inode
+/// to its data extents, are understood.  The filesystem must have been cleanly
+/// unmounted, as the log is not replayed, which `open` checks.
+pub(crate) struct XfsFilesystem<'a> {
+    device: &'a File,
+    sb: Superblock,
+}
+
+impl<'a> XfsFilesystem<'a> {
+    /// Reads and checks the primary superblock, all AG headers and that
+    /// the log is clean.
+    pub fn open(device: &'a File) -> ResultType<Self> {
+        let mut buf = [0u8; 512];
+        device.read_exact_at(&mut buf, 0)?;
+        let sb = parse_superblock(&buf)?;
+        debug!("XFS superblock: {:?}", sb);
+
+        let fs = Self { device, sb };
+        for agno in 0..fs.sb.agcount {
+            fs.check_ag_headers(agno)?;
+        }
+        fs.check_log_clean()?;
+        Ok(fs)
+    }
+
+    pub fn blocksize(&self) -> u64 {
+        self.sb.blocksize
+    }
+
+    /// Byte offset on the device of a filesystem block number.
+    pub fn fsb_to_bytes(&self, fsbno: u64) -> u64 {
+        let agno = fsbno >> self.sb.agblklog;
+        let agbno = fsbno & ((1u64 << self.sb.agblklog) - 1);
+        (agno * self.sb.agblocks + agbno) << self.sb.blocklog
+    }
+
+    fn check_ag_headers(&self, agno: u64) -> ResultType<()> {
+        let ag_start = (agno * self.sb.agblocks) << self.sb.blocklog;
+        let mut buf = [0u8; 16];
+
+        self.device
+            .read_exact_at(&mut buf, ag_start + self.sb.sectsize)?;
+        if be32(&buf, 0) != XFS_AGF_MAGIC || u64::from(be32(&buf, 8)) != agno {
+            return Err(format!("Bad AGF header in AG {}.", agno).into());
+        }
+
+        self.device
+            .read_exact_at(&mut buf, ag_start + 2 * self.sb.sectsize)?;
+        if be32(&buf, 0) != XFS_AGI_MAGIC || u64::from(be32(&buf, 8)) != agno {
+            return Err(format!("Bad AGI header in AG {}.", agno).into());
+        }
+        Ok(())
+    }
+
+    /// Checks that the log holds nothing to replay, as `xfs_repair -n` does:
+    /// the last record written must be a lone unmount record, so that the
+    /// tail of the log is at its head.  Otherwise block maps may be stale.
+    fn check_log_clean(&self) -> ResultType<()> {
+        if self.sb.logstart == 0 {
+            return Err("XFS filesystems with an external log are not supported.".into());
+        }
+        let start = self.fsb_to_bytes(self.sb.logstart);
+        let length = self.sb.logblocks * self.sb.blocksize;
+        let log_bbs = length / XLOG_BBSIZE;
+
+        // The last record written has the highest LSN, stale ones from
+        // earlier passes around the log lower ones.
+        let mut last: Option<(u64, u64)> = None;
+        let mut buf = vec![0u8; 1024 * 1024];
+        let mut offset = 0;
+        while offset < length {
+            let chunk = &mut buf[..usize::try_from(u64::min(1024 * 1024, length - offset))?];
+            self.device.read_exact_at(chunk, start + offset)?;
+            for (i, bb) in chunk.chunks(XLOG_BBSIZE as usize).enumerate() {
+                if be32(bb, 0) != XLOG_HEADER_MAGIC {
+                    continue;
+                }
+                let lsn = be64(bb, 16);
+                if last.is_none_or(|(last_lsn, _)| lsn > last_lsn) {
+                    last = Some((lsn, offset / XLOG_BBSIZE + u64::try_from(i)?));
+                }
+            }
+            offset += u64::try_from(chunk.len())?;
+        }
+        let Some((lsn, header_bb)) = last else {
+            return Err("The XFS log holds no records.".into());
+        };
+
+        let mut header = [0u8; XLOG_BBSIZE as usize];
+        self.device
+            .read_exact_at(&mut header, start + header_bb * XLOG_BBSIZE)?;
+        let header_bbs = match be32(&header, 8) & XLOG_VERSION_2 {
+            0 => 1,
+            _ => u64::max(
+                1,
+                u64::from(be32(&header, 320)).div_ceil(XLOG_HEADER_CYCLE_SIZE),
+            ),
+        };
+        let mut op = [0u8; 16];
+        self.device.read_exact_at(
+            &mut op,
+            start + ((header_bb + header_bbs) % log_bbs) * XLOG_BBSIZE,
+        )?;
+        debug!(
+            "Last XFS log record at block {}, LSN 0x{:x}, {} operations, flags 0x{:x}",
+            header_bb,
+            lsn,
+            be32(&header, 40),
+            op[9]
+        );
+        if be32(&header, 40) != 1 || op[9] & XLOG_UNMOUNT_TRANS == 0 {
+            return Err("The XFS log is dirty: mount and cleanly unmount the filesystem first, as the log is not replayed.".into());
+        }
+        Ok(())
+    }
+
+    /// Resolves an absolute path within the filesystem to an inode number.
+    pub fn lookup(&self, path: &str) -> ResultType<u64> {
+        let mut ino = self.sb.rootino;
+        for name in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let inode = self.read_inode(ino)?;
+            if inode.mode & S_IFMT != S_IFDIR {
+                return Err(format!("Path component before '{}' is not a directory.", name).into());
+            }
+            ino = self
+                .dir_lookup(&inode, name.as_bytes())?
+                .ok_or_else(|| format!("No such file or directory: '{}'.", name))?;
+        }
+        Ok(ino)
+    }
+
+    pub fn read_inode(&self, ino: u64) -> ResultType<Inode> {
+        let inopblog = self.sb.inopblog;
+        let agno = ino >> (self.sb.agblklog + inopblog);
+        let agbno = (ino >> inopblog) & ((1u64 << self.sb.agblklog) - 1);
+        let index = ino & ((1u64 << inopblog) - 1);
+        if agno >= self.sb.agcount {
+            return Err(format!("Inode {} is outside of the filesystem.", ino).into());
+        }
+        let offset =
+            ((agno * self.sb.agblocks + agbno) << self.sb.blocklog) + index * self.sb.inodesize;
+
+        let mut buf = vec![0u8; self.sb.inodesize.try_into().unwrap()];
+        self.device.read_exact_at(&mut buf, offset)?;
+        parse_inode(&self.sb, &buf).map_err(|e| format!("Inode {}: {}", ino, e).into())
+    }
+
+    /// Decodes the data fork of an inode into its extents, ordered by file offset.
+    pub fn extents(&self, inode: &Inode) -> ResultType<Vec<BmapExtent>> {
+        let mut result = match inode.format {
+            XFS_DINODE_FMT_EXTENTS => {
+                if inode.fork.len() < usize::try_from(inode.nextents).unwrap() * 16 {
+                    return Err("Extent count exceeds the size of the data fork.".into());
+                }
+                parse_extent_records(&inode.fork, inode.nextents)
+            }
+            XFS_DINODE_FMT_BTREE => {
+                let level = be16(&inode.fork, 0);
+                let numrecs = u64::from(be16(&inode.fork, 2));
+                let maxrecs = (u64::try_from(inode.fork.len()).unwrap() - 4) / 16;
+                if level == 0 || numrecs > maxrecs {
+                    return Err("Corrupt bmap btree root.".into());
+                }
+                let mut result = Vec::new();
+                for i in 0..numrecs {
+                    let ptr = be64(
+                        &inode.fork,
+                        usize::try_from(4 + maxrecs * 8 + i * 8).unwrap(),
+                    );
+                    self.walk_bmap_btree(ptr, level - 1, &mut result)?;
+                }
+                result
+            }
+            _ => {
+                return Err(format!("Unsupported data fork format {}.", inode.format).into());
+            }
+        };
+        result.sort_by_key(|e| e.startoff);
+        Ok(result)
+    }
+
+    fn walk_bmap_btree(
+        &self,
+        fsbno: u64,
+        expected_level: u16,
+        result: &mut Vec<BmapExtent>,
+    ) -> ResultType<()> {
+        let block = self.read_block(fsbno)?;
+        let (magic, header_len) = match self.sb.has_crc {
+            true => (XFS_BMAP_CRC_MAGIC, 72),
+            false => (XFS_BMAP_MAGIC, 24),
+        };
+        let level = be16(&block, 4);
+        let numrecs = u64::from(be16(&block, 6));
+        let maxrecs = (u64::try_from(block.len() - header_len).unwrap()) / 16;
+        if be32(&block, 0) != magic || level != expected_level || numrecs > maxrecs {
+            return Err(format!("Corrupt bmap btree block {}.", fsbno).into());
+        }
+
+        if level == 0 {
+            result.extend(parse_extent_records(&block[header_len..], numrecs));
+        } else {
+            for i in 0..numrecs {
+                let ptr = be64(
+                    &block,
+                    header_len + usize::try_from(maxrecs * 8 + i * 8).unwrap(),
+                );
+                self.walk_bmap_btree(ptr, level - 1, result)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, fsbno: u64) -> ResultType<Vec<u8>> {
+        let mut buf = vec![0u8; self.sb.blocksize.try_into().unwrap()];
+        self.device
+            .read_exact_at(&mut buf, self.fsb_to_bytes(fsbno))?;
+        Ok(buf)
+    }
+
+    fn dir_lookup(&self, dir: &Inode, name: &[u8]) -> ResultType<Option<u64>> {
+        match dir.format {
+            XFS_DINODE_FMT_LOCAL => Ok(shortform_dir_lookup(&dir.fork, self.sb.has_ftype, name)),
+            XFS_DINODE_FMT_EXTENTS | XFS_DINODE_FMT_BTREE => {
+                let extents = self.extents(dir)?;
+                let dirblkfsbs = 1u64 << self.sb.dirblklog;
+                let leaf_fsb = XFS_DIR2_LEAF_OFFSET >> self.sb.blocklog;
+
+                for e in &extents {
+                    let first = e.startoff.next_multiple_of(dirblkfsbs);
+                    let end = u64::min(e.startoff + e.blockcount, leaf_fsb);
+                    for dir_fsb in (first..end).step_by(dirblkfsbs.try_into().unwrap()) {
+                        let block = self.read_dir_block(&extents, dir_fsb, dirblkfsbs)?;
+                        if let Some(ino) = data_block_lookup(&block, self.sb.has_ftype, name)? {
+                            return Ok(Some(ino));
+                        }
+                    }
+                }
+                Ok(None)
+            }
+            _ => Err(format!("Unsupported directory format {}.", dir.format).into()),
+        }
+    }
+
+    /// Reads one directory block, which may span several (not necessarily
+    /// physically contiguous) filesystem blocks.
+    fn read_dir_block(
+        &self,
+        extents: &[BmapExtent],
+        first_fsb: u64,
+        count: u64,
+    ) -> ResultType<Vec<u8>> {
+        let mut result = Vec::new();
+        for logical in first_fsb..(first_fsb + count) {
+            let e = extents
+                .iter()
+                .find(|e| e.startoff <= logical && logical < e.startoff + e.blockcount)
+                .ok_or("Directory block is not fully mapped.")?;
+            result.extend(self.read_block(e.startblock + (logical - e.startoff))?);
+        }
+        Ok(result)
+    }
+}
+
+impl Inode {
+    pub fn is_regular_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_realtime(&self) -> bool {
+        self.flags & XFS_DIFLAG_REALTIME != 0
+    }
+}
+
+fn parse_superblock(buf: &[u8]) -> ResultType<Superblock> {
+    if be32(buf, 0) != XFS_SB_MAGIC {
+        return Err("Device does not contain an XFS filesystem.".into());
+    }
+    let version = be16(buf, 100) & XFS_SB_VERSION_NUMBITS;
+    let features2 = be32(buf, 200);
+    let incompat = if version == 5 { be32(buf, 216) } else { 0 };
+    if incompat & !XFS_SB_FEAT_INCOMPAT_UNDERSTOOD != 0 {
+        return Err(format!("Unsupported XFS incompatible features 0x{:x}.", incompat).into());
+    }
+    if buf[126] != 0 {
+        return Err("XFS filesystem creation is still in progress.".into());
+    }
+    // Only set while the log holds items needing them.
+    let log_incompat = if version == 5 { be32(buf, 220) } else { 0 };
+    if log_incompat != 0 {
+        return Err(format!(
+            "XFS log has incompatible features 0x{:x} in use, so was not cleanly unmounted.",
+            log_incompat
+        )
+        .into());
+    }
+
+    let sb = Superblock {
+        blocksize: be32(buf, 4).into(),
+        rootino: be64(buf, 56),
+        agblocks: be32(buf, 84).into(),
+        agcount: be32(buf, 88).into(),
+        sectsize: be16(buf, 102).into(),
+        inodesize: be16(buf, 104).into(),
+        blocklog: buf[120],
+        inopblog: buf[123],
+        agblklog: buf[124],
+        dirblklog: buf[192],
+        has_crc: version == 5,
+        has_ftype: (version == 5 && incompat & XFS_SB_FEAT_INCOMPAT_FTYPE != 0)
+            || features2 & XFS_SB_VERSION2_FTYPE != 0,
+        has_nrext64: incompat & XFS_SB_FEAT_INCOMPAT_NREXT64 != 0,
+        logstart: be64(buf, 48),
+        logblocks: be32(buf, 96).into(),
+    };
+
+    if !(4..=5).contains(&version)
+        || sb.blocksize != 1u64 << sb.blocklog
+        || sb.blocklog < 9
+        || sb.agcount == 0
+        || sb.agblocks > 1u64 << sb.agblklog
+        || !(256..=sb.blocksize).contains(&sb.inodesize)
+        || u64::from(sb.inopblog) + u64::from(sb.agblklog) >= 64
+    {
+        return Err("XFS superblock is inconsistent or of an unsupported version.".into());
+    }
+    Ok(sb)
+}
+
+fn parse_inode(sb: &Superblock, buf: &[u8]) -> ResultType<Inode> {
+    if be16(buf, 0) != XFS_DINODE_MAGIC {
+        return Err("bad inode magic".into());
+    }
+    let version = buf[4];
+    let (core_size, flags2) = match version {
+        3 => (176, be64(buf, 120)),
+        _ => (100, 0),
+    };
+    let nextents = if sb.has_nrext64 && flags2 & XFS_DIFLAG2_NREXT64 != 0 {
+        be64(buf, 24)
+    } else {
+        be32(buf, 76).into()
+    };
+    let forkoff = usize::from(buf[82]) * 8;
+    let fork_end = match forkoff {
+        0 => buf.len(),
+        _ => core_size + forkoff,
+    };
+    if fork_end > buf.len() {
+        return Err("attribute fork offset is out of range".into());
+    }
+
+    Ok(Inode {
+        mode: be16(buf, 2),
+        format: buf[5],
+        flags: be16(buf, 90),
+        size: be64(buf, 56),
+        nextents,
+        fork: buf[core_size..fork_end].to_vec(),
+    })
+}
+
+/// Decodes packed 128-bit bmap records.
+fn parse_extent_records(buf: &[u8], count: u64) -> Vec<BmapExtent> {
+    (0..usize::try_from(count).unwrap())
+        .map(|i| {
+            let l0 = be64(buf, i * 16);
+            let l1 = be64(buf, i * 16 + 8);
+            BmapExtent {
+                unwritten: l0 >> 63 != 0,
+                startoff: (l0 & ((1u64 << 63) - 1)) >> 9,
+                startblock: ((l0 & 0x1ff) << 43) | (l1 >> 21),
+                blockcount: l1 & ((1u64 << 21) - 1),
+            }
+        })
+        .collect()
+}
+
+/// Looks up a name in a short-form directory stored inline in an inode.
+fn shortform_dir_lookup(fork: &[u8], has_ftype: bool, name: &[u8]) -> Option<u64> {
+    let count = fork.first()?;
+    let i8count = *fork.get(1)?;
+    let ino_len = if i8count > 0 { 8 } else { 4 };
+    let read_ino = |pos: usize| -> Option<u64> {
+        let bytes = fork.get(pos..(pos + ino_len))?;
+        Some(bytes.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b)))
+    };
+
+    if name == b".." {
+        return read_ino(2);
+    }
+
+    let mut pos = 2 + ino_len;
+    for _ in 0..*count {
+        let namelen = usize::from(*fork.get(pos)?);
+        let entry_name = fork.get((pos + 3)..(pos + 3 + namelen))?;
+        let ino_pos = pos + 3 + namelen + usize::from(has_ftype);
+        if entry_name == name {
+            return read_ino(ino_pos);
+        }
+        pos = ino_pos + ino_len;
+    }
+    None
+}
+
+/// Looks up a name in a single directory data (or single-block directory) block.
+fn data_block_lookup(block: &[u8], has_ftype: bool, name: &[u8]) -> ResultType<Option<u64>> {
+    let (mut pos, end) = match be32(block, 0) {
+        XFS_DIR2_DATA_MAGIC => (16, block.len()),
+        XFS_DIR3_DATA_MAGIC => (64, block.len()),
+        magic @ (XFS_DIR2_BLOCK_MAGIC | XFS_DIR3_BLOCK_MAGIC) => {
+            let leaf_count = usize::try_from(be32(block, block.len() - 8)).unwrap();
+            let end = block
+                .len()
+                .checked_sub(8 + leaf_count * 8)
+                .ok_or("Corrupt directory block tail.")?;
+            (
+                if magic == XFS_DIR2_BLOCK_MAGIC {
+                    16
+                } else {
+                    64
+                },
+                end,
+            )
+        }
+        _ => return Err("Bad directory data block magic.".into()),
+    };
+
+    while pos + 4 <= end {
+        let entry_len = if be16(block, pos) == 0xffff {
+            usize::from(be16(block, pos + 2))
+        } else {
+            if pos + 9 > end {
+                break;
+            }
+            let namelen = usize::from(block[pos + 8]);
+            if pos + 9 + namelen > end {
+                break;
+            }
+            if &block[(pos + 9)..(pos + 9 + namelen)] == name {
+                return Ok(Some(be64(block, pos)));
+            }
+            (8 + 1 + namelen + usize::from(has_ftype) + 2).next_multiple_of(8)
+        };
+        if entry_len == 0 {
+            return Err("Corrupt directory data block entry.".into());
+        }
+        pos += entry_len;
+    }
+    Ok(None)
+}
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(buf[offset..(offset + 2)].try_into().unwrap())
+}
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..(offset + 4)].try_into().unwrap())
+}
+
+fn be64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..(offset + 8)].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, os::unix::fs::FileExt};
+
+    use crate::{
+        tests::{init_logger, scratch_file},
+        ResultType,
+    };
+
+    use super::{parse_extent_records, BmapExtent, XfsFilesystem};
+
+    const BLOCK: usize = 4096;
+    const INODE: usize = 512;
+
+    fn put(buf: &mut [u8], offset: usize, bytes: &[u8]) {
+        buf[offset..(offset + bytes.len())].copy_from_slice(bytes);
+    }
+
+    fn pack_extent(e: &BmapExtent) -> [u8; 16] {
+        let l0 = (u64::from(e.unwritten) << 63) | (e.startoff << 9) | (e.startblock >> 43);
+        let l1 = (e.startblock << 21) | e.blockcount;
+        let mut result = [0u8; 16];
+        put(&mut result, 0, &l0.to_be_bytes());
+        put(&mut result, 8, &l1.to_be_bytes());
+        result
+    }
+
+    fn put_inode(image: &mut [u8], ino: usize, mode: u16, size: u64, fork: &[u8], nextents: u32) {
+        // Inode table starts at block 4, 8 inodes per block.
+        let base = 4 * BLOCK + (ino - 32) * INODE;
+        put(image, base, &0x494eu16.to_be_bytes());
+        put(image, base + 2, &mode.to_be_bytes());
+        image[base + 4] = 3;
+        image[base + 5] = if nextents == 0 { 1 } else { 2 };
+        put(image, base + 56, &size.to_be_bytes());
+        put(image, base + 76, &nextents.to_be_bytes());
+        put(image, base + 176, fork);
+    }
+
+    /// Writes a log record at basic block `bb` of the log, whose first
+    /// operation has `flags`.
+    fn put_log_record(image: &mut [u8], cycle: u32, bb: usize, ops: u32, flags: u8) {
+        let header = 13 * BLOCK + bb * 512;
+        put(image, header, &0xfeedbabeu32.to_be_bytes());
+        put(image, header + 4, &cycle.to_be_bytes());
+        put(image, header + 8, &2u32.to_be_bytes()); // version
+        put(image, header + 12, &512u32.to_be_bytes()); // length
+        let lsn = (u64::from(cycle) << 32) | bb as u64;
+        put(image, header + 16, &lsn.to_be_bytes());
+        put(image, header + 24, &lsn.to_be_bytes()); // tail
+        put(image, header + 40, &ops.to_be_bytes());
+        put(image, header + 320, &32768u32.to_be_bytes()); // header size
+        put(image, header + 512, &cycle.to_be_bytes());
+        image[header + 512 + 9] = flags;
+    }
+
+    fn open_image(image: &[u8]) -> ResultType<File> {
+        let device = scratch_file("xfs")?;
+        device.write_all_at(image, 0)?;
+        Ok(device)
+    }
+
+    /// Builds a minimal single-AG v5 filesystem containing `/dir/file.img`.
+    ///
+    /// Inodes with no extents are written in local (short form) format.
+    fn build_image(file_extents: &[BmapExtent]) -> Vec<u8> {
+        let mut image = vec![0u8; 16 * BLOCK];
+
+        put(&mut image, 0, b"XFSB");
+        put(&mut image, 4, &(BLOCK as u32).to_be_bytes());
+        put(&mut image, 56, &32u64.to_be_bytes()); // rootino
+        put(&mut image, 84, &16u32.to_be_bytes()); // agblocks
+        put(&mut image, 88, &1u32.to_be_bytes()); // agcount
+        put(&mut image, 100, &0xb4a5u16.to_be_bytes()); // version 5
+        put(&mut image, 102, &512u16.to_be_bytes()); // sectsize
+        put(&mut image, 104, &(INODE as u16).to_be_bytes());
+        image[120] = 12; // blocklog
+        image[123] = 3; // inopblog
+        image[124] = 4; // agblklog
+        put(&mut image, 216, &1u32.to_be_bytes()); // ftype
+        put(&mut image, 48, &13u64.to_be_bytes()); // logstart
+        put(&mut image, 96, &3u32.to_be_bytes()); // logblocks
+        put(&mut image, 512, b"XAGF");
+        put(&mut image, 1024, b"XAGI");
+        // The log, as left by a clean unmount.
+        put_log_record(&mut image, 1, 0, 1, 0x20);
+
+        // Root directory, short form, containing "dir".
+        let mut sf = vec![1u8, 0, 0, 0, 0, 32, 3, 0, 0x60];
+        sf.extend(b"dir");
+        sf.push(2);
+        sf.extend(33u32.to_be_bytes());
+        put_inode(&mut image, 32, 0o040755, 6, &sf, 0);
+
+        // "dir", a single block directory in block 6, containing "file.img".
+        let dir_extent = BmapExtent {
+            startoff: 0,
+            startblock: 6,
+            blockcount: 1,
+            unwritten: false,
+        };
+        put_inode(
+            &mut image,
+            33,
+            0o040755,
+            BLOCK as u64,
+            &pack_extent(&dir_extent),
+            1,
+        );
+        let dir_block = 6 * BLOCK;
+        put(&mut image, dir_block, b"XDB3");
+        put(&mut image, dir_block + 64, &34u64.to_be_bytes());
+        image[dir_block + 72] = 8;
+        put(&mut image, dir_block + 73, b"file.img");
+        image[dir_block + 81] = 1;
+        put(&mut image, dir_block + 88, &0xffffu16.to_be_bytes());
+        put(
+            &mut image,
+            dir_block + 90,
+            &((BLOCK - 16 - 88) as u16).to_be_bytes(),
+        );
+        put(&mut image, dir_block + BLOCK - 8, &1u32.to_be_bytes());
+
+        // The file itself.
+        let fork: Vec<u8> = file_extents.iter().flat_map(pack_extent).collect();
+        let nextents = u32::try_from(file_extents.len()).unwrap();
+        put_inode(
+            &mut image,
+            34,
+            0o100644,
+            6 * BLOCK as u64 - 100,
+            &fork,
+            nextents,
+        );
+
+        image
+    }
+
+    #[test]
+    fn extent_records() {
+        init_logger();
+        let e = BmapExtent {
+            startoff: (1 << 54) - 3,
+            startblock: (1 << 52) - 5,
+            blockcount: (1 << 21) - 7,
+            unwritten: true,
+        };
+        assert_eq!(parse_extent_records(&pack_extent(&e), 1), vec![e]);
+    }
+
+    #[test]
+    fn lookup_and_map() -> ResultType<()> {
+        init_logger();
+
+        let file_extents = vec![
+            BmapExtent {
+                startoff: 0,
+                startblock: 8,
+                blockcount: 1,
+                unwritten: false,
+            },
+            BmapExtent {
+                startoff: 2,
+                startblock: 9,
+                blockcount: 1,
+                unwritten: true,
+            },
+            BmapExtent {
+                startoff: 3,
+                startblock: 10,
+                blockcount: 2,
+                unwritten: false,
+            },
+        ];
+        let device = open_image(&build_image(&file_extents))?;
+
+        let fs = XfsFilesystem::open(&device)?;
+        let ino = fs.lookup("/dir/file.img")?;
+        assert_eq!(ino, 34);
+        assert!(fs.lookup("/dir/missing").is_err());
+        assert!(fs.lookup("/dir/file.img/nope").is_err());
+
+        let inode = fs.read_inode(ino)?;
+        assert!(inode.is_regular_file());
+        assert_eq!(inode.size, 6 * 4096 - 100);
+        assert_eq!(fs.extents(&inode)?, file_extents);
+        assert_eq!(fs.fsb_to_bytes(10), 10 * 4096);
+        Ok(())
+    }
+    #[test]
+    fn dirty_log() -> ResultType<()> {
+        init_logger();
+        let clean = build_image(&[]);
+        assert!(XfsFilesystem::open(&open_image(&clean)?).is_ok());
+
+        // A transaction after the unmount record.
+        let mut image = clean.clone();
+        put_log_record(&mut image, 1, 2, 3, 0);
+        assert!(XfsFilesystem::open(&open_image(&image)?).is_err());
+
+        // Older records, from the previous pass around the log, don't count.
+        let mut image = clean.clone();
+        put_log_record(&mut image, 0, 4, 3, 0);
+        assert!(XfsFilesystem::open(&open_image(&image)?).is_ok());
+
+        // An unmount record alongside other operations.
+        let mut image = clean.clone();
+        put_log_record(&mut image, 1, 0, 2, 0x20);
+        assert!(XfsFilesystem::open(&open_image(&image)?).is_err());
+
+        let mut image = clean.clone();
+        put(&mut image, 220, &1u32.to_be_bytes()); // log_incompat
+        assert!(XfsFilesystem::open(&open_image(&image)?).is_err());
+
+        let mut image = clean;
+        image[126] = 1; // inprogress
+        assert!(XfsFilesystem::open(&open_image(&image)?).is_err());
+        Ok(())
+    }
+}
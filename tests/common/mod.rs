@@ -0,0 +1,21 @@
+//! Helpers for the integration tests, which cannot reach the crate's own
+//! test helpers.
+
+/// A linear congruential generator, giving randomized tests the same
+/// numbers on every run.
+pub struct Lcg(u64);
+
+impl Lcg {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// A number below `n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % n
+    }
+}
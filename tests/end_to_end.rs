@@ -0,0 +1,402 @@
+//! End-to-end tests of the `looplift` binary on plain files, needing no
+//! privileges: a synthetic outer device holds the pieces of an inner image
+//! at known places, which are given to `scan` in place of FIEMAP, or to
+//! `import` as the output of `filefrag` and `xfs_bmap`.
+
+use std::{
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+use looplift::scan::{ExtentKind, FileExtent};
+
+mod common;
+
+use common::Lcg;
+
+/// Length of each piece of the inner image.
+const PIECE_LENGTH: u64 = 64 * 1024;
+/// Number of pieces, and so of slots for them on the outer device.
+const PIECES: u64 = 64;
+
+/// A scratch directory, removed afterwards.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("looplift-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The inner image, the outer device holding it, and the extents of the
+/// former within the latter, as a filesystem would report them.
+struct Images {
+    inner: Vec<u8>,
+    outer: Vec<u8>,
+    extents: Vec<FileExtent>,
+}
+
+/// Builds images from `seed`.  Some pieces of the inner image are holes or
+/// unwritten extents, some data with long runs of zeros, and the rest plain
+/// data.  The pieces are scattered over the outer device (merged into longer
+/// extents where they happen to stay adjacent), which holds unrelated data
+/// elsewhere.
+fn build_images(seed: u64) -> Images {
+    let mut rng = Lcg::new(seed);
+
+    let length = (PIECE_LENGTH * PIECES) as usize;
+    let mut inner = vec![0u8; length];
+    let mut outer: Vec<u8> = (0..length).map(|_| rng.below(256) as u8).collect();
+
+    let mut slots: Vec<u64> = (0..PIECES).collect();
+    for i in (1..slots.len()).rev() {
+        slots.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+
+    let mut extents: Vec<FileExtent> = Vec::new();
+    for (piece, &slot) in slots.iter().enumerate() {
+        let logical = piece as u64 * PIECE_LENGTH;
+        let physical = slot * PIECE_LENGTH;
+        let data = &mut inner[(logical as usize)..((logical + PIECE_LENGTH) as usize)];
+        let kind = match rng.below(5) {
+            0 => continue,
+            // Allocated on the device, over unrelated data, but reading as zeros.
+            1 => ExtentKind::Unwritten,
+            2 => {
+                // Zeros but for the first and last bytes.
+                data[0] = 1;
+                data[data.len() - 1] = 1;
+                ExtentKind::Data
+            }
+            _ => {
+                data.iter_mut().for_each(|b| *b = rng.below(256) as u8);
+                ExtentKind::Data
+            }
+        };
+        if kind == ExtentKind::Data {
+            outer[(physical as usize)..((physical + PIECE_LENGTH) as usize)].copy_from_slice(data);
+        }
+
+        match extents.last_mut() {
+            Some(e)
+                if e.logical + e.length == logical
+                    && e.physical + e.length == physical
+                    && e.kind == kind =>
+            {
+                e.length += PIECE_LENGTH
+            }
+            _ => extents.push(FileExtent {
+                logical,
+                physical,
+                length: PIECE_LENGTH,
+                kind,
+            }),
+        }
+    }
+
+    Images {
+        inner,
+        outer,
+        extents,
+    }
+}
+
+fn content_hash(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    fs::read(path).unwrap().hash(&mut hasher);
+    hasher.finish()
+}
+
+fn write_extent_map(path: &Path, extents: &[FileExtent]) {
+    let mut map = Vec::new();
+    for e in extents {
+        serde_json::to_writer(&mut map, e).unwrap();
+        map.push(b'\n');
+    }
+    fs::write(path, map).unwrap();
+}
+
+/// Runs the binary with `args`, and `stdin` as its standard input.
+fn looplift(args: &[&str], stdin: Option<&Path>) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_looplift"))
+        .args(args)
+        .stdin(match stdin {
+            Some(path) => Stdio::from(fs::File::open(path).unwrap()),
+            None => Stdio::null(),
+        })
+        .output()
+        .unwrap();
+    if !output.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+    }
+    output
+}
+
+/// Scans the inner image against the outer device, returning the report.
+fn scan(dir: &TempDir, extents: &[FileExtent], zero_threshold: &str) -> Output {
+    write_extent_map(&dir.join("extents.json"), extents);
+    looplift(
+        &[
+            "scan",
+            "--extent-map",
+            dir.join("extents.json").to_str().unwrap(),
+            "--zero-threshold",
+            zero_threshold,
+            dir.join("inner.img").to_str().unwrap(),
+            dir.join("outer.img").to_str().unwrap(),
+        ],
+        None,
+    )
+}
+
+/// The extents as `filefrag -v` would print them, in 4 KiB blocks.
+fn filefrag_output(extents: &[FileExtent], file_length: u64) -> String {
+    let mut out = format!(
+        "Filesystem type is: ef53\nFile size of inner.img is {} ({} blocks of 4096 bytes)\n",
+        file_length,
+        file_length / 4096
+    );
+    out += " ext:     logical_offset:        physical_offset: length:   expected: flags:\n";
+    for (i, e) in extents.iter().enumerate() {
+        let (logical, physical, length) = (e.logical / 4096, e.physical / 4096, e.length / 4096);
+        let flags = match e.kind {
+            ExtentKind::Unwritten => "unwritten",
+            _ => "",
+        };
+        out += &format!(
+            "{:4}: {:8}..{:8}: {:10}..{:10}: {:6}: {:10} {}\n",
+            i,
+            logical,
+            logical + length - 1,
+            physical,
+            physical + length - 1,
+            length,
+            "",
+            flags
+        );
+    }
+    out + &format!("inner.img: {} extents found\n", extents.len())
+}
+
+/// The extents as `xfs_bmap -vp` would print them, holes included.
+fn xfs_bmap_output(extents: &[FileExtent], file_length: u64) -> String {
+    let mut out =
+        "inner.img:\n EXT: FILE-OFFSET      BLOCK-RANGE      AG AG-OFFSET        TOTAL FLAGS\n"
+            .to_string();
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for e in extents {
+        if e.logical > offset {
+            lines.push(format!(
+                "[{}..{}]: hole {}",
+                offset / 512,
+                e.logical / 512 - 1,
+                (e.logical - offset) / 512
+            ));
+        }
+        let (logical, physical, length) = (e.logical / 512, e.physical / 512, e.length / 512);
+        let flags = match e.kind {
+            ExtentKind::Unwritten => "010000",
+            _ => "000000",
+        };
+        lines.push(format!(
+            "[{}..{}]: {}..{} 0 ({}..{}) {} {}",
+            logical,
+            logical + length - 1,
+            physical,
+            physical + length - 1,
+            physical,
+            physical + length - 1,
+            length,
+            flags
+        ));
+        offset = e.logical + e.length;
+    }
+    if offset < file_length {
+        lines.push(format!(
+            "[{}..{}]: hole {}",
+            offset / 512,
+            file_length / 512 - 1,
+            (file_length - offset) / 512
+        ));
+    }
+    for (i, line) in lines.iter().enumerate() {
+        out += &format!("{:4}: {}\n", i, line);
+    }
+    out
+}
+
+#[test]
+fn scan_and_lift() {
+    for (seed, zero_threshold, lift_args) in [
+        (1, "1048576", &[][..]),
+        (2, "4096", &["--jobs", "3"][..]),
+        (3, "16384", &["--order", "elevator", "--check-zeros"][..]),
+        (4, "0", &["--io-size", "4K"][..]),
+    ] {
+        let dir = TempDir::new(&format!("scan-and-lift-{}", seed));
+        let images = build_images(seed);
+        let outer = dir.join("outer.img");
+        let report = dir.join("report.json");
+        fs::write(dir.join("inner.img"), &images.inner).unwrap();
+        fs::write(&outer, &images.outer).unwrap();
+        let original_outer = content_hash(&outer);
+        let original_inner = content_hash(&dir.join("inner.img"));
+
+        let output = scan(&dir, &images.extents, zero_threshold);
+        assert!(output.status.success());
+        fs::write(&report, &output.stdout).unwrap();
+
+        assert!(looplift(&["plan"], Some(&report)).status.success());
+
+        let outer_arg = outer.to_str().unwrap();
+        let output = looplift(
+            &[&["lift"], lift_args, &[outer_arg]].concat(),
+            Some(&report),
+        );
+        assert!(output.status.success());
+        assert_eq!(
+            content_hash(&outer),
+            original_outer,
+            "Dry run should not alter the device."
+        );
+
+        let output = looplift(
+            &[&["lift", "--dry-run", "false"], lift_args, &[outer_arg]].concat(),
+            Some(&report),
+        );
+        assert!(output.status.success());
+        assert_eq!(
+            content_hash(&outer),
+            original_inner,
+            "Lifting should leave the inner image on the device."
+        );
+    }
+}
+
+#[test]
+fn rejected_before_writing() {
+    let dir = TempDir::new("rejected");
+    let images = build_images(5);
+    let outer = dir.join("outer.img");
+    let report = dir.join("report.json");
+    fs::write(dir.join("inner.img"), &images.inner).unwrap();
+    fs::write(&outer, &images.outer).unwrap();
+
+    let data = images
+        .extents
+        .iter()
+        .position(|e| e.kind == ExtentKind::Data)
+        .unwrap();
+
+    // An extent said to be somewhere its data is not.
+    let mut wrong = images.extents.clone();
+    wrong[data].physical = (wrong[data].physical + PIECE_LENGTH) % (PIECE_LENGTH * PIECES);
+    let output = scan(&dir, &wrong, "1048576");
+    assert_eq!(output.status.code(), Some(7));
+
+    // An extent which cannot be lifted.
+    let mut encoded = images.extents.clone();
+    encoded[data].kind = ExtentKind::Encoded;
+    let output = scan(&dir, &encoded, "1048576");
+    assert_eq!(output.status.code(), Some(5));
+
+    // An extent left out of the map, whose data would be lost as zeros.
+    let mut missing = images.extents.clone();
+    missing.remove(data);
+    let output = scan(&dir, &missing, "1048576");
+    assert_eq!(output.status.code(), Some(5));
+
+    let output = scan(&dir, &images.extents, "1048576");
+    assert!(output.status.success());
+    fs::write(&report, &output.stdout).unwrap();
+
+    // Data changed on the device after scanning.
+    let mut changed = images.outer.clone();
+    let first = images.extents[data].physical as usize;
+    changed[first] ^= 0xff;
+    fs::write(&outer, &changed).unwrap();
+    let output = looplift(
+        &["lift", "--dry-run", "false", outer.to_str().unwrap()],
+        Some(&report),
+    );
+    assert_eq!(output.status.code(), Some(7));
+    assert!(fs::read(&outer).unwrap() == changed);
+
+    // A report for a longer device.
+    fs::write(&outer, &images.outer[..(images.outer.len() / 2)]).unwrap();
+    let output = looplift(
+        &["lift", "--dry-run", "false", outer.to_str().unwrap()],
+        Some(&report),
+    );
+    assert_eq!(output.status.code(), Some(6));
+}
+
+#[test]
+fn import_and_lift() {
+    let images = build_images(6);
+    let length = images.inner.len() as u64;
+    // Unwritten extents are refused, but may be left out as holes instead.
+    let written: Vec<FileExtent> = images
+        .extents
+        .iter()
+        .filter(|e| e.kind == ExtentKind::Data)
+        .cloned()
+        .collect();
+    assert!(written.len() < images.extents.len());
+
+    for format in ["filefrag", "xfs-bmap"] {
+        let dir = TempDir::new(&format!("import-{}", format));
+        let outer = dir.join("outer.img");
+        let captured = dir.join("captured.txt");
+        let report = dir.join("report.json");
+        let length_arg = length.to_string();
+        let import_args = match format {
+            "filefrag" => vec!["import", "--format", format, outer.to_str().unwrap()],
+            _ => vec![
+                "import",
+                "--format",
+                format,
+                "--file-length",
+                &length_arg,
+                outer.to_str().unwrap(),
+            ],
+        };
+        let output_of = |extents: &[FileExtent]| match format {
+            "filefrag" => filefrag_output(extents, length),
+            _ => xfs_bmap_output(extents, length),
+        };
+        fs::write(&outer, &images.outer).unwrap();
+
+        fs::write(&captured, output_of(&images.extents)).unwrap();
+        let output = looplift(&import_args, Some(&captured));
+        assert_eq!(output.status.code(), Some(5));
+
+        fs::write(&captured, output_of(&written)).unwrap();
+        let output = looplift(&import_args, Some(&captured));
+        assert!(output.status.success());
+        fs::write(&report, &output.stdout).unwrap();
+
+        let output = looplift(
+            &["lift", "--dry-run", "false", outer.to_str().unwrap()],
+            Some(&report),
+        );
+        assert!(output.status.success());
+        assert!(fs::read(&outer).unwrap() == images.inner);
+    }
+}